@@ -0,0 +1,231 @@
+//! Round trips of concurrent requests through the dispatcher's pending request store.
+//!
+//! Requests are published to a stub broker which discards them. Responses are passed
+//! to the dispatcher right away the same way the message handling loop does.
+//!
+//! The `baseline_round_trip` group makes the same round trips through a store guarded
+//! by a single lock which parses responses before passing them to the awaiting side
+//! as the dispatcher did before its store got sharded.
+
+use std::{
+    collections::HashMap,
+    future::Future,
+    io::{Read, Write},
+    net::TcpListener,
+    sync::{Arc, Mutex},
+    thread,
+};
+
+use chrono::Utc;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use serde::Deserialize;
+use serde_json::{json, Value as JsonValue};
+use svc_agent::{
+    mqtt::{
+        Agent, AgentBuilder, AgentConfig, ConnectionMode, IncomingPayload, IncomingResponse,
+        IncomingResponseProperties, OutgoingMessage, OutgoingRequest, OutgoingRequestProperties,
+        OutgoingShortTermTimingProperties,
+    },
+    request::Dispatcher,
+    AccountId, AgentId,
+};
+use tokio::{runtime::Runtime, sync::oneshot};
+use uuid::Uuid;
+
+const CONCURRENCY: &[usize] = &[1, 64, 1024];
+
+#[derive(Deserialize)]
+struct Pong {
+    #[allow(dead_code)]
+    ok: bool,
+}
+
+/// Starts an MQTT broker stub accepting any connection and discarding incoming packets.
+fn start_broker() -> u16 {
+    let listener = TcpListener::bind("127.0.0.1:0").expect("Failed to bind broker stub");
+    let port = listener.local_addr().expect("Missing local address").port();
+
+    thread::spawn(move || {
+        for mut stream in listener.incoming().flatten() {
+            thread::spawn(move || {
+                let mut buf = [0; 4096];
+
+                // CONNECT is small enough to arrive in a single read.
+                if stream.read(&mut buf).unwrap_or(0) == 0 {
+                    return;
+                }
+
+                if stream.write_all(&[0x20, 0x02, 0x00, 0x00]).is_err() {
+                    return;
+                }
+
+                while stream.read(&mut buf).unwrap_or(0) > 0 {}
+            });
+        }
+    });
+
+    port
+}
+
+fn start_agent(port: u16) -> Agent {
+    let config = serde_json::from_value::<AgentConfig>(json!({
+        "uri": format!("mqtt://127.0.0.1:{}", port),
+        "keep_alive_interval": 3600,
+        "reconnect_interval": 1,
+        "requests_channel_size": 100_000,
+    }))
+    .expect("Invalid agent config");
+
+    let account_id = AccountId::new("bench", "svc.example.org");
+    let agent_id = AgentId::new("instance01", account_id);
+
+    let (agent, mut rx) = AgentBuilder::new(agent_id, "v1")
+        .connection_mode(ConnectionMode::Service)
+        .start(&config)
+        .expect("Failed to start agent");
+
+    tokio::spawn(async move { while rx.recv().await.is_some() {} });
+    agent
+}
+
+fn response_properties(corr_data: &str) -> IncomingResponseProperties {
+    let session_label = format!("{}.{}", Uuid::new_v4(), Uuid::new_v4());
+
+    serde_json::from_value(json!({
+        "status": "200",
+        "correlation_data": corr_data,
+        "agent_id": "instance01.service.svc.example.org",
+        "connection_version": "v1",
+        "connection_mode": "service",
+        "broker_timestamp": "1600000000000",
+        "broker_processing_timestamp": "1600000000000",
+        "broker_initial_processing_timestamp": "1600000000000",
+        "tracking_id": format!("{}.{}", Uuid::new_v4(), session_label),
+        "session_tracking_label": session_label,
+    }))
+    .expect("Invalid response properties")
+}
+
+fn request(corr_data: &str, to: &AccountId) -> OutgoingRequest<JsonValue> {
+    let short_term_timing = OutgoingShortTermTimingProperties::new(Utc::now());
+    let props = OutgoingRequestProperties::new("ping", "responses", corr_data, short_term_timing);
+
+    match OutgoingRequest::multicast(json!({}), props, to, "v1") {
+        OutgoingMessage::Request(req) => req,
+        _ => unreachable!(),
+    }
+}
+
+async fn round_trip(dispatcher: &Dispatcher, to: &AccountId) {
+    let corr_data = Uuid::new_v4().to_string();
+    let req = request(&corr_data, to);
+
+    let resp = IncomingResponse::new(
+        IncomingPayload::from(r#"{"ok":true}"#),
+        response_properties(&corr_data),
+    );
+
+    // The request gets registered on the first poll so the response is awaited by then.
+    let (result, ()) = futures::future::join(dispatcher.request::<_, Pong>(req), async {
+        dispatcher
+            .response(resp)
+            .expect("Failed to commit response")
+    })
+    .await;
+
+    result.expect("Request failed");
+}
+
+/// Pending requests store guarded by a single lock.
+#[derive(Default)]
+struct BaselineStore(Mutex<HashMap<String, oneshot::Sender<IncomingResponse<JsonValue>>>>);
+
+async fn baseline_round_trip(agent: &Agent, store: &BaselineStore, to: &AccountId) {
+    let corr_data = Uuid::new_v4().to_string();
+    let req = request(&corr_data, to);
+    let (tx, rx) = oneshot::channel();
+
+    store
+        .0
+        .lock()
+        .expect("Store lock poisoned")
+        .insert(corr_data.clone(), tx);
+
+    agent
+        .clone()
+        .publish(OutgoingMessage::Request(req))
+        .expect("Failed to publish request");
+
+    let payload = serde_json::from_str::<JsonValue>(r#"{"ok":true}"#).expect("Invalid payload");
+    let resp = IncomingResponse::new(payload, response_properties(&corr_data));
+
+    store
+        .0
+        .lock()
+        .expect("Store lock poisoned")
+        .remove(&corr_data)
+        .expect("Response is not awaited")
+        .send(resp)
+        .unwrap_or_else(|_| panic!("Failed to commit response"));
+
+    let resp = rx.await.expect("Failed to receive response");
+    serde_json::from_value::<Pong>(resp.extract_payload()).expect("Invalid response payload");
+}
+
+/// Benchmarks running the given number of round trips concurrently.
+fn bench_group<F, Fut>(c: &mut Criterion, rt: &Runtime, name: &str, round_trip: F)
+where
+    F: Fn() -> Fut,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    let mut group = c.benchmark_group(name);
+
+    for &concurrency in CONCURRENCY {
+        group.throughput(Throughput::Elements(concurrency as u64));
+
+        group.bench_with_input(
+            BenchmarkId::from_parameter(concurrency),
+            &concurrency,
+            |b, &concurrency| {
+                b.iter(|| {
+                    rt.block_on(async {
+                        let tasks = (0..concurrency)
+                            .map(|_| tokio::spawn(round_trip()))
+                            .collect::<Vec<_>>();
+
+                        for task in tasks {
+                            task.await.expect("Round trip task panicked");
+                        }
+                    })
+                })
+            },
+        );
+    }
+
+    group.finish();
+}
+
+fn bench_round_trips(c: &mut Criterion) {
+    let rt = Runtime::new().expect("Failed to build runtime");
+    let port = start_broker();
+    let agent = rt.block_on(async { start_agent(port) });
+    let dispatcher = Arc::new(Dispatcher::new(&agent));
+    let store = Arc::new(BaselineStore::default());
+    let to = Arc::new(AccountId::new("service", "svc.example.org"));
+
+    bench_group(c, &rt, "dispatcher_round_trip", || {
+        let dispatcher = dispatcher.clone();
+        let to = to.clone();
+        async move { round_trip(&dispatcher, &to).await }
+    });
+
+    bench_group(c, &rt, "baseline_round_trip", || {
+        let agent = agent.clone();
+        let store = store.clone();
+        let to = to.clone();
+        async move { baseline_round_trip(&agent, &store, &to).await }
+    });
+}
+
+criterion_group!(benches, bench_round_trips);
+criterion_main!(benches);
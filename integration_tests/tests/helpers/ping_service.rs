@@ -34,7 +34,8 @@ pub(crate) fn run(init_tx: mpsc::Sender<()>) {
             QoS::AtLeastOnce,
             Some(&SharedGroup::new("loadbalancer", account_id)),
         )
-        .expect("Error subscribing to multicast requests");
+        .expect("Error subscribing to multicast requests")
+        .detach();
 
     match rx.recv_timeout(Duration::from_secs(5)) {
         Ok(AgentNotification::Connack(_)) => (),
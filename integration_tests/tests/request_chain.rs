@@ -56,7 +56,8 @@ fn run_service_a(init_tx: mpsc::Sender<()>) {
 
     agent
         .subscribe(&req_subscription, QoS::AtLeastOnce, Some(&group))
-        .expect("Error subscribing to multicast requests in service A");
+        .expect("Error subscribing to multicast requests in service A")
+        .detach();
 
     match rx.recv_timeout(Duration::from_secs(5)) {
         Ok(AgentNotification::Connack(_)) => (),
@@ -76,7 +77,8 @@ fn run_service_a(init_tx: mpsc::Sender<()>) {
 
     agent
         .subscribe(&resp_subscription, QoS::AtLeastOnce, None)
-        .expect("Error subscribing to unicast responses in service A");
+        .expect("Error subscribing to unicast responses in service A")
+        .detach();
 
     match rx.recv_timeout(Duration::from_secs(5)) {
         Ok(AgentNotification::Suback(_)) => (),
@@ -202,7 +204,8 @@ fn run_service_b(init_tx: mpsc::Sender<()>) {
 
     agent
         .subscribe(&subscription, QoS::AtLeastOnce, Some(&group))
-        .expect("Error subscribing to multicast requests in service B");
+        .expect("Error subscribing to multicast requests in service B")
+        .detach();
 
     match rx.recv_timeout(Duration::from_secs(5)) {
         Ok(AgentNotification::Connack(_)) => (),
@@ -227,6 +230,9 @@ fn run_service_b(init_tx: mpsc::Sender<()>) {
         match message {
             IncomingMessage::Request(req) => match IncomingRequest::convert::<JsonValue>(req) {
                 Ok(request) => {
+                    // The client's deadline passes through service A.
+                    assert!(request.properties().deadline().is_some());
+
                     let props = request.properties().to_response(
                         ResponseStatus::OK,
                         ShortTermTimingProperties::new(Utc::now()),
@@ -285,7 +291,8 @@ fn request_chain() {
 
     agent
         .subscribe(&subscription, QoS::AtLeastOnce, None)
-        .expect("Error subscribing to unicast responses");
+        .expect("Error subscribing to unicast responses")
+        .detach();
 
     match rx.recv_timeout(Duration::from_secs(5)) {
         Ok(AgentNotification::Connack(_)) => (),
@@ -306,13 +313,15 @@ fn request_chain() {
 
     let correlation_data = generate_correlation_data();
 
-    let reqp = OutgoingRequestProperties::new(
+    let mut reqp = OutgoingRequestProperties::new(
         "a",
         &response_topic,
         &correlation_data,
         ShortTermTimingProperties::new(Utc::now()),
     );
 
+    reqp.set_deadline(Utc::now() + ChronoDuration::seconds(30));
+
     let request = OutgoingRequest::multicast(json!({}), reqp, &service_a_account_id, "v1");
 
     agent.publish(request).expect("Failed to publish request");
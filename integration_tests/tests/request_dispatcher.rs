@@ -55,7 +55,8 @@ fn request_dispatcher() {
 
     agent
         .subscribe(&subscription, QoS::AtLeastOnce, None)
-        .expect("Error subscribing to unicast responses");
+        .expect("Error subscribing to unicast responses")
+        .detach();
         
     match rx.recv_timeout(Duration::from_secs(5)) {
             Ok(AgentNotification::Connack(_)) => (),
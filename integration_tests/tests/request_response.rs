@@ -47,7 +47,8 @@ fn request_response() {
 
     agent
         .subscribe(&subscription, QoS::AtLeastOnce, None)
-        .expect("Error subscribing to unicast responses");
+        .expect("Error subscribing to unicast responses")
+        .detach();
 
     match rx.recv_timeout(Duration::from_secs(5)) {
         Ok(AgentNotification::Connack(_)) => (),
@@ -0,0 +1,352 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+use std::time::Instant;
+
+use futures::future::{self, BoxFuture, FutureExt};
+use log::error;
+use serde::de::DeserializeOwned;
+
+use crate::{
+    mqtt::{agent::error_payload, Agent, IncomingPayload, IncomingRequest, ResponseStatus},
+    AccountId, Authenticable, Error,
+};
+
+/// What's being authorized: an account calling a method on an object.
+#[derive(Debug, Clone, Copy)]
+pub struct AuthorizationRequest<'a> {
+    account_id: &'a AccountId,
+    method: &'a str,
+    object: &'a [String],
+}
+
+impl<'a> AuthorizationRequest<'a> {
+    pub fn account_id(&self) -> &'a AccountId {
+        self.account_id
+    }
+
+    pub fn method(&self) -> &'a str {
+        self.method
+    }
+
+    /// Path of the object the request is about, e.g. `["rooms", "<room_id>", "events"]`.
+    pub fn object(&self) -> &'a [String] {
+        self.object
+    }
+}
+
+/// Decides whether an incoming request is allowed.
+///
+/// Returns `Ok(false)` to deny the request and an error if the decision can't be made.
+/// Plain functions and closures taking [AuthorizationRequest](struct.AuthorizationRequest.html)
+/// and returning `Result<bool, Error>` are synchronous authorizers. Implement the trait to call
+/// an authorization service asynchronously.
+///
+/// # Example
+///
+/// ```
+/// struct AuthzService(Client);
+///
+/// impl Authorizer for AuthzService {
+///     fn authorize<'a>(
+///         &'a self,
+///         request: AuthorizationRequest<'a>,
+///     ) -> BoxFuture<'a, Result<bool, Error>> {
+///         async move {
+///             self.0
+///                 .authorize(request.account_id(), request.object(), request.method())
+///                 .await
+///         }
+///         .boxed()
+///     }
+/// }
+/// ```
+pub trait Authorizer: Send + Sync {
+    fn authorize<'a>(
+        &'a self,
+        request: AuthorizationRequest<'a>,
+    ) -> BoxFuture<'a, Result<bool, Error>>;
+}
+
+impl<F> Authorizer for F
+where
+    F: Fn(AuthorizationRequest<'_>) -> Result<bool, Error> + Send + Sync,
+{
+    fn authorize<'a>(
+        &'a self,
+        request: AuthorizationRequest<'a>,
+    ) -> BoxFuture<'a, Result<bool, Error>> {
+        future::ready(self(request)).boxed()
+    }
+}
+
+type ObjectFn =
+    Box<dyn Fn(&IncomingRequest<IncomingPayload>) -> Result<Vec<String>, Error> + Send + Sync>;
+
+/// Authorization of incoming requests.
+///
+/// Requests are passed to the [Authorizer](trait.Authorizer.html) along with the caller's
+/// account, the method and the object path derived from the payload by the function registered
+/// for the method. Requests of methods with no registered function have an empty object path.
+///
+/// Denied requests get answered with `403 Forbidden` and never reach the application.
+/// Requests failing to authorize get answered with `500 Internal Server Error` and ones
+/// with payloads the object can't be derived from with `400 Bad Request`.
+///
+/// The time spent on authorization is carried over to the messages built of the request with
+/// [to_response](../mqtt/type.IncomingRequest.html#method.to_response) and similar methods
+/// so `cumulative_authorization_time` stays accurate.
+///
+/// Requests are authorized concurrently so they may reach the application in a different
+/// order than received.
+///
+/// Attach it to the agent with
+/// [AgentBuilder::authorization](../mqtt/struct.AgentBuilder.html#method.authorization).
+///
+/// # Example
+///
+/// ```
+/// let authorization = Authorization::new(|request: AuthorizationRequest| {
+///     Ok(request.account_id().audience() == "svc.example.org")
+/// })
+/// .object("room.enter", |payload: RoomEnter| {
+///     vec!["rooms".to_owned(), payload.id.to_string()]
+/// });
+/// ```
+#[derive(Clone)]
+pub struct Authorization {
+    authorizer: Arc<dyn Authorizer>,
+    objects: Arc<HashMap<String, ObjectFn>>,
+}
+
+impl fmt::Debug for Authorization {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Authorization")
+            .field("objects", &self.objects.keys().collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+impl Authorization {
+    pub fn new<A: Authorizer + 'static>(authorizer: A) -> Self {
+        Self {
+            authorizer: Arc::new(authorizer),
+            objects: Arc::new(HashMap::new()),
+        }
+    }
+
+    /// Registers a function deriving the object path of requests with the `method`
+    /// of their payload.
+    ///
+    /// # Arguments
+    ///
+    /// * `method` – request method.
+    /// * `object` – function taking the payload of type `T` and returning the object path.
+    pub fn object<T, F>(mut self, method: &str, object: F) -> Self
+    where
+        T: DeserializeOwned,
+        F: Fn(T) -> Vec<String> + Send + Sync + 'static,
+    {
+        let object = move |request: &IncomingRequest<IncomingPayload>| {
+            let payload = request
+                .properties()
+                .codec()?
+                .decode::<T>(request.payload())?;
+
+            Ok(object(payload))
+        };
+
+        // Not shared yet since the builder takes the ownership.
+        if let Some(objects) = Arc::get_mut(&mut self.objects) {
+            objects.insert(method.to_owned(), Box::new(object));
+        }
+
+        self
+    }
+
+    /// Authorizes the request recording the time spent on it.
+    ///
+    /// Returns the request if it's allowed. Otherwise answers it on behalf of the application.
+    pub(crate) async fn authorize(
+        &self,
+        mut request: IncomingRequest<IncomingPayload>,
+        agent: &mut Agent,
+    ) -> Option<IncomingRequest<IncomingPayload>> {
+        let start = Instant::now();
+        let method = request.properties().method().to_owned();
+
+        let object = match self.objects.get(&method) {
+            Some(object) => match object(&request) {
+                Ok(object) => object,
+                Err(err) => {
+                    let payload = error_payload(ResponseStatus::BAD_REQUEST, &err.to_string());
+                    agent.reject_request(&request, ResponseStatus::BAD_REQUEST, payload);
+                    return None;
+                }
+            },
+            None => vec![],
+        };
+
+        let account_id = request.properties().as_account_id().to_owned();
+
+        let authz_request = AuthorizationRequest {
+            account_id: &account_id,
+            method: &method,
+            object: &object,
+        };
+
+        let result = self.authorizer.authorize(authz_request).await;
+
+        let authorization_time = chrono::Duration::from_std(start.elapsed())
+            .unwrap_or_else(|_| chrono::Duration::zero());
+
+        request
+            .properties_mut()
+            .set_authorization_time(authorization_time);
+
+        let (status, detail) = match result {
+            Ok(true) => return Some(request),
+            Ok(false) => (
+                ResponseStatus::FORBIDDEN,
+                format!(
+                    "account = '{}' is not allowed to call method = '{}' on object = '{}'",
+                    account_id,
+                    method,
+                    object.join("/")
+                ),
+            ),
+            Err(err) => {
+                error!(
+                    "Failed to authorize request with method = '{}': {}",
+                    method, err
+                );
+
+                (
+                    ResponseStatus::INTERNAL_SERVER_ERROR,
+                    format!("failed to authorize request, {}", err),
+                )
+            }
+        };
+
+        agent.reject_request(&request, status, error_payload(status, &detail));
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use serde::Deserialize;
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::mqtt::testing::{published, request};
+
+    #[derive(Deserialize)]
+    struct RoomEnter {
+        id: String,
+    }
+
+    fn authorization(result: Result<bool, &'static str>) -> Authorization {
+        Authorization::new(move |_: AuthorizationRequest| result.map_err(Error::new))
+            .object("room.enter", |payload: RoomEnter| {
+                vec!["rooms".to_owned(), payload.id]
+            })
+    }
+
+    /// Returns the status and the payload of the only response published by the agent.
+    fn rejection(rx: &async_channel::Receiver<rumqttc::Request>) -> (String, Value) {
+        let messages = published(rx);
+        assert_eq!(messages.len(), 1);
+
+        let envelope = &messages[0].1;
+        let payload = envelope["payload"].as_str().unwrap();
+
+        (
+            envelope["properties"]["status"]
+                .as_str()
+                .unwrap()
+                .to_owned(),
+            serde_json::from_str(payload).unwrap(),
+        )
+    }
+
+    #[tokio::test]
+    async fn passes_allowed_request_with_authorization_time() {
+        let (mut agent, rx) = Agent::stub();
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let calls_ = calls.clone();
+
+        let authorization = Authorization::new(move |request: AuthorizationRequest| {
+            calls_.lock().unwrap().push((
+                request.account_id().to_string(),
+                request.method().to_owned(),
+                request.object().to_vec(),
+            ));
+
+            Ok(true)
+        })
+        .object("room.enter", |payload: RoomEnter| {
+            vec!["rooms".to_owned(), payload.id]
+        });
+
+        let req = request("room.enter", "corr", json!({ "id": "123" }));
+        let req = authorization.authorize(req, &mut agent).await.unwrap();
+
+        assert!(req.properties().authorization_time().is_some());
+        assert!(published(&rx).is_empty());
+
+        let account_id = AccountId::new("sender", "svc.example.org").to_string();
+        let object = vec!["rooms".to_owned(), "123".to_owned()];
+
+        assert_eq!(
+            *calls.lock().unwrap(),
+            vec![(account_id, "room.enter".to_owned(), object)]
+        );
+    }
+
+    #[tokio::test]
+    async fn rejects_denied_request() {
+        let (mut agent, rx) = Agent::stub();
+        let req = request("room.enter", "corr", json!({ "id": "123" }));
+        assert!(authorization(Ok(false))
+            .authorize(req, &mut agent)
+            .await
+            .is_none());
+
+        let (status, payload) = rejection(&rx);
+        assert_eq!(status, "403");
+        assert_eq!(payload["status"], 403);
+        assert!(payload["detail"].as_str().unwrap().contains("rooms/123"));
+    }
+
+    #[tokio::test]
+    async fn rejects_request_failing_to_authorize() {
+        let (mut agent, rx) = Agent::stub();
+        let req = request("room.leave", "corr", json!({}));
+        let authorization = authorization(Err("authz is down"));
+        assert!(authorization.authorize(req, &mut agent).await.is_none());
+
+        let (status, payload) = rejection(&rx);
+        assert_eq!(status, "500");
+        assert!(payload["detail"]
+            .as_str()
+            .unwrap()
+            .contains("authz is down"));
+    }
+
+    #[tokio::test]
+    async fn rejects_request_with_payload_missing_object() {
+        let (mut agent, rx) = Agent::stub();
+        let req = request("room.enter", "corr", json!({ "room": "123" }));
+        assert!(authorization(Ok(true))
+            .authorize(req, &mut agent)
+            .await
+            .is_none());
+
+        let (status, payload) = rejection(&rx);
+        assert_eq!(status, "400");
+        assert_eq!(payload["status"], 400);
+    }
+}
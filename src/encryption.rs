@@ -0,0 +1,637 @@
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::fmt;
+use std::sync::Arc;
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chacha20poly1305::{
+    aead::{Aead, AeadCore, KeyInit, OsRng, Payload},
+    ChaCha20Poly1305, Key, Nonce,
+};
+use hkdf::Hkdf;
+use rumqttc::Publish;
+use serde::{Deserialize, Serialize};
+use serde_json::{value::RawValue, Map, Value};
+use sha2::Sha256;
+use x25519_dalek::{EphemeralSecret, StaticSecret};
+
+use crate::{
+    mqtt::compat::{OutgoingEnvelope, RAW_PAYLOAD_FORMAT},
+    AccountId, Error,
+};
+
+const ALGORITHM_PROPERTY: &str = "encryption";
+const KEY_ID_PROPERTY: &str = "encryption_key_id";
+const EPHEMERAL_KEY_PROPERTY: &str = "encryption_ephemeral_key";
+const PAYLOAD_FORMAT_PROPERTY: &str = "encryption_payload_format";
+
+/// Payload encrypted to the recipient's public key with a key agreed over an ephemeral one.
+const ACCOUNT_ALGORITHM: &str = "x25519-chacha20poly1305";
+/// Payload encrypted with a shared group key.
+const GROUP_ALGORITHM: &str = "chacha20poly1305";
+
+/// Versions the key derivation.
+const KDF_INFO: &[u8] = b"svc-agent-encryption-v1";
+
+const NONCE_SIZE: usize = 12;
+
+/// Whom to encrypt the payload of an outgoing message for.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Recipient {
+    /// Agents of the account having its secret key.
+    Account(AccountId),
+    /// Agents having the shared group key with the id.
+    Group(String),
+}
+
+/// X25519 secret key of the agent's account to decrypt payloads encrypted for the account.
+#[derive(Clone)]
+pub struct SecretKey(StaticSecret);
+
+impl SecretKey {
+    /// Generates a random key.
+    pub fn generate() -> Self {
+        Self(StaticSecret::random_from_rng(OsRng))
+    }
+
+    pub fn from_bytes(bytes: [u8; 32]) -> Self {
+        Self(StaticSecret::from(bytes))
+    }
+
+    pub fn to_bytes(&self) -> [u8; 32] {
+        self.0.to_bytes()
+    }
+
+    /// Returns the public key to share with the senders.
+    pub fn public_key(&self) -> PublicKey {
+        PublicKey(x25519_dalek::PublicKey::from(&self.0))
+    }
+}
+
+impl fmt::Debug for SecretKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "SecretKey(..)")
+    }
+}
+
+/// X25519 public key of an account to encrypt payloads for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PublicKey(x25519_dalek::PublicKey);
+
+impl PublicKey {
+    pub fn from_bytes(bytes: [u8; 32]) -> Self {
+        Self(x25519_dalek::PublicKey::from(bytes))
+    }
+
+    pub fn to_bytes(&self) -> [u8; 32] {
+        self.0.to_bytes()
+    }
+}
+
+/// Looks up public keys of recipient accounts.
+///
+/// Implement it to fetch keys from a database or a directory service.
+/// A `HashMap` of keys works as a static store.
+pub trait PublicKeyStore: Send + Sync {
+    fn public_key(&self, account_id: &AccountId) -> Option<PublicKey>;
+}
+
+impl PublicKeyStore for HashMap<AccountId, PublicKey> {
+    fn public_key(&self, account_id: &AccountId) -> Option<PublicKey> {
+        self.get(account_id).copied()
+    }
+}
+
+/// A reason of an incoming encrypted payload failing to decrypt.
+#[derive(Debug, Clone, PartialEq)]
+pub enum EncryptionError {
+    /// The payload is encrypted for another account or with an unknown group key.
+    UnknownKey(String),
+    /// The payload has been tampered with or encrypted with another key.
+    Invalid(String),
+    /// The message or its encryption properties can't be parsed.
+    Malformed(String),
+}
+
+impl fmt::Display for EncryptionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnknownKey(key_id) => write!(f, "no key to decrypt payload, key = '{}'", key_id),
+            Self::Invalid(key_id) => {
+                write!(f, "failed to decrypt payload with key = '{}'", key_id)
+            }
+            Self::Malformed(reason) => write!(f, "malformed encrypted message, {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for EncryptionError {}
+
+/// End-to-end payload encryption settings.
+///
+/// Payloads of outgoing messages marked with `encrypt_for` on their properties get encrypted
+/// with ChaCha20-Poly1305 so neither the broker nor observer agents could read them.
+/// A payload for a [Recipient::Account](enum.Recipient.html#variant.Account) is encrypted
+/// with a key agreed between a one-off X25519 key and the account's public key
+/// looked up in the [PublicKeyStore](trait.PublicKeyStore.html). A payload for
+/// a [Recipient::Group](enum.Recipient.html#variant.Group) is encrypted with the shared
+/// group key which is handy for broadcast events.
+///
+/// The envelope properties stay in clear so routing keeps working. The encryption details are
+/// passed in `encryption`, `encryption_key_id` and `encryption_ephemeral_key` properties.
+/// Encrypted payloads are always serialized to a string even if
+/// [raw payloads](../mqtt/struct.AgentBuilder.html#method.raw_payload) are enabled.
+/// The original `payload_format` is moved to `encryption_payload_format` property then
+/// and restored on decryption.
+///
+/// Incoming payloads get decrypted before they get to the application. Messages failing to
+/// decrypt are reported as
+/// [AgentNotification::UndecryptableMessage](../mqtt/enum.AgentNotification.html#variant.UndecryptableMessage)
+/// instead.
+///
+/// Attach it to the agent with
+/// [AgentBuilder::encryption](../mqtt/struct.AgentBuilder.html#method.encryption).
+///
+/// # Example
+///
+/// ```
+/// let mut public_keys = HashMap::new();
+/// public_keys.insert(profile_account_id, PublicKey::from_bytes(profile_public_key));
+///
+/// let encryption = Encryption::new()
+///     .secret_key(SecretKey::from_bytes(secret))
+///     .public_keys(public_keys)
+///     .group_key("rooms", group_key);
+///
+/// let mut props = OutgoingRequestProperties::new(/* ... */);
+/// props.encrypt_for(Recipient::Account(profile_account_id));
+/// ```
+#[derive(Clone, Default)]
+pub struct Encryption {
+    secret_key: Option<SecretKey>,
+    public_keys: Option<Arc<dyn PublicKeyStore>>,
+    group_keys: HashMap<String, [u8; 32]>,
+}
+
+impl fmt::Debug for Encryption {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Encryption")
+            .field("secret_key", &self.secret_key)
+            .field("public_keys", &self.public_keys.is_some())
+            .field("group_keys", &self.group_keys.keys().collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+#[derive(Deserialize)]
+struct EncryptedMarker {
+    properties: EncryptedMarkerProperties,
+}
+
+#[derive(Deserialize)]
+struct EncryptedMarkerProperties {
+    encryption: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct EncryptedEnvelope {
+    payload: String,
+    properties: Map<String, Value>,
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+enum DecryptedPayload {
+    String(String),
+    Raw(Box<RawValue>),
+}
+
+#[derive(Serialize)]
+struct DecryptedEnvelope {
+    payload: DecryptedPayload,
+    properties: Map<String, Value>,
+}
+
+impl Encryption {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the secret key of the agent's account to decrypt payloads encrypted for it.
+    pub fn secret_key(self, secret_key: SecretKey) -> Self {
+        Self {
+            secret_key: Some(secret_key),
+            ..self
+        }
+    }
+
+    /// Looks up public keys of recipient accounts in the `public_keys` store.
+    pub fn public_keys<K: PublicKeyStore + 'static>(self, public_keys: K) -> Self {
+        Self {
+            public_keys: Some(Arc::new(public_keys)),
+            ..self
+        }
+    }
+
+    /// Adds a shared group key to encrypt and decrypt payloads for the group `id`.
+    pub fn group_key(mut self, id: &str, key: [u8; 32]) -> Self {
+        self.group_keys.insert(id.to_owned(), key);
+        self
+    }
+
+    /// Encrypts the envelope payload if the message has a recipient.
+    pub(crate) fn encrypt(&self, envelope: &mut OutgoingEnvelope) -> Result<(), Error> {
+        let recipient = match envelope.properties.recipient() {
+            Some(recipient) => recipient.to_owned(),
+            None => return Ok(()),
+        };
+
+        let (algorithm, key_id, key, ephemeral_key) = match recipient {
+            Recipient::Account(account_id) => {
+                let public_key = self
+                    .public_keys
+                    .as_ref()
+                    .and_then(|public_keys| public_keys.public_key(&account_id))
+                    .ok_or_else(|| {
+                        Error::new(&format!(
+                            "no public key to encrypt payload for account = '{}'",
+                            account_id
+                        ))
+                    })?;
+
+                let ephemeral_secret = EphemeralSecret::random_from_rng(OsRng);
+                let ephemeral_key = PublicKey(x25519_dalek::PublicKey::from(&ephemeral_secret));
+                let shared_secret = ephemeral_secret.diffie_hellman(&public_key.0);
+
+                if !shared_secret.was_contributory() {
+                    return Err(Error::new(&format!(
+                        "invalid public key of account = '{}'",
+                        account_id
+                    )));
+                }
+
+                let key = derive_key(shared_secret.as_bytes(), &ephemeral_key, &public_key)?;
+
+                let ephemeral_key = Some(STANDARD.encode(ephemeral_key.to_bytes()));
+                (
+                    ACCOUNT_ALGORITHM,
+                    account_id.to_string(),
+                    key,
+                    ephemeral_key,
+                )
+            }
+            Recipient::Group(id) => {
+                let key = self.group_keys.get(&id).copied().ok_or_else(|| {
+                    Error::new(&format!("no key to encrypt payload for group = '{}'", id))
+                })?;
+
+                (GROUP_ALGORITHM, id, key, None)
+            }
+        };
+
+        let payload_format = envelope.payload_format();
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let aad = associated_data(algorithm, &key_id, ephemeral_key.as_deref(), payload_format);
+
+        let payload = Payload {
+            msg: envelope.payload_str().as_bytes(),
+            aad: &aad,
+        };
+
+        let ciphertext = ChaCha20Poly1305::new(Key::from_slice(&key))
+            .encrypt(&nonce, payload)
+            .map_err(|e| Error::new(&format!("error encrypting payload, {}", e)))?;
+
+        let mut sealed = Vec::with_capacity(NONCE_SIZE + ciphertext.len());
+        sealed.extend_from_slice(&nonce);
+        sealed.extend_from_slice(&ciphertext);
+
+        envelope.set_payload(STANDARD.encode(sealed));
+        envelope.insert_property(ALGORITHM_PROPERTY, Value::from(algorithm));
+        envelope.insert_property(KEY_ID_PROPERTY, Value::from(key_id));
+
+        if let Some(ephemeral_key) = ephemeral_key {
+            envelope.insert_property(EPHEMERAL_KEY_PROPERTY, Value::from(ephemeral_key));
+        }
+
+        if let Some(payload_format) = payload_format {
+            envelope.insert_property(PAYLOAD_FORMAT_PROPERTY, Value::from(payload_format));
+        }
+
+        Ok(())
+    }
+
+    /// Decrypts the payload of an incoming packet encrypted for the `account_id`.
+    ///
+    /// Returns the packet as is if its payload is not encrypted.
+    pub(crate) fn decrypt(
+        &self,
+        publish: Publish,
+        account_id: &AccountId,
+    ) -> Result<Publish, EncryptionError> {
+        match serde_json::from_slice::<EncryptedMarker>(&publish.payload) {
+            Ok(EncryptedMarker {
+                properties:
+                    EncryptedMarkerProperties {
+                        encryption: Some(_),
+                    },
+            }) => (),
+            _ => return Ok(publish),
+        }
+
+        let malformed = |e: &dyn fmt::Display| EncryptionError::Malformed(e.to_string());
+
+        let mut envelope = serde_json::from_slice::<EncryptedEnvelope>(&publish.payload)
+            .map_err(|e| malformed(&e))?;
+
+        let properties = &mut envelope.properties;
+        let algorithm = take_property(properties, ALGORITHM_PROPERTY)?;
+        let key_id = take_property(properties, KEY_ID_PROPERTY)?;
+        let ephemeral_key = match algorithm.as_str() {
+            ACCOUNT_ALGORITHM => Some(take_property(properties, EPHEMERAL_KEY_PROPERTY)?),
+            _ => None,
+        };
+        let payload_format = match properties.remove(PAYLOAD_FORMAT_PROPERTY) {
+            Some(Value::String(payload_format)) => Some(payload_format),
+            Some(_) => return Err(malformed(&"payload format is not a string")),
+            None => None,
+        };
+
+        let key = match algorithm.as_str() {
+            ACCOUNT_ALGORITHM => {
+                let secret_key = match self.secret_key {
+                    Some(ref secret_key) if key_id == account_id.to_string() => secret_key,
+                    _ => return Err(EncryptionError::UnknownKey(key_id)),
+                };
+
+                let ephemeral_key = ephemeral_key.as_deref().unwrap_or_default();
+                let ephemeral_key = decode_base64(ephemeral_key)?
+                    .try_into()
+                    .map(PublicKey::from_bytes)
+                    .map_err(|_| malformed(&"invalid ephemeral key"))?;
+
+                let shared_secret = secret_key.0.diffie_hellman(&ephemeral_key.0);
+
+                if !shared_secret.was_contributory() {
+                    return Err(EncryptionError::Invalid(key_id));
+                }
+
+                derive_key(
+                    shared_secret.as_bytes(),
+                    &ephemeral_key,
+                    &secret_key.public_key(),
+                )
+                .map_err(|e| malformed(&e))?
+            }
+            GROUP_ALGORITHM => match self.group_keys.get(&key_id) {
+                Some(key) => *key,
+                None => return Err(EncryptionError::UnknownKey(key_id)),
+            },
+            _ => {
+                return Err(malformed(&format!(
+                    "unsupported encryption = '{}'",
+                    algorithm
+                )))
+            }
+        };
+
+        let sealed = decode_base64(&envelope.payload)?;
+
+        if sealed.len() < NONCE_SIZE {
+            return Err(malformed(&"payload is too short"));
+        }
+
+        let (nonce, ciphertext) = sealed.split_at(NONCE_SIZE);
+        let aad = associated_data(
+            &algorithm,
+            &key_id,
+            ephemeral_key.as_deref(),
+            payload_format.as_deref(),
+        );
+
+        let payload = Payload {
+            msg: ciphertext,
+            aad: &aad,
+        };
+
+        let plaintext = ChaCha20Poly1305::new(Key::from_slice(&key))
+            .decrypt(Nonce::from_slice(nonce), payload)
+            .map_err(|_| EncryptionError::Invalid(key_id))?;
+
+        let plaintext = String::from_utf8(plaintext).map_err(|e| malformed(&e))?;
+
+        let payload = match payload_format {
+            Some(payload_format) if payload_format == RAW_PAYLOAD_FORMAT => {
+                envelope
+                    .properties
+                    .insert("payload_format".to_owned(), Value::from(payload_format));
+
+                DecryptedPayload::Raw(RawValue::from_string(plaintext).map_err(|e| malformed(&e))?)
+            }
+            Some(payload_format) => {
+                return Err(malformed(&format!(
+                    "unsupported payload format = '{}'",
+                    payload_format
+                )))
+            }
+            None => DecryptedPayload::String(plaintext),
+        };
+
+        let envelope = DecryptedEnvelope {
+            payload,
+            properties: envelope.properties,
+        };
+
+        let payload = serde_json::to_vec(&envelope).map_err(|e| malformed(&e))?;
+
+        Ok(Publish {
+            payload: payload.into(),
+            ..publish
+        })
+    }
+}
+
+/// Derives a symmetric key of the X25519 shared secret bound to both public keys.
+fn derive_key(
+    shared_secret: &[u8],
+    ephemeral_key: &PublicKey,
+    public_key: &PublicKey,
+) -> Result<[u8; 32], Error> {
+    let mut salt = [0; 64];
+    salt[..32].copy_from_slice(ephemeral_key.0.as_bytes());
+    salt[32..].copy_from_slice(public_key.0.as_bytes());
+
+    let mut key = [0; 32];
+
+    Hkdf::<Sha256>::new(Some(&salt), shared_secret)
+        .expand(KDF_INFO, &mut key)
+        .map_err(|e| Error::new(&format!("error deriving encryption key, {}", e)))?;
+
+    Ok(key)
+}
+
+/// Binds the ciphertext to the encryption properties so they can't be swapped.
+fn associated_data(
+    algorithm: &str,
+    key_id: &str,
+    ephemeral_key: Option<&str>,
+    payload_format: Option<&str>,
+) -> Vec<u8> {
+    serde_json::json!([algorithm, key_id, ephemeral_key, payload_format])
+        .to_string()
+        .into_bytes()
+}
+
+fn decode_base64(value: &str) -> Result<Vec<u8>, EncryptionError> {
+    STANDARD
+        .decode(value)
+        .map_err(|e| EncryptionError::Malformed(format!("invalid base64, {}", e)))
+}
+
+fn take_property(
+    properties: &mut Map<String, Value>,
+    key: &str,
+) -> Result<String, EncryptionError> {
+    match properties.remove(key) {
+        Some(Value::String(value)) => Ok(value),
+        _ => Err(EncryptionError::Malformed(format!(
+            "missing property = '{}'",
+            key
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+    use rumqttc::QoS;
+    use serde_json::json;
+
+    use super::*;
+    use crate::mqtt::{
+        publishable::DumpOptions, Address, OutgoingEvent, OutgoingEventProperties,
+        OutgoingShortTermTimingProperties,
+    };
+    use crate::AgentId;
+
+    fn recipient() -> AccountId {
+        AccountId::new("profile", "svc.example.org")
+    }
+
+    /// Publishes an event encrypted by the `encryption` for the `recipient`.
+    fn publish(encryption: Encryption, recipient: Option<Recipient>) -> Result<Publish, Error> {
+        let account_id = AccountId::new("sender", "svc.example.org");
+        let address = Address::new(AgentId::new("instance01", account_id), "v1");
+
+        let options = DumpOptions {
+            encryption: Some(Arc::new(encryption)),
+            ..DumpOptions::default()
+        };
+
+        let timing = OutgoingShortTermTimingProperties::new(Utc::now());
+        let mut props = OutgoingEventProperties::new("profile.update", timing);
+
+        if let Some(recipient) = recipient {
+            props.encrypt_for(recipient);
+        }
+
+        let message = OutgoingEvent::broadcast(json!({ "name": "John" }), props, "profiles");
+        let dump = message.into_dumps(&address, &options)?.remove(0);
+        Ok(Publish::new(dump.topic(), QoS::AtLeastOnce, dump.payload()))
+    }
+
+    fn envelope(publish: &Publish) -> Value {
+        serde_json::from_slice(&publish.payload).unwrap()
+    }
+
+    #[test]
+    fn decrypts_payload_for_account() {
+        let secret_key = SecretKey::generate();
+        let mut public_keys = HashMap::new();
+        public_keys.insert(recipient(), secret_key.public_key());
+
+        let sender = Encryption::new().public_keys(public_keys);
+        let recipient_account = Recipient::Account(recipient());
+        let publish = publish(sender, Some(recipient_account)).unwrap();
+
+        let encrypted = envelope(&publish);
+        assert_eq!(encrypted["properties"]["encryption"], ACCOUNT_ALGORITHM);
+        assert_eq!(encrypted["properties"]["label"], "profile.update");
+        assert!(!encrypted["payload"].as_str().unwrap().contains("John"));
+
+        let receiver = Encryption::new().secret_key(secret_key);
+        let decrypted = envelope(&receiver.decrypt(publish, &recipient()).unwrap());
+        assert_eq!(decrypted["payload"], r#"{"name":"John"}"#);
+        assert!(decrypted["properties"].get("encryption").is_none());
+    }
+
+    #[test]
+    fn rejects_payload_for_another_account() {
+        let secret_key = SecretKey::generate();
+        let mut public_keys = HashMap::new();
+        public_keys.insert(recipient(), secret_key.public_key());
+
+        let sender = Encryption::new().public_keys(public_keys);
+        let publish = publish(sender, Some(Recipient::Account(recipient()))).unwrap();
+
+        let receiver = Encryption::new().secret_key(secret_key);
+        let account_id = AccountId::new("observer", "svc.example.org");
+
+        assert_eq!(
+            receiver.decrypt(publish, &account_id).err(),
+            Some(EncryptionError::UnknownKey(recipient().to_string()))
+        );
+    }
+
+    #[test]
+    fn rejects_payload_encrypted_with_another_key() {
+        let sender = Encryption::new().group_key("rooms", [7; 32]);
+        let publish = publish(sender, Some(Recipient::Group("rooms".to_owned()))).unwrap();
+
+        let receiver = Encryption::new().group_key("rooms", [8; 32]);
+
+        assert_eq!(
+            receiver.decrypt(publish, &recipient()).err(),
+            Some(EncryptionError::Invalid("rooms".to_owned()))
+        );
+    }
+
+    #[test]
+    fn rejects_swapped_encryption_properties() {
+        let encryption = Encryption::new()
+            .group_key("rooms", [7; 32])
+            .group_key("classes", [7; 32]);
+
+        let publish = publish(
+            encryption.clone(),
+            Some(Recipient::Group("rooms".to_owned())),
+        )
+        .unwrap();
+
+        let mut envelope = envelope(&publish);
+        envelope["properties"]["encryption_key_id"] = json!("classes");
+        let publish = Publish::new("profiles", QoS::AtLeastOnce, envelope.to_string());
+
+        assert_eq!(
+            encryption.decrypt(publish, &recipient()).err(),
+            Some(EncryptionError::Invalid("classes".to_owned()))
+        );
+    }
+
+    #[test]
+    fn passes_plain_payload_as_is() {
+        let encryption = Encryption::new().group_key("rooms", [7; 32]);
+        let publish = publish(encryption.clone(), None).unwrap();
+        let payload = publish.payload.clone();
+
+        let decrypted = encryption.decrypt(publish, &recipient()).unwrap();
+        assert_eq!(decrypted.payload, payload);
+    }
+
+    #[test]
+    fn fails_to_encrypt_for_unknown_recipient() {
+        let encryption = Encryption::new();
+        assert!(publish(encryption.clone(), Some(Recipient::Account(recipient()))).is_err());
+        assert!(publish(encryption, Some(Recipient::Group("rooms".to_owned()))).is_err());
+    }
+}
@@ -304,7 +304,7 @@ impl FromStr for SharedGroup {
 /// * `ACCOUNT_ID` – destination [AccountId](struct.AccountId) (no specific agent).
 /// * `AGENT_ID` – destination [AgentId](struct.AgentId).
 /// * `VER` – destination agent version.
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum Destination {
     /// Publish a message to each of the topic subscribers.
     ///
@@ -651,9 +651,17 @@ impl<'a> ResponseSubscription<'a> {
 pub use svc_authn::{AccountId, Authenticable};
 
 pub use self::error::Error;
+pub mod authz;
+#[cfg(feature = "encryption")]
+pub mod encryption;
 pub mod error;
 pub mod mqtt;
 #[cfg(feature = "queue-counter")]
 pub mod queue_counter;
 pub mod request;
+pub mod router;
+#[cfg(feature = "json-schema")]
+pub mod schema;
 pub(crate) mod serde;
+#[cfg(feature = "signing")]
+pub mod signing;
@@ -1,20 +1,40 @@
 use std::fmt;
 use std::str::FromStr;
+#[cfg(any(feature = "encryption", feature = "json-schema", feature = "signing"))]
+use std::sync::Arc;
+use std::sync::PoisonError;
 
 use async_channel::Sender;
-use log::{debug, error, info};
+use log::{debug, error, info, warn};
 use rumqttc::{
     ConnAck, Connect, Event, MqttOptions, Packet, PubAck, PubComp, PubRec, PubRel, Publish,
     Request, SubAck, Subscribe, UnsubAck, Unsubscribe,
 };
 use serde::{Deserialize, Serialize};
 use tokio::sync::mpsc::UnboundedReceiver;
-
+use uuid::Uuid;
+
+use super::chunking::Reassembler;
+use super::dedup::{Deduplicator, Seen};
+use super::event_stream::EventStreams;
+use super::publishable::DumpOptions;
+use super::rate_limit::RateLimiter;
+use super::subscription_handle::SubscriptionCounter;
 use super::*;
-use crate::{AccountId, Addressable, AgentId, Authenticable, Error, SharedGroup};
+use crate::authz::Authorization;
+#[cfg(feature = "encryption")]
+use crate::encryption::{Encryption, EncryptionError};
+use crate::{
+    AccountId, Addressable, AgentId, Authenticable, Error, EventSubscription, SharedGroup,
+};
 
 #[cfg(feature = "queue-counter")]
 use crate::queue_counter::QueueCounterHandle;
+use crate::request::PendingResponses;
+#[cfg(feature = "json-schema")]
+use crate::schema::SchemaRegistry;
+#[cfg(feature = "signing")]
+use crate::signing::{SignatureError, Signing};
 
 const DEFAULT_MQTT_REQUESTS_CHAN_SIZE: Option<usize> = Some(10_000);
 
@@ -34,6 +54,17 @@ const DEFAULT_MQTT_REQUESTS_CHAN_SIZE: Option<usize> = Some(10_000);
 /// * `max_message_size` – maximum message size in bytes. Default: 256 * 1024.
 /// * `password` – MQTT broker password.
 /// * `requests_channel_size` - requests channel capacity.
+/// * `compression` – [Compression](struct.Compression.html) settings for outgoing payloads
+///   exceeding a threshold. Default: no compression.
+/// * `chunking` – [Chunking](struct.Chunking.html) settings to transfer payloads exceeding
+///   `max_message_size` in chunks. Default: no chunking.
+/// * `rate_limit` – [RateLimit](struct.RateLimit.html) of incoming requests per account.
+///   Default: no limit.
+/// * `deduplication` – [Deduplication](struct.Deduplication.html) of redelivered incoming
+///   messages. Default: no deduplication.
+/// * `max_chain_depth` – maximum number of sessions in `session_tracking_label` of incoming
+///   messages. Longer chains are considered looping: requests get answered with
+///   `508 Loop Detected` and other messages get dropped. Default: no limit.
 #[derive(Debug, Clone, Deserialize)]
 pub struct AgentConfig {
     uri: String,
@@ -46,6 +77,15 @@ pub struct AgentConfig {
     max_message_size: Option<usize>,
     #[serde(default = "default_mqtt_requests_chan_size")]
     requests_channel_size: Option<usize>,
+    #[serde(default)]
+    compression: Option<Compression>,
+    #[serde(default)]
+    chunking: Option<Chunking>,
+    #[serde(default)]
+    rate_limit: Option<RateLimit>,
+    #[serde(default)]
+    deduplication: Option<Deduplication>,
+    max_chain_depth: Option<usize>,
 }
 
 fn default_mqtt_requests_chan_size() -> Option<usize> {
@@ -68,6 +108,16 @@ impl AgentConfig {
 pub struct AgentBuilder {
     connection: Connection,
     api_version: String,
+    dispatcher: bool,
+    codec: Codec,
+    raw_payload: bool,
+    authorization: Option<Authorization>,
+    #[cfg(feature = "json-schema")]
+    schemas: Option<Arc<SchemaRegistry>>,
+    #[cfg(feature = "signing")]
+    signing: Option<Arc<Signing>>,
+    #[cfg(feature = "encryption")]
+    encryption: Option<Arc<Encryption>>,
 }
 
 impl AgentBuilder {
@@ -89,6 +139,16 @@ impl AgentBuilder {
         Self {
             connection: Connection::new(agent_id),
             api_version: api_version.to_owned(),
+            dispatcher: false,
+            codec: Codec::default(),
+            raw_payload: false,
+            authorization: None,
+            #[cfg(feature = "json-schema")]
+            schemas: None,
+            #[cfg(feature = "signing")]
+            signing: None,
+            #[cfg(feature = "encryption")]
+            encryption: None,
         }
     }
 
@@ -116,6 +176,104 @@ impl AgentBuilder {
         Self { connection, ..self }
     }
 
+    /// Attaches [Dispatcher](../request/struct.Dispatcher.html) to the agent's event loop.
+    ///
+    /// Responses awaited by dispatchers built with
+    /// [Dispatcher::new](../request/struct.Dispatcher.html#method.new) for the agent get routed
+    /// to them automatically. Only responses that are not being awaited reach the
+    /// [AgentNotification](enum.AgentNotification.html) receiver.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let (agent, rx) = AgentBuilder::new(agent_id, "v1")
+    ///     .with_dispatcher()
+    ///     .start(&config)?;
+    ///
+    /// let dispatcher = Dispatcher::new(&agent);
+    /// ```
+    pub fn with_dispatcher(self) -> Self {
+        Self {
+            dispatcher: true,
+            ..self
+        }
+    }
+
+    /// Sets a default payload [Codec](enum.Codec.html) for messages published by the agent.
+    ///
+    /// It applies to messages published with [Agent::publish](struct.Agent.html#method.publish)
+    /// unless the message properties have their own codec set. JSON is used by default.
+    pub fn codec(self, codec: Codec) -> Self {
+        Self { codec, ..self }
+    }
+
+    /// Embeds JSON payloads of messages published by the agent into the envelope as raw JSON.
+    ///
+    /// By default the payload is serialized to a JSON string inside the envelope JSON so it gets
+    /// encoded twice. Raw payloads are marked with `payload_format` property so the receiving
+    /// side could tell them apart. Enable it only if the broker and the receivers support
+    /// the property. Incoming messages are parsed in both formats regardless of this option.
+    pub fn raw_payload(self) -> Self {
+        Self {
+            raw_payload: true,
+            ..self
+        }
+    }
+
+    /// Authorizes incoming requests before they get to the application.
+    ///
+    /// Denied requests get answered with `403 Forbidden`.
+    /// See [Authorization](../authz/struct.Authorization.html) for details.
+    pub fn authorization(self, authorization: Authorization) -> Self {
+        Self {
+            authorization: Some(authorization),
+            ..self
+        }
+    }
+
+    /// Validates payloads of incoming requests and events against JSON schemas.
+    ///
+    /// Requests with invalid payloads get answered with `422 Unprocessable Entity` and
+    /// events with invalid payloads get dropped so they never reach the application.
+    /// With [authorization](#method.authorization) requests get validated once authorized
+    /// so unauthorized ones are answered with `403 Forbidden` regardless of their payloads.
+    /// See [SchemaRegistry](../schema/struct.SchemaRegistry.html) for details.
+    #[cfg(feature = "json-schema")]
+    pub fn schemas(self, schemas: SchemaRegistry) -> Self {
+        Self {
+            schemas: Some(Arc::new(schemas)),
+            ..self
+        }
+    }
+
+    /// Signs outgoing messages and verifies signatures of incoming ones.
+    ///
+    /// Incoming messages failing verification get reported as
+    /// [AgentNotification::UnverifiedMessage](enum.AgentNotification.html#variant.UnverifiedMessage)
+    /// instead of being passed to the application.
+    /// See [Signing](../signing/struct.Signing.html) for details.
+    #[cfg(feature = "signing")]
+    pub fn signing(self, signing: Signing) -> Self {
+        Self {
+            signing: Some(Arc::new(signing)),
+            ..self
+        }
+    }
+
+    /// Encrypts payloads of outgoing messages having a recipient and decrypts incoming ones.
+    ///
+    /// Incoming messages failing to decrypt get reported as
+    /// [AgentNotification::UndecryptableMessage](enum.AgentNotification.html#variant.UndecryptableMessage)
+    /// instead of being passed to the application.
+    /// See [Encryption](../encryption/struct.Encryption.html) for details.
+    #[cfg(feature = "encryption")]
+    pub fn encryption(self, encryption: Encryption) -> Self {
+        Self {
+            encryption: Some(Arc::new(encryption)),
+            ..self
+        }
+    }
+
     /// Starts an MQTT client and in case of successful connection returns a tuple containing
     /// an [Agent](struct.Agent.html) instance and a channel receiver which one can
     /// iterate over to get incoming messages.
@@ -163,6 +321,54 @@ impl AgentBuilder {
             let queue_counter = QueueCounterHandle::start();
             #[cfg(feature = "queue-counter")]
             let queue_counter_ = queue_counter.clone();
+            let pending_responses = if self.dispatcher {
+                Some(PendingResponses::default())
+            } else {
+                None
+            };
+            let pending_responses_ = pending_responses.clone();
+            let event_streams = EventStreams::default();
+            let event_streams_ = event_streams.clone();
+            let deduplicator = config.deduplication.clone().map(Deduplicator::new);
+            let deduplicator_ = deduplicator.clone();
+            let agent = Agent::new(
+                self.connection.agent_id,
+                &self.api_version,
+                mqtt_tx,
+                pending_responses,
+                event_streams,
+                deduplicator,
+                OutgoingDefaults {
+                    codec: self.codec,
+                    compression: config.compression.clone(),
+                    raw_payload: self.raw_payload,
+                    dump: DumpOptions {
+                        chunking: config.chunking.clone(),
+                        #[cfg(feature = "encryption")]
+                        encryption: self.encryption.clone(),
+                        #[cfg(feature = "signing")]
+                        signing: self.signing.clone(),
+                    },
+                    #[cfg(feature = "json-schema")]
+                    schemas: self.schemas.clone(),
+                },
+                #[cfg(feature = "queue-counter")]
+                queue_counter,
+            );
+            let mut agent_ = agent.clone();
+            let authorization = self.authorization.clone();
+            let reassembler = config
+                .chunking
+                .clone()
+                .map(|chunking| Reassembler::new(chunking).with_eviction());
+            let mut rate_limiter = config.rate_limit.clone().map(RateLimiter::new);
+            let max_chain_depth = config.max_chain_depth;
+            #[cfg(feature = "signing")]
+            let signing = self.signing.clone();
+            #[cfg(feature = "encryption")]
+            let encryption = self.encryption.clone();
+            #[cfg(feature = "encryption")]
+            let account_id = agent.id().as_account_id().to_owned();
             tokio::spawn(async move {
                 let mut recovering_connection = false;
                 loop {
@@ -180,17 +386,270 @@ impl AgentBuilder {
                                 }
                                 Event::Incoming(message) => {
                                     debug!("Incoming item = {:?}", message);
+                                    let received_at = chrono::Utc::now();
+
+                                    // Hold chunks back until the whole message gets reassembled.
+                                    let message = match (reassembler.as_ref(), message) {
+                                        (Some(reassembler), Packet::Publish(publish)) => {
+                                            let result = reassembler
+                                                .lock()
+                                                .unwrap_or_else(PoisonError::into_inner)
+                                                .push(publish);
+
+                                            match result {
+                                                Ok(Some(publish)) => Packet::Publish(publish),
+                                                Ok(None) => continue,
+                                                Err(err) => {
+                                                    error!(
+                                                        "Failed to reassemble chunked message: {}",
+                                                        err
+                                                    );
+                                                    continue;
+                                                }
+                                            }
+                                        }
+                                        (_, message) => message,
+                                    };
+
+                                    // Keep forged and tampered messages from the application.
+                                    #[cfg(feature = "signing")]
+                                    if let (Some(signing), Packet::Publish(publish)) =
+                                        (signing.as_ref(), &message)
+                                    {
+                                        if let Err(err) = signing.verify(&publish.payload) {
+                                            warn!(
+                                                "Dropping message to topic = '{}': {}",
+                                                publish.topic, err
+                                            );
+
+                                            let notification = AgentNotification::UnverifiedMessage(
+                                                err,
+                                                MessageData::from(publish),
+                                            );
+
+                                            if let Err(e) = tx.send(notification) {
+                                                error!(
+                                                    "Failed to notify about unverified message: {}",
+                                                    e
+                                                );
+                                            }
+
+                                            continue;
+                                        }
+                                    }
+
+                                    #[cfg(feature = "encryption")]
+                                    let message = match (encryption.as_ref(), message) {
+                                        (Some(encryption), Packet::Publish(publish)) => {
+                                            let message_data = MessageData::from(&publish);
+
+                                            match encryption.decrypt(publish, &account_id) {
+                                                Ok(publish) => Packet::Publish(publish),
+                                                Err(err) => {
+                                                    warn!(
+                                                        "Dropping message to topic = '{}': {}",
+                                                        message_data.topic, err
+                                                    );
+
+                                                    let notification =
+                                                        AgentNotification::UndecryptableMessage(
+                                                            err,
+                                                            message_data,
+                                                        );
+
+                                                    if let Err(e) = tx.send(notification) {
+                                                        error!("Failed to notify about undecryptable message: {}", e);
+                                                    }
+
+                                                    continue;
+                                                }
+                                            }
+                                        }
+                                        (_, message) => message,
+                                    };
+
                                     let mut msg: AgentNotification = message.into();
                                     if let AgentNotification::Message(Ok(ref mut content), _) = msg
                                     {
+                                        content.set_received_at(received_at);
+
                                         if let IncomingMessage::Request(req) = content {
                                             let method = req.properties().method().to_owned();
                                             req.properties_mut().set_method(&method);
                                         }
                                         #[cfg(feature = "queue-counter")]
                                         queue_counter_.add_incoming_message(content);
+                                        #[cfg(feature = "tracing")]
+                                        trace_context::open_span(content);
+                                    }
+
+                                    // Drop redeliveries replaying the responses already published.
+                                    if let (
+                                        Some(ref deduplicator),
+                                        AgentNotification::Message(Ok(ref content), _),
+                                    ) = (&deduplicator_, &msg)
+                                    {
+                                        match deduplicator.check(content) {
+                                            Seen::New => (),
+                                            Seen::Duplicate => {
+                                                debug!("Dropping duplicate incoming message");
+                                                continue;
+                                            }
+                                            Seen::Replay(dumps) => {
+                                                debug!("Replaying response to duplicate request");
+
+                                                for dump in dumps {
+                                                    if let Err(err) = agent_.publish_dump(dump) {
+                                                        error!(
+                                                            "Failed to replay response: {}",
+                                                            err
+                                                        );
+                                                    }
+                                                }
+
+                                                continue;
+                                            }
+                                        }
                                     }
 
+                                    // Break message chains looping between agents.
+                                    if let (
+                                        Some(max_chain_depth),
+                                        AgentNotification::Message(Ok(ref content), _),
+                                    ) = (max_chain_depth, &msg)
+                                    {
+                                        if !agent_.check_chain_depth(content, max_chain_depth) {
+                                            continue;
+                                        }
+                                    }
+
+                                    // Reject requests the caller has given up awaiting the response to.
+                                    if let AgentNotification::Message(
+                                        Ok(IncomingMessage::Request(ref req)),
+                                        _,
+                                    ) = msg
+                                    {
+                                        if !agent_.check_deadline(req) {
+                                            continue;
+                                        }
+                                    }
+
+                                    // Reject requests exceeding the rate limit of the caller's account.
+                                    if let (
+                                        Some(rate_limiter),
+                                        AgentNotification::Message(
+                                            Ok(IncomingMessage::Request(ref req)),
+                                            _,
+                                        ),
+                                    ) = (rate_limiter.as_mut(), &msg)
+                                    {
+                                        let account_id = req.properties().as_account_id();
+
+                                        if !rate_limiter
+                                            .check(account_id, req.properties().method())
+                                        {
+                                            let status = ResponseStatus::TOO_MANY_REQUESTS;
+                                            let detail = format!(
+                                                "account = '{}' exceeded the rate limit",
+                                                account_id
+                                            );
+
+                                            agent_.reject_request(
+                                                req,
+                                                status,
+                                                error_payload(status, &detail),
+                                            );
+
+                                            #[cfg(feature = "queue-counter")]
+                                            queue_counter_.add_rate_limited_request(req);
+
+                                            continue;
+                                        }
+                                    }
+
+                                    // Authorize requests concurrently not to hold the other messages.
+                                    if let Some(ref authorization) = authorization {
+                                        msg = match msg {
+                                            AgentNotification::Message(
+                                                Ok(IncomingMessage::Request(req)),
+                                                data,
+                                            ) => {
+                                                let authorization = authorization.clone();
+                                                let mut agent = agent_.clone();
+                                                let tx = tx.clone();
+
+                                                tokio::spawn(async move {
+                                                    let req = match authorization
+                                                        .authorize(req, &mut agent)
+                                                        .await
+                                                    {
+                                                        Some(req) => req,
+                                                        None => return,
+                                                    };
+
+                                                    let req = IncomingMessage::Request(req);
+
+                                                    // Validate authorized requests only not to disclose the schemas.
+                                                    #[cfg(feature = "json-schema")]
+                                                    if !agent.validate_incoming(&req) {
+                                                        return;
+                                                    }
+
+                                                    let msg =
+                                                        AgentNotification::Message(Ok(req), data);
+
+                                                    if let Err(e) = tx.send(msg) {
+                                                        error!("Failed to transmit message, reason = {}", e);
+                                                    }
+                                                });
+
+                                                continue;
+                                            }
+                                            msg => msg,
+                                        };
+                                    }
+
+                                    // Reject messages with payloads not matching their schemas.
+                                    // Requests are validated after authorization if there's one.
+                                    #[cfg(feature = "json-schema")]
+                                    if let AgentNotification::Message(Ok(ref content), _) = msg {
+                                        if !agent_.validate_incoming(content) {
+                                            continue;
+                                        }
+                                    }
+
+                                    // Route the response to the dispatcher if it's awaiting for it.
+                                    if let Some(ref pending_responses) = pending_responses_ {
+                                        msg = match msg {
+                                            AgentNotification::Message(
+                                                Ok(IncomingMessage::Response(resp)),
+                                                data,
+                                            ) => match pending_responses.commit(resp) {
+                                                None => continue,
+                                                Some(resp) => AgentNotification::Message(
+                                                    Ok(IncomingMessage::Response(resp)),
+                                                    data,
+                                                ),
+                                            },
+                                            msg => msg,
+                                        };
+                                    }
+
+                                    // Route the event to the event streams subscribed to its topic.
+                                    msg = match msg {
+                                        AgentNotification::Message(
+                                            Ok(IncomingMessage::Event(event)),
+                                            data,
+                                        ) => match event_streams_.dispatch(event, &data.topic) {
+                                            None => continue,
+                                            Some(event) => AgentNotification::Message(
+                                                Ok(IncomingMessage::Event(event)),
+                                                data,
+                                            ),
+                                        },
+                                        msg => msg,
+                                    };
+
                                     if let Err(e) = tx.send(msg) {
                                         error!("Failed to transmit message, reason = {}", e);
                                     };
@@ -213,13 +672,6 @@ impl AgentBuilder {
                     }
                 }
             });
-            let agent = Agent::new(
-                self.connection.agent_id,
-                &self.api_version,
-                mqtt_tx,
-                #[cfg(feature = "queue-counter")]
-                queue_counter,
-            );
 
             Ok((agent, rx))
         }
@@ -294,34 +746,76 @@ impl Address {
     }
 }
 
+/// Settings applied to messages published by the agent unless overridden by the message.
+#[derive(Clone)]
+struct OutgoingDefaults {
+    codec: Codec,
+    compression: Option<Compression>,
+    raw_payload: bool,
+    dump: DumpOptions,
+    #[cfg(feature = "json-schema")]
+    schemas: Option<Arc<SchemaRegistry>>,
+}
+
 #[derive(Clone)]
 pub struct Agent {
     address: Address,
+    session_id: SessionId,
     tx: Sender<Request>,
+    pending_responses: Option<PendingResponses>,
+    event_streams: EventStreams,
+    subscription_counter: SubscriptionCounter,
+    deduplicator: Option<Deduplicator>,
+    outgoing_defaults: OutgoingDefaults,
     #[cfg(feature = "queue-counter")]
     queue_counter: QueueCounterHandle,
 }
 
 impl Agent {
     #[cfg(feature = "queue-counter")]
+    #[allow(clippy::too_many_arguments)]
     fn new(
         id: AgentId,
         api_version: &str,
         tx: Sender<Request>,
+        pending_responses: Option<PendingResponses>,
+        event_streams: EventStreams,
+        deduplicator: Option<Deduplicator>,
+        outgoing_defaults: OutgoingDefaults,
         queue_counter: QueueCounterHandle,
     ) -> Self {
         Self {
             address: Address::new(id, api_version),
+            session_id: SessionId::new(Uuid::new_v4(), Uuid::nil()),
             tx,
+            pending_responses,
+            event_streams,
+            subscription_counter: SubscriptionCounter::default(),
+            deduplicator,
+            outgoing_defaults,
             queue_counter,
         }
     }
 
     #[cfg(not(feature = "queue-counter"))]
-    fn new(id: AgentId, api_version: &str, tx: Sender<Request>) -> Self {
+    fn new(
+        id: AgentId,
+        api_version: &str,
+        tx: Sender<Request>,
+        pending_responses: Option<PendingResponses>,
+        event_streams: EventStreams,
+        deduplicator: Option<Deduplicator>,
+        outgoing_defaults: OutgoingDefaults,
+    ) -> Self {
         Self {
             address: Address::new(id, api_version),
+            session_id: SessionId::new(Uuid::new_v4(), Uuid::nil()),
             tx,
+            pending_responses,
+            event_streams,
+            subscription_counter: SubscriptionCounter::default(),
+            deduplicator,
+            outgoing_defaults,
         }
     }
 
@@ -333,9 +827,37 @@ impl Agent {
         self.address.id()
     }
 
+    /// Starts a new message chain originated by the agent.
+    ///
+    /// Use it when publishing a message that isn't caused by another one, e.g. on a timer.
+    /// The chain is started in the session of the agent. Its agent session label is generated
+    /// on the agent start and the broker session label is nil since it's not known to the agent.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let mut props = OutgoingEventProperties::new("room.tick", short_term_timing);
+    /// props.set_tracking(agent.start_tracking());
+    /// ```
+    pub fn start_tracking(&self) -> TrackingProperties {
+        TrackingProperties::start(self.session_id.clone())
+    }
+
+    pub(crate) fn pending_responses(&self) -> Option<&PendingResponses> {
+        self.pending_responses.as_ref()
+    }
+
+    pub(crate) fn subscription_counter(&self) -> &SubscriptionCounter {
+        &self.subscription_counter
+    }
+
     /// Publish a message.
     ///
     /// This method is a shorthand to dump and publish the message with a single call.
+    /// The payload is serialized with the agent's default [codec](struct.AgentBuilder.html#method.codec)
+    /// unless the message properties specify another one. Payloads exceeding the configured
+    /// [compression](struct.AgentConfig.html) threshold get compressed and ones exceeding
+    /// the [chunk](struct.Chunking.html) size get published in chunks.
     /// If you want to print out the dump before or after publishing or assert it in tests
     /// consider using [IntoPublishableDump::into_dump](trait.IntoPublishableDump.html#method.into_dump)
     /// and [publish_dump](#method.publish_dump).
@@ -367,12 +889,199 @@ impl Agent {
         &mut self,
         message: OutgoingMessage<T>,
     ) -> Result<(), Error> {
-        let dump = Box::new(message).into_dump(&self.address)?;
-        self.publish_dump(dump)
+        for dump in self.dump(message)? {
+            self.publish_dump(dump)?;
+        }
+
+        Ok(())
+    }
+
+    /// Serializes the message into dumps applying the agent's defaults.
+    ///
+    /// Responses are remembered to replay them to the request's redeliveries.
+    pub(crate) fn dump<T: serde::Serialize>(
+        &self,
+        mut message: OutgoingMessage<T>,
+    ) -> Result<Vec<PublishableMessage>, Error> {
+        let defaults = &self.outgoing_defaults;
+
+        #[cfg(feature = "json-schema")]
+        if let Some(ref schemas) = defaults.schemas {
+            schemas.validate_outgoing_message(&message)?;
+        }
+
+        if defaults.codec != Codec::Json {
+            message.set_default_codec(defaults.codec);
+        }
+
+        if let Some(ref compression) = defaults.compression {
+            message.set_compression(compression.to_owned());
+        }
+
+        if defaults.raw_payload {
+            message.set_raw_payload();
+        }
+
+        #[cfg(feature = "tracing")]
+        message.set_default_trace_context();
+
+        let options = defaults.dump.clone();
+
+        // Remember the response to replay it to the request's redeliveries.
+        let request_key = match message {
+            OutgoingMessage::Response(ref resp) if self.deduplicator.is_some() => {
+                match resp.destination {
+                    Destination::Unicast(ref agent_id, _) => Some((
+                        agent_id.to_owned(),
+                        resp.properties().correlation_data().to_owned(),
+                    )),
+                    _ => None,
+                }
+            }
+            _ => None,
+        };
+
+        let dumps = message.into_dumps(&self.address, &options)?;
+
+        if let (Some(deduplicator), Some((agent_id, correlation_data))) =
+            (&self.deduplicator, request_key)
+        {
+            deduplicator.record_response(&agent_id, &correlation_data, &dumps);
+        }
+
+        Ok(dumps)
+    }
+
+    /// Answers the request with an error response on behalf of the application.
+    ///
+    /// Used for requests rejected by the agent before they get to the application.
+    /// The deduplicator forgets the request so its retry isn't answered with the same rejection.
+    pub(crate) fn reject_request<R: serde::Serialize>(
+        &mut self,
+        request: &IncomingRequest<IncomingPayload>,
+        status: ResponseStatus,
+        payload: R,
+    ) {
+        let timing = OutgoingShortTermTimingProperties::new(chrono::Utc::now());
+        let response = request.to_response(payload, status, timing, self.address.version());
+
+        if let Err(err) = self.publish(response) {
+            error!(
+                "Failed to reject request with method = '{}': {}",
+                request.properties().method(),
+                err
+            );
+        }
+
+        if let Some(ref deduplicator) = self.deduplicator {
+            let props = request.properties();
+            deduplicator.forget(props.as_agent_id(), props.correlation_data());
+        }
+    }
+
+    /// Checks that the caller still awaits the response to the incoming request.
+    ///
+    /// Answers expired requests with 504 response. Returns whether the deadline hasn't passed.
+    fn check_deadline(&mut self, req: &IncomingRequest<IncomingPayload>) -> bool {
+        if !req.properties().is_expired() {
+            return true;
+        }
+
+        let status = ResponseStatus::GATEWAY_TIMEOUT;
+        let detail = format!(
+            "request with method = '{}' has expired",
+            req.properties().method()
+        );
+
+        debug!("Rejecting {}", detail);
+        self.reject_request(req, status, error_payload(status, &detail));
+        false
+    }
+
+    /// Checks that the incoming message chain hasn't passed through too many sessions.
+    ///
+    /// Answers requests of longer chains with 508 response. Returns whether the chain is fine.
+    fn check_chain_depth(
+        &mut self,
+        message: &IncomingMessage<IncomingPayload>,
+        max_chain_depth: usize,
+    ) -> bool {
+        let tracking = match message {
+            IncomingMessage::Request(req) => req.properties().tracking(),
+            IncomingMessage::Response(resp) => resp.properties().tracking(),
+            IncomingMessage::Event(event) => event.properties().tracking(),
+        };
+
+        if tracking.depth() <= max_chain_depth {
+            return true;
+        }
+
+        let detail = format!(
+            "message chain with tracking id = '{}' exceeded the depth of {}",
+            tracking.tracking_id(),
+            max_chain_depth
+        );
+
+        warn!("Dropping message: {}", detail);
+
+        if let IncomingMessage::Request(req) = message {
+            let status = ResponseStatus::LOOP_DETECTED;
+            self.reject_request(req, status, error_payload(status, &detail));
+        }
+
+        false
+    }
+
+    /// Validates the incoming message payload against its schema.
+    ///
+    /// Answers invalid requests with 422 response. Returns whether the message is valid.
+    #[cfg(feature = "json-schema")]
+    fn validate_incoming(&mut self, message: &IncomingMessage<IncomingPayload>) -> bool {
+        let schemas = match self.outgoing_defaults.schemas {
+            Some(ref schemas) => schemas.to_owned(),
+            None => return true,
+        };
+
+        match message {
+            IncomingMessage::Request(req) => match schemas.validate_request(req) {
+                Ok(()) => true,
+                Err(violations) => {
+                    warn!(
+                        "Rejecting request with method = '{}': {}",
+                        req.properties().method(),
+                        violations
+                    );
+
+                    let status = ResponseStatus::UNPROCESSABLE_ENTITY;
+                    let mut payload = error_payload(status, &violations.to_string());
+                    payload["errors"] = serde_json::json!(violations.violations());
+                    self.reject_request(req, status, payload);
+                    false
+                }
+            },
+            IncomingMessage::Event(event) => match schemas.validate_event(event) {
+                Ok(()) => true,
+                Err(violations) => {
+                    warn!(
+                        "Dropping event with label = '{}': {}",
+                        event.properties().label().unwrap_or_default(),
+                        violations
+                    );
+
+                    false
+                }
+            },
+            IncomingMessage::Response(_) => true,
+        }
     }
 
     /// Publish a publishable message.
     ///
+    /// [OutgoingMessage](enum.OutgoingMessage.html) gets the same defaults applied as with
+    /// [publish](#method.publish). Other implementations of
+    /// [IntoPublishableMessage](trait.IntoPublishableMessage.html) are published as they
+    /// serialize themselves.
+    ///
     /// # Arguments
     ///
     /// * `message` – message to publish.
@@ -401,10 +1110,19 @@ impl Agent {
         &mut self,
         message: Box<dyn IntoPublishableMessage>,
     ) -> Result<(), Error> {
-        let dump = message.into_dump(&self.address)?;
-        self.publish_dump(dump)
+        for dump in message.into_dumps_for(self)? {
+            self.publish_dump(dump)?;
+        }
+
+        Ok(())
     }
 
+    /// Publish a serialized message as is.
+    ///
+    /// None of the agent's defaults get applied: codec, compression, raw payload,
+    /// trace context, schema validation, encryption, signing and chunking are all up to the dump.
+    /// Dumps made with [into_dump](trait.IntoPublishableMessage.html#tymethod.into_dump) have
+    /// none of them. Prefer [publish](#method.publish) unless the dump is ready to go.
     pub fn publish_dump(&mut self, dump: PublishableMessage) -> Result<(), Error> {
         #[cfg(feature = "queue-counter")]
         self.queue_counter.add_outgoing_message(&dump);
@@ -439,6 +1157,15 @@ impl Agent {
     /// Note that the subscription is actually gets confirmed on receiving
     /// `AgentNotification::Suback` notification.
     ///
+    /// Returns a [SubscriptionHandle](struct.SubscriptionHandle.html). Handles are
+    /// reference-counted per topic and shared group so the topic gets unsubscribed only when
+    /// the last handle of it is dropped. [Detach](struct.SubscriptionHandle.html#method.detach)
+    /// the handle to stay subscribed regardless.
+    ///
+    /// Since v0.22 it returns the handle instead of `()`. Callers ignoring the result with `?;`
+    /// drop the handle at once and unsubscribe. Call `detach` on it to keep the previous
+    /// behaviour.
+    ///
     /// # Arguments
     ///
     /// * `subscription` – the [Subscription](struct.Subscription.html).
@@ -448,11 +1175,13 @@ impl Agent {
     /// # Example
     ///
     /// ```
-    /// agent.subscribe(
-    ///     &Subscription::multicast_requests(Some("v1")),
-    ///     QoS::AtMostOnce,
-    ///     Some(&group),
-    /// )?;
+    /// agent
+    ///     .subscribe(
+    ///         &Subscription::multicast_requests(Some("v1")),
+    ///         QoS::AtMostOnce,
+    ///         Some(&group),
+    ///     )?
+    ///     .detach();
     ///
     /// match rx.recv_timeout(Duration::from_secs(5)) {
     ///     Ok(AgentNotification::Suback(_)) => (),
@@ -465,12 +1194,54 @@ impl Agent {
         subscription: &S,
         qos: QoS,
         maybe_group: Option<&SharedGroup>,
-    ) -> Result<(), Error>
+    ) -> Result<SubscriptionHandle, Error>
     where
         S: SubscriptionTopic,
     {
         let topic = self.get_topic(subscription, maybe_group)?;
+        self.subscribe_topic(&topic, qos)?;
+        Ok(SubscriptionHandle::new(self.clone(), &topic))
+    }
 
+    /// Subscribe to broadcast events and receive them as a stream.
+    ///
+    /// The stream receives only events with topics matching the subscription including
+    /// `+` and `#` wildcards. Their payloads get converted into `T` and events failed to convert
+    /// are logged and skipped. Such events don't get to the agent's notifications channel.
+    ///
+    /// The stream holds a [SubscriptionHandle](struct.SubscriptionHandle.html) so dropping it
+    /// unsubscribes from the topic unless there are other handles of it.
+    ///
+    /// # Arguments
+    ///
+    /// * `subscription` – the [EventSubscription](../struct.EventSubscription.html).
+    /// * `qos` – quality of service. See [QoS](enum.QoS.html) for available values.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let subscription = Subscription::broadcast_events(&service, "v1", "rooms/+/events");
+    /// let mut events = agent.subscribe_events::<RoomEvent>(&subscription, QoS::AtLeastOnce)?;
+    ///
+    /// while let Some(event) = events.next().await {
+    ///     println!("Room event: {:?}", event.payload());
+    /// }
+    /// ```
+    pub fn subscribe_events<T>(
+        &mut self,
+        subscription: &EventSubscription,
+        qos: QoS,
+    ) -> Result<EventStream<T>, Error> {
+        let topic = self.get_topic(subscription, None)?;
+
+        // Register the stream before subscribing so it doesn't miss the first events.
+        let registration = self.event_streams.register(&topic);
+        self.subscribe_topic(&topic, qos)?;
+        let subscription = SubscriptionHandle::new(self.clone(), &topic);
+        Ok(EventStream::new(registration, subscription))
+    }
+
+    fn subscribe_topic(&self, topic: &str, qos: QoS) -> Result<(), Error> {
         self.tx
             .try_send(Request::Subscribe(Subscribe::new(topic, qos)))
             .map_err(|e| Error::new(&format!("error creating MQTT subscription, {}", e)))?;
@@ -483,6 +1254,10 @@ impl Agent {
     /// Note that the unsubscribing is actually gets confirmed on receiving
     /// `AgentNotification::Unsuback` notification.
     ///
+    /// It's intended for [detached](struct.SubscriptionHandle.html#method.detach) subscriptions
+    /// and fails while there are live [SubscriptionHandle](struct.SubscriptionHandle.html)
+    /// instances of the topic. Drop the handles instead.
+    ///
     /// # Arguments
     ///
     /// * `subscription` – the [Subscription](struct.Subscription.html).
@@ -512,6 +1287,17 @@ impl Agent {
     {
         let topic = self.get_topic(subscription, maybe_group)?;
 
+        self.subscription_counter.reset(&topic).map_err(|handles| {
+            Error::new(&format!(
+                "refusing to unsubscribe from topic = '{}' having {} live subscription handle(s)",
+                topic, handles
+            ))
+        })?;
+
+        self.unsubscribe_topic(&topic)
+    }
+
+    pub(crate) fn unsubscribe_topic(&self, topic: &str) -> Result<(), Error> {
         self.tx
             .try_send(Request::Unsubscribe(Unsubscribe::new(topic)))
             .map_err(|e| Error::new(&format!("error creating MQTT subscription, {}", e)))?;
@@ -540,6 +1326,41 @@ impl Agent {
     }
 }
 
+#[cfg(test)]
+impl Agent {
+    /// Builds an agent with a dispatcher that isn't connected to any broker.
+    ///
+    /// Packets the agent sends to the broker go to the returned receiver.
+    pub(crate) fn stub() -> (Self, async_channel::Receiver<Request>) {
+        let (tx, rx) = async_channel::unbounded();
+        let agent_id = AgentId::new("instance01", AccountId::new("receiver", "svc.example.org"));
+
+        let outgoing_defaults = OutgoingDefaults {
+            codec: Codec::Json,
+            compression: None,
+            raw_payload: false,
+            dump: DumpOptions::default(),
+            #[cfg(feature = "json-schema")]
+            schemas: None,
+        };
+
+        let agent = Self {
+            address: Address::new(agent_id, "v1"),
+            session_id: SessionId::new(Uuid::new_v4(), Uuid::nil()),
+            tx,
+            pending_responses: Some(PendingResponses::default()),
+            event_streams: EventStreams::default(),
+            subscription_counter: SubscriptionCounter::default(),
+            deduplicator: None,
+            outgoing_defaults,
+            #[cfg(feature = "queue-counter")]
+            queue_counter: QueueCounterHandle::start(),
+        };
+
+        (agent, rx)
+    }
+}
+
 /// Connection mode of an agent that defines the level of privileges.
 #[derive(Debug, Clone)]
 pub enum ConnectionMode {
@@ -718,7 +1539,18 @@ impl Addressable for &ConnectionProperties {
 #[derive(Debug)]
 #[allow(clippy::large_enum_variant)]
 pub enum AgentNotification {
-    Message(Result<IncomingMessage<String>, String>, MessageData),
+    Message(
+        Result<IncomingMessage<IncomingPayload>, String>,
+        MessageData,
+    ),
+    /// A message failed signature verification.
+    /// See [Signing](../signing/struct.Signing.html) for details.
+    #[cfg(feature = "signing")]
+    UnverifiedMessage(SignatureError, MessageData),
+    /// A message payload failed to decrypt.
+    /// See [Encryption](../encryption/struct.Encryption.html) for details.
+    #[cfg(feature = "encryption")]
+    UndecryptableMessage(EncryptionError, MessageData),
     Reconnection,
     ConnectionError,
     Puback(PubAck),
@@ -745,6 +1577,27 @@ pub struct MessageData {
     pub pkid: u16,
 }
 
+/// Builds the payload of an error response sent on behalf of the application.
+pub(crate) fn error_payload(status: ResponseStatus, detail: &str) -> serde_json::Value {
+    serde_json::json!({
+        "title": status.canonical_reason().unwrap_or_default(),
+        "status": status.as_u16(),
+        "detail": detail,
+    })
+}
+
+impl From<&Publish> for MessageData {
+    fn from(message: &Publish) -> Self {
+        Self {
+            dup: message.dup,
+            qos: message.qos,
+            retain: message.retain,
+            topic: message.topic.to_owned(),
+            pkid: message.pkid,
+        }
+    }
+}
+
 impl From<Packet> for AgentNotification {
     fn from(notification: Packet) -> Self {
         match notification {
@@ -757,21 +1610,24 @@ impl From<Packet> for AgentNotification {
                     pkid: message.pkid,
                 };
 
-                let env_result =
-                    serde_json::from_slice::<compat::IncomingEnvelope>(&message.payload)
-                        .map_err(|err| format!("Failed to parse incoming envelope: {}", err))
-                        .and_then(|env| match env.properties() {
-                            compat::IncomingEnvelopeProperties::Request(_) => {
-                                compat::into_request(env)
-                                    .map_err(|e| format!("Failed to convert into request: {}", e))
-                            }
-                            compat::IncomingEnvelopeProperties::Response(_) => {
-                                compat::into_response(env)
-                                    .map_err(|e| format!("Failed to convert into response: {}", e))
-                            }
-                            compat::IncomingEnvelopeProperties::Event(_) => compat::into_event(env)
-                                .map_err(|e| format!("Failed to convert into event: {}", e)),
-                        });
+                let buffer = message.payload;
+
+                let env_result = serde_json::from_slice::<compat::IncomingEnvelope>(&buffer)
+                    .map_err(|err| format!("Failed to parse incoming envelope: {}", err))
+                    .and_then(|env| match env.properties() {
+                        compat::IncomingEnvelopeProperties::Request(_) => {
+                            compat::into_request(env, &buffer)
+                                .map_err(|e| format!("Failed to convert into request: {}", e))
+                        }
+                        compat::IncomingEnvelopeProperties::Response(_) => {
+                            compat::into_response(env, &buffer)
+                                .map_err(|e| format!("Failed to convert into response: {}", e))
+                        }
+                        compat::IncomingEnvelopeProperties::Event(_) => {
+                            compat::into_event(env, &buffer)
+                                .map_err(|e| format!("Failed to convert into event: {}", e))
+                        }
+                    });
 
                 Self::Message(env_result, message_data)
             }
@@ -791,3 +1647,138 @@ impl From<Packet> for AgentNotification {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::mqtt::testing::{packet, published, receive, request};
+
+    #[tokio::test]
+    async fn forgets_rejected_request() {
+        let (mut agent, rx) = Agent::stub();
+        let deduplicator = Deduplicator::new(Deduplication::default());
+        agent.deduplicator = Some(deduplicator.clone());
+
+        let handled = IncomingMessage::Request(request("room.enter", "handled", json!({})));
+        let rejected = IncomingMessage::Request(request("room.enter", "rejected", json!({})));
+
+        for message in [&handled, &rejected] {
+            assert!(matches!(deduplicator.check(message), Seen::New));
+        }
+
+        if let IncomingMessage::Request(ref req) = handled {
+            let timing = OutgoingShortTermTimingProperties::new(chrono::Utc::now());
+            let response = req.to_response(json!({}), ResponseStatus::OK, timing, "v1");
+            agent.publish(response).unwrap();
+        }
+
+        if let IncomingMessage::Request(ref req) = rejected {
+            let status = ResponseStatus::TOO_MANY_REQUESTS;
+            agent.reject_request(req, status, error_payload(status, "slow down"));
+        }
+
+        assert_eq!(published(&rx).len(), 2);
+        assert!(matches!(deduplicator.check(&handled), Seen::Replay(dumps) if dumps.len() == 1));
+        assert!(matches!(deduplicator.check(&rejected), Seen::New));
+    }
+
+    #[tokio::test]
+    async fn rejects_expired_request() {
+        let (mut agent, rx) = Agent::stub();
+        assert!(agent.check_deadline(&request("room.enter", "corr", json!({}))));
+
+        let deadline = chrono::Utc::now() - chrono::Duration::seconds(1);
+        let properties = json!({
+            "type": "request",
+            "method": "room.enter",
+            "correlation_data": "corr",
+            "response_topic": "agents/instance01.sender.svc.example.org/api/v1/in/receiver",
+            "deadline": deadline.timestamp_millis().to_string(),
+        });
+
+        let req = match receive(packet(properties, &json!({}))) {
+            IncomingMessage::Request(req) => req,
+            _ => panic!("expected a request"),
+        };
+
+        assert!(published(&rx).is_empty());
+        assert!(!agent.check_deadline(&req));
+
+        let messages = published(&rx);
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].1["properties"]["status"], "504");
+    }
+
+    #[tokio::test]
+    async fn rejects_looping_chain() {
+        let (mut agent, rx) = Agent::stub();
+        let session_id = format!("{}.{}", Uuid::new_v4(), Uuid::new_v4());
+        let chain = [session_id.as_str(); 3].join(" ");
+
+        let properties = json!({
+            "type": "request",
+            "method": "room.enter",
+            "correlation_data": "corr",
+            "response_topic": "agents/instance01.sender.svc.example.org/api/v1/in/receiver",
+            "session_tracking_label": chain,
+        });
+
+        let message = receive(packet(properties, &json!({})));
+        assert!(agent.check_chain_depth(&message, 3));
+        assert!(published(&rx).is_empty());
+
+        assert!(!agent.check_chain_depth(&message, 2));
+        let messages = published(&rx);
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].1["properties"]["status"], "508");
+
+        let properties =
+            json!({ "type": "event", "label": "room.update", "session_tracking_label": chain });
+        let message = receive(packet(properties, &json!({})));
+        assert!(!agent.check_chain_depth(&message, 2));
+        assert!(published(&rx).is_empty());
+    }
+
+    #[tokio::test]
+    async fn starts_tracking_in_own_session() {
+        let (agent, _rx) = Agent::stub();
+        let tracking = agent.start_tracking();
+
+        assert_eq!(tracking.depth(), 1);
+        assert_eq!(tracking.root_session_id(), &agent.session_id);
+    }
+
+    #[cfg(feature = "json-schema")]
+    #[tokio::test]
+    async fn rejects_invalid_request_with_error_payload() {
+        let (mut agent, rx) = Agent::stub();
+
+        let schemas = SchemaRegistry::new()
+            .request(
+                "room.enter",
+                json!({ "type": "object", "required": ["id"] }),
+            )
+            .unwrap();
+
+        agent.outgoing_defaults.schemas = Some(Arc::new(schemas));
+
+        let message = IncomingMessage::Request(request("room.enter", "corr", json!({})));
+        assert!(!agent.validate_incoming(&message));
+
+        let messages = published(&rx);
+        assert_eq!(messages.len(), 1);
+
+        let envelope = &messages[0].1;
+        assert_eq!(envelope["properties"]["status"], "422");
+
+        let payload =
+            serde_json::from_str::<serde_json::Value>(envelope["payload"].as_str().unwrap())
+                .unwrap();
+        assert_eq!(payload["status"], 422);
+        assert_eq!(payload["title"], "Unprocessable Entity");
+        assert!(payload["detail"].as_str().unwrap().contains("id"));
+        assert_eq!(payload["errors"][0]["path"], "");
+    }
+}
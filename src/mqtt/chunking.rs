@@ -0,0 +1,558 @@
+use std::collections::{BTreeMap, HashMap};
+use std::num::NonZeroUsize;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use log::warn;
+use rumqttc::Publish;
+use serde::{Deserialize, Serialize};
+use serde_json::{value::RawValue, Map, Value};
+use uuid::Uuid;
+
+use super::compat::{OutgoingEnvelope, RAW_PAYLOAD_FORMAT};
+use crate::Error;
+
+const DEFAULT_CHUNK_SIZE: usize = 128 * 1024;
+const DEFAULT_TIMEOUT: u64 = 30;
+const DEFAULT_MAX_TRANSFER_SIZE: usize = 16 * 1024 * 1024;
+const DEFAULT_MAX_TRANSFERS: usize = 64;
+
+const SENDER_PROPERTY: &str = "agent_id";
+const TRANSFER_ID_PROPERTY: &str = "chunk_transfer_id";
+const INDEX_PROPERTY: &str = "chunk_index";
+const COUNT_PROPERTY: &str = "chunk_count";
+
+fn default_chunk_size() -> NonZeroUsize {
+    NonZeroUsize::new(DEFAULT_CHUNK_SIZE).expect("zero default chunk size")
+}
+
+fn default_timeout() -> u64 {
+    DEFAULT_TIMEOUT
+}
+
+fn default_max_transfer_size() -> usize {
+    DEFAULT_MAX_TRANSFER_SIZE
+}
+
+fn default_max_transfers() -> usize {
+    DEFAULT_MAX_TRANSFERS
+}
+
+/// Chunked transfer settings.
+///
+/// Outgoing messages with payloads larger than `chunk_size` are split into chunks sharing
+/// a transfer id. Each chunk is published as a separate envelope with the original properties
+/// plus `chunk_transfer_id`, `chunk_index` and `chunk_count` ones. Incoming chunks are
+/// reassembled into a single message before it gets to the application.
+///
+/// Both sides must have chunking enabled.
+///
+/// # Options
+///
+/// * `chunk_size` – maximum payload size of a single chunk in bytes. It must be positive and
+///   leave room for the properties within `max_message_size`. Default: 128 KiB.
+/// * `timeout` – seconds to wait for the missing chunks of a transfer. Default: 30.
+/// * `max_transfer_size` – maximum payload size of a reassembled message in bytes. Default: 16 MiB.
+/// * `max_transfers` – maximum number of transfers reassembled at the same time.
+///   The oldest one is dropped when exceeded. Default: 64.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Chunking {
+    #[serde(default = "default_chunk_size")]
+    chunk_size: NonZeroUsize,
+    #[serde(default = "default_timeout")]
+    timeout: u64,
+    #[serde(default = "default_max_transfer_size")]
+    max_transfer_size: usize,
+    #[serde(default = "default_max_transfers")]
+    max_transfers: usize,
+}
+
+impl Default for Chunking {
+    fn default() -> Self {
+        Self {
+            chunk_size: default_chunk_size(),
+            timeout: DEFAULT_TIMEOUT,
+            max_transfer_size: DEFAULT_MAX_TRANSFER_SIZE,
+            max_transfers: DEFAULT_MAX_TRANSFERS,
+        }
+    }
+}
+
+impl Chunking {
+    /// Splits the envelope into serialized chunk envelopes.
+    ///
+    /// Returns `None` if the payload fits into a single chunk.
+    pub(crate) fn split(&self, envelope: &OutgoingEnvelope) -> Result<Option<Vec<String>>, Error> {
+        let payload = envelope.payload_str();
+
+        let chunk_size = self.chunk_size.get();
+
+        if payload.len() <= chunk_size {
+            return Ok(None);
+        }
+
+        let to_error =
+            |e: serde_json::Error| Error::new(&format!("error serializing chunk, {}", e));
+
+        let mut properties = envelope.properties_map()?;
+
+        let pieces = split_str(payload, chunk_size);
+        let transfer_id = Uuid::new_v4().to_string();
+        properties.insert(TRANSFER_ID_PROPERTY.to_owned(), Value::from(transfer_id));
+        properties.insert(
+            COUNT_PROPERTY.to_owned(),
+            Value::from(pieces.len().to_string()),
+        );
+
+        pieces
+            .into_iter()
+            .enumerate()
+            .map(|(index, piece)| {
+                properties.insert(INDEX_PROPERTY.to_owned(), Value::from(index.to_string()));
+
+                let chunk = ChunkEnvelope {
+                    payload: ChunkPayload::String(piece),
+                    properties: &properties,
+                };
+
+                serde_json::to_string(&chunk).map_err(to_error)
+            })
+            .collect::<Result<Vec<_>, _>>()
+            .map(Some)
+    }
+}
+
+/// Splits the string into pieces of at most `size` bytes on char boundaries.
+fn split_str(value: &str, size: usize) -> Vec<&str> {
+    let mut pieces = Vec::with_capacity(value.len() / size + 1);
+    let mut rest = value;
+
+    while !rest.is_empty() {
+        let mut end = size.min(rest.len());
+
+        while !rest.is_char_boundary(end) {
+            end -= 1;
+        }
+
+        // A single char is larger than the chunk size.
+        if end == 0 {
+            end = rest.chars().next().map_or(rest.len(), char::len_utf8);
+        }
+
+        let (piece, tail) = rest.split_at(end);
+        pieces.push(piece);
+        rest = tail;
+    }
+
+    pieces
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+enum ChunkPayload<'a> {
+    String(&'a str),
+    Raw(&'a RawValue),
+}
+
+#[derive(Serialize)]
+struct ChunkEnvelope<'a> {
+    payload: ChunkPayload<'a>,
+    properties: &'a Map<String, Value>,
+}
+
+#[derive(Deserialize)]
+struct IncomingChunk {
+    payload: String,
+    properties: Map<String, Value>,
+}
+
+/// Chunk properties only. Used to tell chunks from regular messages without parsing them fully.
+#[derive(Deserialize)]
+struct IncomingChunkMarker {
+    properties: IncomingChunkProperties,
+}
+
+#[derive(Deserialize)]
+struct IncomingChunkProperties {
+    chunk_transfer_id: Option<String>,
+}
+
+struct Transfer {
+    // Chunks are stored as they arrive so the count announced by the sender allocates nothing.
+    chunks: BTreeMap<usize, String>,
+    count: usize,
+    size: usize,
+    properties: Map<String, Value>,
+    started_at: Instant,
+}
+
+/// Sender agent id and transfer id.
+///
+/// Transfer ids are only unique per sender so chunks of different senders never mix up.
+type TransferKey = (String, String);
+
+/// Reassembles incoming chunks into complete messages.
+pub(crate) struct Reassembler {
+    config: Chunking,
+    transfers: HashMap<TransferKey, Transfer>,
+}
+
+impl Reassembler {
+    pub(crate) fn new(config: Chunking) -> Self {
+        Self {
+            config,
+            transfers: HashMap::new(),
+        }
+    }
+
+    /// Puts the reassembler behind a lock and spawns a task dropping expired transfers every
+    /// `timeout` so they don't linger until the next chunk arrives.
+    ///
+    /// The task stops when the reassembler gets dropped.
+    pub(crate) fn with_eviction(self) -> Arc<Mutex<Self>> {
+        let period = Duration::from_secs(self.config.timeout.max(1));
+        let reassembler = Arc::new(Mutex::new(self));
+        let weak_reassembler = Arc::downgrade(&reassembler);
+
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(period);
+
+            loop {
+                interval.tick().await;
+
+                match weak_reassembler.upgrade() {
+                    Some(reassembler) => reassembler
+                        .lock()
+                        .unwrap_or_else(PoisonError::into_inner)
+                        .evict_expired(),
+                    None => break,
+                }
+            }
+        });
+
+        reassembler
+    }
+
+    /// Accepts an incoming packet.
+    ///
+    /// Returns the packet as is if it's not a chunk, the reassembled packet if it's the last
+    /// missing chunk of a transfer and `None` otherwise.
+    pub(crate) fn push(&mut self, publish: Publish) -> Result<Option<Publish>, Error> {
+        match serde_json::from_slice::<IncomingChunkMarker>(&publish.payload) {
+            Ok(IncomingChunkMarker {
+                properties:
+                    IncomingChunkProperties {
+                        chunk_transfer_id: Some(_),
+                    },
+            }) => (),
+            _ => return Ok(Some(publish)),
+        }
+
+        self.evict_expired();
+
+        let chunk = serde_json::from_slice::<IncomingChunk>(&publish.payload)
+            .map_err(|e| Error::new(&format!("error parsing chunk, {}", e)))?;
+
+        let mut properties = chunk.properties;
+        let transfer_id = take_property(&mut properties, TRANSFER_ID_PROPERTY)?;
+
+        let sender = match properties.get(SENDER_PROPERTY) {
+            Some(Value::String(sender)) => sender.to_owned(),
+            _ => {
+                return Err(Error::new(&format!(
+                    "missing chunk property = '{}'",
+                    SENDER_PROPERTY
+                )))
+            }
+        };
+        let index = take_property(&mut properties, INDEX_PROPERTY)?
+            .parse::<usize>()
+            .map_err(|e| Error::new(&format!("invalid chunk index, {}", e)))?;
+        let count = take_property(&mut properties, COUNT_PROPERTY)?
+            .parse::<usize>()
+            .map_err(|e| Error::new(&format!("invalid chunk count, {}", e)))?;
+
+        // Chunks are never empty so there can't be more of them than bytes in the transfer.
+        if index >= count || count > self.config.max_transfer_size || chunk.payload.is_empty() {
+            return Err(Error::new(&format!(
+                "invalid chunk {} of {} in transfer = '{}'",
+                index, count, transfer_id
+            )));
+        }
+
+        let key = (sender, transfer_id);
+
+        if !self.transfers.contains_key(&key) {
+            // Someone else may have seen the transfer id on a shared topic.
+            let is_foreign = self.transfers.keys().any(|(_, id)| *id == key.1);
+
+            if is_foreign {
+                return Err(Error::new(&format!(
+                    "chunk of transfer = '{}' from another sender = '{}'",
+                    key.1, key.0
+                )));
+            }
+
+            self.make_room();
+
+            let transfer = Transfer {
+                chunks: BTreeMap::new(),
+                count,
+                size: 0,
+                properties,
+                started_at: Instant::now(),
+            };
+
+            self.transfers.insert(key.clone(), transfer);
+        }
+
+        let transfer = self
+            .transfers
+            .get_mut(&key)
+            .ok_or_else(|| Error::new("missing transfer"))?;
+
+        let transfer_id = &key.1;
+
+        if transfer.count != count {
+            self.transfers.remove(&key);
+
+            return Err(Error::new(&format!(
+                "inconsistent chunk count in transfer = '{}'",
+                transfer_id
+            )));
+        }
+
+        // Duplicate delivery of a chunk.
+        if transfer.chunks.contains_key(&index) {
+            return Ok(None);
+        }
+
+        transfer.size += chunk.payload.len();
+
+        if transfer.size > self.config.max_transfer_size {
+            self.transfers.remove(&key);
+
+            return Err(Error::new(&format!(
+                "transfer = '{}' exceeds {} bytes",
+                transfer_id, self.config.max_transfer_size
+            )));
+        }
+
+        transfer.chunks.insert(index, chunk.payload);
+
+        if transfer.chunks.len() < count {
+            return Ok(None);
+        }
+
+        let transfer = self
+            .transfers
+            .remove(&key)
+            .ok_or_else(|| Error::new("missing transfer"))?;
+
+        let payload = transfer.assemble()?;
+
+        Ok(Some(Publish { payload, ..publish }))
+    }
+
+    fn evict_expired(&mut self) {
+        let timeout = Duration::from_secs(self.config.timeout);
+
+        self.transfers.retain(|(_, transfer_id), transfer| {
+            let is_expired = transfer.started_at.elapsed() > timeout;
+
+            if is_expired {
+                warn!(
+                    "Dropping transfer = '{}' missing {} of {} chunks after timeout",
+                    transfer_id,
+                    transfer.count - transfer.chunks.len(),
+                    transfer.count
+                );
+            }
+
+            !is_expired
+        });
+    }
+
+    fn make_room(&mut self) {
+        while self.transfers.len() >= self.config.max_transfers.max(1) {
+            let oldest = self
+                .transfers
+                .iter()
+                .min_by_key(|(_, transfer)| transfer.started_at)
+                .map(|(key, _)| key.to_owned());
+
+            match oldest {
+                Some(key) => {
+                    warn!(
+                        "Dropping transfer = '{}' to start a new one: too many transfers",
+                        key.1
+                    );
+
+                    self.transfers.remove(&key);
+                }
+                None => break,
+            }
+        }
+    }
+}
+
+impl Transfer {
+    /// Builds the envelope of the original message.
+    fn assemble(self) -> Result<Bytes, Error> {
+        let mut joined = String::with_capacity(self.size);
+
+        for chunk in self.chunks.into_values() {
+            joined.push_str(&chunk);
+        }
+
+        let is_raw = self
+            .properties
+            .get("payload_format")
+            .and_then(Value::as_str)
+            == Some(RAW_PAYLOAD_FORMAT);
+
+        let to_error =
+            |e: serde_json::Error| Error::new(&format!("error reassembling chunks, {}", e));
+
+        let raw_payload;
+
+        let payload = if is_raw {
+            raw_payload = RawValue::from_string(joined).map_err(to_error)?;
+            ChunkPayload::Raw(&raw_payload)
+        } else {
+            ChunkPayload::String(&joined)
+        };
+
+        let envelope = ChunkEnvelope {
+            payload,
+            properties: &self.properties,
+        };
+
+        serde_json::to_vec(&envelope)
+            .map(Bytes::from)
+            .map_err(to_error)
+    }
+}
+
+fn take_property(properties: &mut Map<String, Value>, key: &str) -> Result<String, Error> {
+    match properties.remove(key) {
+        Some(Value::String(value)) => Ok(value),
+        _ => Err(Error::new(&format!("missing chunk property = '{}'", key))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rumqttc::QoS;
+    use serde_json::json;
+
+    use super::*;
+
+    const SENDER: &str = "instance01.sender.svc.example.org";
+
+    fn chunk_from(sender: &str, index: usize, count: usize, payload: &str) -> Publish {
+        let envelope = json!({
+            "payload": payload,
+            "properties": {
+                "type": "event",
+                "agent_id": sender,
+                "chunk_transfer_id": "transfer",
+                "chunk_index": index.to_string(),
+                "chunk_count": count.to_string(),
+            },
+        });
+
+        Publish::new("topic", QoS::AtLeastOnce, envelope.to_string())
+    }
+
+    fn chunk(index: usize, count: usize, payload: &str) -> Publish {
+        chunk_from(SENDER, index, count, payload)
+    }
+
+    fn key() -> TransferKey {
+        (SENDER.to_owned(), "transfer".to_owned())
+    }
+
+    #[test]
+    fn rejects_count_exceeding_transfer_size() {
+        let mut reassembler = Reassembler::new(Chunking::default());
+        let count = DEFAULT_MAX_TRANSFER_SIZE + 1;
+
+        assert!(reassembler.push(chunk(0, count, "foo")).is_err());
+        assert!(reassembler.transfers.is_empty());
+    }
+
+    #[test]
+    fn stores_chunks_as_they_arrive() {
+        let mut reassembler = Reassembler::new(Chunking::default());
+        let count = DEFAULT_MAX_TRANSFER_SIZE;
+
+        assert!(reassembler.push(chunk(1, count, "foo")).unwrap().is_none());
+        assert_eq!(reassembler.transfers[&key()].chunks.len(), 1);
+    }
+
+    #[test]
+    fn reassembles_chunks() {
+        let mut reassembler = Reassembler::new(Chunking::default());
+
+        assert!(reassembler.push(chunk(1, 2, "bar")).unwrap().is_none());
+        let publish = reassembler.push(chunk(0, 2, "foo")).unwrap().unwrap();
+        let envelope = serde_json::from_slice::<Value>(&publish.payload).unwrap();
+
+        assert_eq!(envelope["payload"], "foobar");
+        assert!(envelope["properties"].get(COUNT_PROPERTY).is_none());
+    }
+
+    #[test]
+    fn rejects_zero_chunk_size() {
+        assert!(serde_json::from_value::<Chunking>(json!({ "chunk_size": 0 })).is_err());
+        assert!(serde_json::from_value::<Chunking>(json!({ "chunk_size": 1 })).is_ok());
+    }
+
+    #[test]
+    fn rejects_chunk_without_sender() {
+        let mut reassembler = Reassembler::new(Chunking::default());
+        let mut envelope = serde_json::from_slice::<Value>(&chunk(0, 2, "foo").payload).unwrap();
+        envelope["properties"]
+            .as_object_mut()
+            .unwrap()
+            .remove(SENDER_PROPERTY);
+        let publish = Publish::new("topic", QoS::AtLeastOnce, envelope.to_string());
+
+        assert!(reassembler.push(publish).is_err());
+    }
+
+    #[test]
+    fn rejects_chunk_from_another_sender() {
+        let mut reassembler = Reassembler::new(Chunking::default());
+        let intruder = "instance01.intruder.svc.example.org";
+
+        assert!(reassembler.push(chunk(0, 2, "foo")).unwrap().is_none());
+        assert!(reassembler.push(chunk_from(intruder, 1, 2, "baz")).is_err());
+
+        let publish = reassembler.push(chunk(1, 2, "bar")).unwrap().unwrap();
+        let envelope = serde_json::from_slice::<Value>(&publish.payload).unwrap();
+        assert_eq!(envelope["payload"], "foobar");
+        assert_eq!(envelope["properties"][SENDER_PROPERTY], SENDER);
+    }
+
+    #[tokio::test]
+    async fn evicts_expired_transfers_on_timer() {
+        let config = serde_json::from_value::<Chunking>(json!({ "timeout": 0 })).unwrap();
+        let mut reassembler = Reassembler::new(config);
+        assert!(reassembler.push(chunk(0, 2, "foo")).unwrap().is_none());
+
+        let reassembler = reassembler.with_eviction();
+
+        for _ in 0..100 {
+            if reassembler.lock().unwrap().transfers.is_empty() {
+                return;
+            }
+
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        panic!("expired transfer has not been evicted");
+    }
+}
@@ -0,0 +1,206 @@
+use serde::{de::DeserializeOwned, Serialize, Serializer};
+
+use crate::Error;
+
+/// Payload serialization format.
+///
+/// The envelope itself is always JSON. Payloads of binary formats are encoded with base64
+/// to fit into the envelope's string payload field. The format is passed to receivers in the
+/// `content_type` property so they could pick the decoder. Messages without the property
+/// are considered JSON.
+///
+/// JSON is always available, MessagePack and CBOR require `msgpack` and `cbor`
+/// cargo features respectively.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Codec {
+    #[default]
+    Json,
+    #[cfg(feature = "msgpack")]
+    MessagePack,
+    #[cfg(feature = "cbor")]
+    Cbor,
+}
+
+impl Codec {
+    /// Returns the value of `content_type` property for the codec.
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Self::Json => "application/json",
+            #[cfg(feature = "msgpack")]
+            Self::MessagePack => "application/msgpack",
+            #[cfg(feature = "cbor")]
+            Self::Cbor => "application/cbor",
+        }
+    }
+
+    /// Picks a codec by `content_type` property value. Missing value means JSON.
+    pub fn from_content_type(content_type: Option<&str>) -> Result<Self, Error> {
+        match content_type {
+            None | Some("application/json") => Ok(Self::Json),
+            #[cfg(feature = "msgpack")]
+            Some("application/msgpack") => Ok(Self::MessagePack),
+            #[cfg(feature = "cbor")]
+            Some("application/cbor") => Ok(Self::Cbor),
+            Some(other) => Err(Error::new(&format!(
+                "unsupported content type = '{}'",
+                other
+            ))),
+        }
+    }
+
+    /// Serializes the value into an envelope payload string.
+    pub fn encode<T: Serialize>(&self, value: &T) -> Result<String, Error> {
+        let to_error = |e: &dyn std::fmt::Display| {
+            Error::new(&format!("error serializing payload of an envelope, {}", e))
+        };
+
+        match self {
+            Self::Json => serde_json::to_string(value).map_err(|e| to_error(&e)),
+            #[cfg(feature = "msgpack")]
+            Self::MessagePack => rmp_serde::to_vec_named(value)
+                .map(|bytes| encode_base64(&bytes))
+                .map_err(|e| to_error(&e)),
+            #[cfg(feature = "cbor")]
+            Self::Cbor => {
+                let mut bytes = Vec::new();
+
+                ciborium::ser::into_writer(value, &mut bytes)
+                    .map(|()| encode_base64(&bytes))
+                    .map_err(|e| to_error(&e))
+            }
+        }
+    }
+
+    /// Deserializes an envelope payload string.
+    pub fn decode<T: DeserializeOwned>(&self, payload: &str) -> Result<T, Error> {
+        let to_error = |e: &dyn std::fmt::Display| {
+            Error::new(&format!(
+                "error deserializing payload of an envelope, {}",
+                e
+            ))
+        };
+
+        match self {
+            Self::Json => serde_json::from_str::<T>(payload).map_err(|e| to_error(&e)),
+            #[cfg(feature = "msgpack")]
+            Self::MessagePack => {
+                rmp_serde::from_slice::<T>(&decode_base64(payload)?).map_err(|e| to_error(&e))
+            }
+            #[cfg(feature = "cbor")]
+            Self::Cbor => ciborium::de::from_reader::<T, _>(&decode_base64(payload)?[..])
+                .map_err(|e| to_error(&e)),
+        }
+    }
+}
+
+impl Serialize for Codec {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.content_type())
+    }
+}
+
+#[cfg_attr(
+    not(any(
+        feature = "msgpack",
+        feature = "cbor",
+        feature = "zstd",
+        feature = "gzip"
+    )),
+    allow(dead_code)
+)]
+pub(crate) fn encode_base64(bytes: &[u8]) -> String {
+    use base64::Engine;
+
+    base64::engine::general_purpose::STANDARD.encode(bytes)
+}
+
+pub(crate) fn decode_base64(payload: &str) -> Result<Vec<u8>, Error> {
+    use base64::Engine;
+
+    base64::engine::general_purpose::STANDARD
+        .decode(payload)
+        .map_err(|e| Error::new(&format!("error decoding base64 payload, {}", e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Deserialize;
+
+    use super::*;
+    use crate::mqtt::{
+        publishable::DumpOptions, testing::deliver, Address, IncomingEvent, IncomingMessage,
+        OutgoingEvent, OutgoingEventProperties, OutgoingShortTermTimingProperties,
+    };
+    use crate::{AccountId, AgentId};
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Room {
+        id: u64,
+        name: String,
+        tags: Vec<String>,
+    }
+
+    fn room() -> Room {
+        Room {
+            id: 123,
+            name: "Lobby".to_owned(),
+            tags: vec!["public".to_owned()],
+        }
+    }
+
+    fn codecs() -> Vec<Codec> {
+        #[allow(unused_mut)]
+        let mut codecs = vec![Codec::Json];
+        #[cfg(feature = "msgpack")]
+        codecs.push(Codec::MessagePack);
+        #[cfg(feature = "cbor")]
+        codecs.push(Codec::Cbor);
+        codecs
+    }
+
+    #[test]
+    fn round_trips_payload() {
+        for codec in codecs() {
+            let payload = codec.encode(&room()).unwrap();
+            assert_eq!(codec.decode::<Room>(&payload).unwrap(), room());
+        }
+    }
+
+    #[test]
+    fn picks_codec_by_content_type() {
+        for codec in codecs() {
+            let content_type = Some(codec.content_type());
+            assert_eq!(Codec::from_content_type(content_type).unwrap(), codec);
+        }
+
+        assert_eq!(Codec::from_content_type(None).unwrap(), Codec::Json);
+        assert!(Codec::from_content_type(Some("text/plain")).is_err());
+    }
+
+    #[test]
+    fn delivers_payload_with_content_type() {
+        let account_id = AccountId::new("sender", "svc.example.org");
+        let address = Address::new(AgentId::new("instance01", account_id), "v1");
+
+        for codec in codecs() {
+            let timing = OutgoingShortTermTimingProperties::new(chrono::Utc::now());
+            let mut props = OutgoingEventProperties::new("room.update", timing);
+            props.set_codec(codec);
+
+            let message = OutgoingEvent::broadcast(room(), props, "rooms/123/events");
+            let dumps = message
+                .into_dumps(&address, &DumpOptions::default())
+                .unwrap();
+
+            let event = match deliver(&dumps[0]) {
+                IncomingMessage::Event(event) => event,
+                _ => panic!("expected an event"),
+            };
+
+            assert_eq!(event.properties().codec().unwrap(), codec);
+
+            let event = IncomingEvent::convert::<Room>(event).unwrap();
+            assert_eq!(event.payload(), &room());
+        }
+    }
+}
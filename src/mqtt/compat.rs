@@ -68,15 +68,24 @@
 ///
 /// Enveloping of outgoing messages is up to svc-agent.
 /// Just use (Agent::publish)[../struct.Agent.html#method.publish] method to publish messages.
-use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+
+use bytes::Bytes;
+use serde::{ser::SerializeStruct, Deserialize, Serialize, Serializer};
+use serde_json::{value::RawValue, Map, Value};
 
 use super::{
-    Destination, IncomingEvent, IncomingEventProperties, IncomingMessage, IncomingRequest,
-    IncomingRequestProperties, IncomingResponse, IncomingResponseProperties,
-    OutgoingEventProperties, OutgoingRequestProperties, OutgoingResponseProperties,
+    compression::{self, CompressionStats},
+    Codec, Compression, Destination, IncomingEvent, IncomingEventProperties, IncomingMessage,
+    IncomingPayload, IncomingRequest, IncomingRequestProperties, IncomingResponse,
+    IncomingResponseProperties, OutgoingEventProperties, OutgoingRequestProperties,
+    OutgoingResponseProperties,
 };
 use crate::Error;
 
+/// `payload_format` property value of envelopes with the payload embedded as raw JSON.
+pub(crate) const RAW_PAYLOAD_FORMAT: &str = "raw";
+
 ////////////////////////////////////////////////////////////////////////////////
 
 /// Enveloped properties of an incoming message.
@@ -90,21 +99,80 @@ pub(crate) enum IncomingEnvelopeProperties {
 }
 
 /// Incoming enveloped message.
+///
+/// The payload is borrowed from the packet bytes. It's either a JSON string or raw JSON
+/// if the sender has marked it with the `payload_format` property.
 #[derive(Debug, Deserialize)]
-pub(crate) struct IncomingEnvelope {
-    payload: String,
+pub(crate) struct IncomingEnvelope<'a> {
+    #[serde(borrow)]
+    payload: &'a RawValue,
     properties: IncomingEnvelopeProperties,
 }
 
-impl IncomingEnvelope {
+impl<'a> IncomingEnvelope<'a> {
     pub(crate) fn properties(&self) -> &IncomingEnvelopeProperties {
         &self.properties
     }
+
+    /// Extracts the payload from the packet `buffer` the envelope has been parsed from.
+    ///
+    /// The payload is copied only if it needs unescaping or decompression.
+    fn payload(&self, buffer: &Bytes) -> Result<IncomingPayload, Error> {
+        match self.payload_str()? {
+            Cow::Borrowed(payload) => IncomingPayload::from_buffer(buffer, payload),
+            Cow::Owned(payload) => Ok(IncomingPayload::from(payload)),
+        }
+    }
+
+    /// Extracts the payload string decompressing it if the envelope properties
+    /// specify a content encoding.
+    fn payload_str(&self) -> Result<Cow<'a, str>, Error> {
+        let (payload_format, content_encoding) = match self.properties {
+            IncomingEnvelopeProperties::Event(ref props) => {
+                (props.payload_format(), props.content_encoding())
+            }
+            IncomingEnvelopeProperties::Request(ref props) => {
+                (props.payload_format(), props.content_encoding())
+            }
+            IncomingEnvelopeProperties::Response(ref props) => {
+                (props.payload_format(), props.content_encoding())
+            }
+        };
+
+        if payload_format == Some(RAW_PAYLOAD_FORMAT) {
+            return Ok(Cow::Borrowed(self.payload.get()));
+        }
+
+        let payload = parse_json_string(self.payload.get())?;
+
+        match content_encoding {
+            Some(content_encoding) => {
+                compression::decompress(content_encoding, &payload).map(Cow::Owned)
+            }
+            None => Ok(payload),
+        }
+    }
+}
+
+/// Parses a JSON string borrowing it when there are no escape sequences to unescape.
+fn parse_json_string(json: &str) -> Result<Cow<'_, str>, Error> {
+    let to_error =
+        |e: serde_json::Error| Error::new(&format!("error parsing payload of an envelope, {}", e));
+
+    match serde_json::from_str::<&str>(json) {
+        Ok(value) => Ok(Cow::Borrowed(value)),
+        Err(_) => serde_json::from_str::<String>(json)
+            .map(Cow::Owned)
+            .map_err(to_error),
+    }
 }
 
 /// Parses an incoming envelope as an event with payload of type `T`.
-pub(crate) fn into_event(envelope: IncomingEnvelope) -> Result<IncomingMessage<String>, Error> {
-    let payload = envelope.payload;
+pub(crate) fn into_event(
+    envelope: IncomingEnvelope,
+    buffer: &Bytes,
+) -> Result<IncomingMessage<IncomingPayload>, Error> {
+    let payload = envelope.payload(buffer)?;
     match envelope.properties {
         IncomingEnvelopeProperties::Event(props) => {
             Ok(IncomingMessage::Event(IncomingEvent::new(payload, props)))
@@ -114,8 +182,11 @@ pub(crate) fn into_event(envelope: IncomingEnvelope) -> Result<IncomingMessage<S
 }
 
 /// Parses an incoming envelope as a request with payload of type `T`.
-pub(crate) fn into_request(envelope: IncomingEnvelope) -> Result<IncomingMessage<String>, Error> {
-    let payload = envelope.payload;
+pub(crate) fn into_request(
+    envelope: IncomingEnvelope,
+    buffer: &Bytes,
+) -> Result<IncomingMessage<IncomingPayload>, Error> {
+    let payload = envelope.payload(buffer)?;
     match envelope.properties {
         IncomingEnvelopeProperties::Request(props) => Ok(IncomingMessage::Request(
             IncomingRequest::new(payload, props),
@@ -125,8 +196,11 @@ pub(crate) fn into_request(envelope: IncomingEnvelope) -> Result<IncomingMessage
 }
 
 /// Parses an incoming envelope as a response with payload of type `T`.
-pub(crate) fn into_response(envelope: IncomingEnvelope) -> Result<IncomingMessage<String>, Error> {
-    let payload = envelope.payload;
+pub(crate) fn into_response(
+    envelope: IncomingEnvelope,
+    buffer: &Bytes,
+) -> Result<IncomingMessage<IncomingPayload>, Error> {
+    let payload = envelope.payload(buffer)?;
     match envelope.properties {
         IncomingEnvelopeProperties::Response(props) => Ok(IncomingMessage::Response(
             IncomingResponse::new(payload, props),
@@ -147,14 +221,79 @@ pub enum OutgoingEnvelopeProperties {
     Response(OutgoingResponseProperties),
 }
 
-/// Outgoing enveloped message.
+impl OutgoingEnvelopeProperties {
+    /// Returns the recipient to encrypt the payload for.
+    #[cfg(feature = "encryption")]
+    pub(crate) fn recipient(&self) -> Option<&crate::encryption::Recipient> {
+        match self {
+            Self::Event(props) => props.recipient(),
+            Self::Request(props) => props.recipient(),
+            Self::Response(props) => props.recipient(),
+        }
+    }
+
+    #[cfg(feature = "encryption")]
+    fn set_payload_format(&mut self, payload_format: Option<&'static str>) {
+        match self {
+            Self::Event(props) => {
+                props.set_payload_format(payload_format);
+            }
+            Self::Request(props) => {
+                props.set_payload_format(payload_format);
+            }
+            Self::Response(props) => {
+                props.set_payload_format(payload_format);
+            }
+        }
+    }
+}
+
+/// Payload of an outgoing envelope: either serialized to a JSON string or embedded as raw JSON.
 #[derive(Debug, Serialize)]
+#[serde(untagged)]
+enum OutgoingEnvelopePayload {
+    String(String),
+    Raw(Box<RawValue>),
+}
+
+/// Outgoing enveloped message.
+#[derive(Debug)]
 pub struct OutgoingEnvelope {
-    payload: String,
+    payload: OutgoingEnvelopePayload,
     pub(crate) properties: OutgoingEnvelopeProperties,
-    #[serde(skip)]
     #[allow(dead_code)]
     destination: Destination,
+    compression: Option<CompressionStats>,
+    /// Properties added on top of the typed ones, e.g. a signature.
+    extra_properties: Map<String, Value>,
+}
+
+impl Serialize for OutgoingEnvelope {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        #[derive(Serialize)]
+        struct ExtendedProperties<'a> {
+            #[serde(flatten)]
+            properties: &'a OutgoingEnvelopeProperties,
+            #[serde(flatten)]
+            extra_properties: &'a Map<String, Value>,
+        }
+
+        let mut envelope = serializer.serialize_struct("OutgoingEnvelope", 2)?;
+        envelope.serialize_field("payload", &self.payload)?;
+
+        if self.extra_properties.is_empty() {
+            envelope.serialize_field("properties", &self.properties)?;
+        } else {
+            let properties = ExtendedProperties {
+                properties: &self.properties,
+                extra_properties: &self.extra_properties,
+            };
+
+            envelope.serialize_field("properties", &properties)?;
+        }
+
+        envelope.end()
+    }
 }
 
 impl OutgoingEnvelope {
@@ -171,11 +310,68 @@ impl OutgoingEnvelope {
         destination: Destination,
     ) -> Self {
         Self {
-            payload: payload.to_owned(),
+            payload: OutgoingEnvelopePayload::String(payload.to_owned()),
             properties,
             destination,
+            compression: None,
+            extra_properties: Map::new(),
         }
     }
+
+    /// Returns payload compression stats if the payload has been compressed.
+    pub fn compression(&self) -> Option<CompressionStats> {
+        self.compression
+    }
+
+    /// Returns the payload as it's embedded into the envelope.
+    pub(crate) fn payload_str(&self) -> &str {
+        match self.payload {
+            OutgoingEnvelopePayload::String(ref payload) => payload,
+            OutgoingEnvelopePayload::Raw(ref payload) => payload.get(),
+        }
+    }
+
+    /// Returns all the envelope properties as a JSON object.
+    pub(crate) fn properties_map(&self) -> Result<Map<String, Value>, Error> {
+        let to_error = |e: &dyn std::fmt::Display| {
+            Error::new(&format!("error serializing envelope properties, {}", e))
+        };
+
+        match serde_json::to_value(&self.properties).map_err(|e| to_error(&e))? {
+            Value::Object(mut properties) => {
+                properties.extend(self.extra_properties.clone());
+                Ok(properties)
+            }
+            _ => Err(to_error(&"not an object")),
+        }
+    }
+
+    /// Returns `payload_format` property value matching the way the payload is embedded.
+    #[cfg(feature = "encryption")]
+    pub(crate) fn payload_format(&self) -> Option<&'static str> {
+        match self.payload {
+            OutgoingEnvelopePayload::String(_) => None,
+            OutgoingEnvelopePayload::Raw(_) => Some(RAW_PAYLOAD_FORMAT),
+        }
+    }
+
+    /// Replaces the payload with a string, e.g. a ciphertext.
+    ///
+    /// Drops `payload_format` property since the payload is not embedded as raw JSON anymore.
+    #[cfg(feature = "encryption")]
+    pub(crate) fn set_payload(&mut self, payload: String) {
+        self.payload = OutgoingEnvelopePayload::String(payload);
+        self.properties.set_payload_format(None);
+    }
+
+    /// Adds a property to the envelope on top of the typed ones.
+    #[cfg_attr(
+        not(any(feature = "encryption", feature = "signing")),
+        allow(dead_code)
+    )]
+    pub(crate) fn insert_property(&mut self, key: &str, value: Value) {
+        self.extra_properties.insert(key.to_owned(), value);
+    }
 }
 
 ////////////////////////////////////////////////////////////////////////////////
@@ -188,19 +384,94 @@ pub trait IntoEnvelope {
 ////////////////////////////////////////////////////////////////////////////////
 
 use super::OutgoingMessage;
+
+/// Outgoing payload encoded according to the message properties.
+struct EncodedPayload {
+    payload: OutgoingEnvelopePayload,
+    content_encoding: Option<&'static str>,
+    payload_format: Option<&'static str>,
+    compression: Option<CompressionStats>,
+}
+
+impl EncodedPayload {
+    /// Serializes the payload with the `codec` and compresses it if it exceeds the `compression`
+    /// threshold. Uncompressed JSON payloads are embedded as raw JSON if `raw` is set.
+    fn new<T: serde::Serialize>(
+        payload: &T,
+        codec: Option<Codec>,
+        compression: Option<&Compression>,
+        raw: bool,
+    ) -> Result<Self, Error> {
+        let codec = codec.unwrap_or_default();
+        let encoded = codec.encode(payload)?;
+
+        let compressed = match compression {
+            Some(compression) => compression.compress(&encoded)?,
+            None => None,
+        };
+
+        match compressed {
+            Some(compressed) => Ok(Self {
+                payload: OutgoingEnvelopePayload::String(compressed.payload),
+                content_encoding: Some(compressed.content_encoding),
+                payload_format: None,
+                compression: Some(compressed.stats),
+            }),
+            None if raw && codec == Codec::Json => {
+                let raw_payload = RawValue::from_string(encoded).map_err(|e| {
+                    Error::new(&format!("error serializing payload of an envelope, {}", e))
+                })?;
+
+                Ok(Self {
+                    payload: OutgoingEnvelopePayload::Raw(raw_payload),
+                    content_encoding: None,
+                    payload_format: Some(RAW_PAYLOAD_FORMAT),
+                    compression: None,
+                })
+            }
+            None => Ok(Self {
+                payload: OutgoingEnvelopePayload::String(encoded),
+                content_encoding: None,
+                payload_format: None,
+                compression: None,
+            }),
+        }
+    }
+
+    fn into_envelope(
+        self,
+        properties: OutgoingEnvelopeProperties,
+        destination: Destination,
+    ) -> OutgoingEnvelope {
+        OutgoingEnvelope {
+            payload: self.payload,
+            properties,
+            destination,
+            compression: self.compression,
+            extra_properties: Map::new(),
+        }
+    }
+}
+
 impl<T> IntoEnvelope for super::OutgoingEvent<T>
 where
     T: serde::Serialize,
 {
     fn into_envelope(self) -> Result<OutgoingEnvelope, Error> {
-        let payload = serde_json::to_string(&self.payload)
-            .map_err(|e| Error::new(&format!("error serializing payload of an envelope, {}", e)))?;
-        let envelope = OutgoingEnvelope::new(
-            &payload,
-            OutgoingEnvelopeProperties::Event(self.properties),
-            self.destination,
-        );
-        Ok(envelope)
+        let mut properties = self.properties;
+
+        let encoded = EncodedPayload::new(
+            &self.payload,
+            properties.codec(),
+            properties.compression(),
+            properties.is_raw_payload(),
+        )?;
+
+        properties.set_content_encoding(encoded.content_encoding);
+        properties.set_payload_format(encoded.payload_format);
+
+        let properties = OutgoingEnvelopeProperties::Event(properties);
+        Ok(encoded.into_envelope(properties, self.destination))
     }
 }
 
@@ -209,14 +480,20 @@ where
     T: serde::Serialize,
 {
     fn into_envelope(self) -> Result<OutgoingEnvelope, Error> {
-        let payload = serde_json::to_string(&self.payload)
-            .map_err(|e| Error::new(&format!("error serializing payload of an envelope, {}", e)))?;
-        let envelope = OutgoingEnvelope::new(
-            &payload,
-            OutgoingEnvelopeProperties::Request(self.properties),
-            self.destination,
-        );
-        Ok(envelope)
+        let mut properties = self.properties;
+
+        let encoded = EncodedPayload::new(
+            &self.payload,
+            properties.codec(),
+            properties.compression(),
+            properties.is_raw_payload(),
+        )?;
+
+        properties.set_content_encoding(encoded.content_encoding);
+        properties.set_payload_format(encoded.payload_format);
+
+        let properties = OutgoingEnvelopeProperties::Request(properties);
+        Ok(encoded.into_envelope(properties, self.destination))
     }
 }
 
@@ -225,14 +502,20 @@ where
     T: serde::Serialize,
 {
     fn into_envelope(self) -> Result<OutgoingEnvelope, Error> {
-        let payload = serde_json::to_string(&self.payload)
-            .map_err(|e| Error::new(&format!("error serializing payload of an envelope, {}", e)))?;
-        let envelope = OutgoingEnvelope::new(
-            &payload,
-            OutgoingEnvelopeProperties::Response(self.properties),
-            self.destination,
-        );
-        Ok(envelope)
+        let mut properties = self.properties;
+
+        let encoded = EncodedPayload::new(
+            &self.payload,
+            properties.codec(),
+            properties.compression(),
+            properties.is_raw_payload(),
+        )?;
+
+        properties.set_content_encoding(encoded.content_encoding);
+        properties.set_payload_format(encoded.payload_format);
+
+        let properties = OutgoingEnvelopeProperties::Response(properties);
+        Ok(encoded.into_envelope(properties, self.destination))
     }
 }
 
@@ -248,3 +531,89 @@ where
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use rumqttc::{Packet, Publish, QoS};
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::mqtt::{
+        publishable::DumpOptions,
+        testing::{broker_properties, deliver, SENDER},
+        Address, AgentNotification, OutgoingEvent, OutgoingShortTermTimingProperties,
+        PublishableMessage,
+    };
+    use crate::{AccountId, AgentId};
+
+    fn dump(payload: &Value, raw: bool) -> PublishableMessage {
+        let account_id = AccountId::new("sender", "svc.example.org");
+        let address = Address::new(AgentId::new("instance01", account_id), "v1");
+        let timing = OutgoingShortTermTimingProperties::new(chrono::Utc::now());
+        let props = OutgoingEventProperties::new("room.update", timing);
+        let mut message = OutgoingEvent::broadcast(payload, props, "rooms/123/events");
+
+        if raw {
+            message.set_raw_payload();
+        }
+
+        let mut dumps = message
+            .into_dumps(&address, &DumpOptions::default())
+            .unwrap();
+        dumps.remove(0)
+    }
+
+    fn envelope(dump: &PublishableMessage) -> Value {
+        serde_json::from_str(dump.payload()).unwrap()
+    }
+
+    #[test]
+    fn embeds_raw_payload() {
+        let payload = json!({ "text": "say \"hello\"" });
+        let dump = dump(&payload, true);
+
+        let envelope = envelope(&dump);
+        assert_eq!(envelope["payload"], payload);
+        assert_eq!(envelope["properties"]["payload_format"], RAW_PAYLOAD_FORMAT);
+
+        match deliver(&dump) {
+            IncomingMessage::Event(event) => {
+                assert_eq!(event.payload().as_str(), payload.to_string())
+            }
+            _ => panic!("expected an event"),
+        }
+    }
+
+    #[test]
+    fn serializes_payload_into_string_by_default() {
+        let payload = json!({ "text": "say \"hello\"" });
+        let dump = dump(&payload, false);
+
+        let envelope = envelope(&dump);
+        assert_eq!(envelope["payload"], payload.to_string());
+        assert!(envelope["properties"].get("payload_format").is_none());
+
+        match deliver(&dump) {
+            IncomingMessage::Event(event) => {
+                assert_eq!(event.payload().as_str(), payload.to_string())
+            }
+            _ => panic!("expected an event"),
+        }
+    }
+
+    #[test]
+    fn rejects_raw_payload_without_format() {
+        let mut properties = broker_properties(SENDER);
+        properties["type"] = json!("event");
+        properties["label"] = json!("room.update");
+
+        // Senders not aware of raw payloads always put a string into the envelope.
+        let envelope = json!({ "payload": { "id": 1 }, "properties": properties });
+        let publish = Publish::new("topic", QoS::AtLeastOnce, envelope.to_string());
+
+        assert!(matches!(
+            AgentNotification::from(Packet::Publish(publish)),
+            AgentNotification::Message(Err(_), _)
+        ));
+    }
+}
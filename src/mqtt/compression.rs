@@ -0,0 +1,300 @@
+use serde::Deserialize;
+
+use crate::Error;
+
+/// Default payload size in bytes to compress payloads above.
+const DEFAULT_THRESHOLD: usize = 64 * 1024;
+/// Maximum size of a decompressed payload to protect from decompression bombs.
+const MAX_DECOMPRESSED_SIZE: u64 = 64 * 1024 * 1024;
+
+/// Payload compression algorithm.
+///
+/// zstd and gzip require `zstd` and `gzip` cargo features respectively.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CompressionAlgorithm {
+    #[cfg(feature = "zstd")]
+    Zstd,
+    #[cfg(feature = "gzip")]
+    Gzip,
+}
+
+impl CompressionAlgorithm {
+    /// Returns the value of `content_encoding` property for the algorithm.
+    pub fn content_encoding(&self) -> &'static str {
+        match *self {
+            #[cfg(feature = "zstd")]
+            Self::Zstd => "zstd",
+            #[cfg(feature = "gzip")]
+            Self::Gzip => "gzip",
+        }
+    }
+
+    fn from_content_encoding(content_encoding: &str) -> Result<Self, Error> {
+        match content_encoding {
+            #[cfg(feature = "zstd")]
+            "zstd" => Ok(Self::Zstd),
+            #[cfg(feature = "gzip")]
+            "gzip" => Ok(Self::Gzip),
+            other => Err(Error::new(&format!(
+                "unsupported content encoding = '{}'",
+                other
+            ))),
+        }
+    }
+
+    #[allow(unused_variables)]
+    fn compress(&self, data: &[u8], level: Option<i32>) -> Result<Vec<u8>, Error> {
+        let to_error = |e: std::io::Error| Error::new(&format!("error compressing payload, {}", e));
+
+        match *self {
+            #[cfg(feature = "zstd")]
+            Self::Zstd => zstd::stream::encode_all(data, level.unwrap_or(0)).map_err(to_error),
+            #[cfg(feature = "gzip")]
+            Self::Gzip => {
+                use std::io::Write;
+
+                let level = level.map_or_else(flate2::Compression::default, |level| {
+                    flate2::Compression::new(level.clamp(0, 9) as u32)
+                });
+
+                let mut encoder = flate2::write::GzEncoder::new(Vec::new(), level);
+                encoder.write_all(data).map_err(to_error)?;
+                encoder.finish().map_err(to_error)
+            }
+        }
+    }
+
+    #[allow(unused_variables)]
+    fn decompress(&self, data: &[u8]) -> Result<Vec<u8>, Error> {
+        match *self {
+            #[cfg(feature = "zstd")]
+            Self::Zstd => read_limited(
+                zstd::stream::Decoder::new(data)
+                    .map_err(|e| Error::new(&format!("error decompressing payload, {}", e)))?,
+            ),
+            #[cfg(feature = "gzip")]
+            Self::Gzip => read_limited(flate2::read::GzDecoder::new(data)),
+        }
+    }
+}
+
+#[cfg_attr(not(any(feature = "zstd", feature = "gzip")), allow(dead_code))]
+fn read_limited<R: std::io::Read>(reader: R) -> Result<Vec<u8>, Error> {
+    use std::io::Read;
+
+    let mut decompressed = Vec::new();
+
+    reader
+        .take(MAX_DECOMPRESSED_SIZE + 1)
+        .read_to_end(&mut decompressed)
+        .map_err(|e| Error::new(&format!("error decompressing payload, {}", e)))?;
+
+    if decompressed.len() as u64 > MAX_DECOMPRESSED_SIZE {
+        return Err(Error::new(&format!(
+            "decompressed payload exceeds {} bytes",
+            MAX_DECOMPRESSED_SIZE
+        )));
+    }
+
+    Ok(decompressed)
+}
+
+fn default_threshold() -> usize {
+    DEFAULT_THRESHOLD
+}
+
+/// Payload compression settings.
+///
+/// # Options
+///
+/// * `algorithm` – `zstd` or `gzip`.
+/// * `threshold` – compress payloads larger than this number of bytes. Default: 64 KiB.
+/// * `level` – compression level specific to the algorithm. Default: the algorithm's default.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Compression {
+    algorithm: CompressionAlgorithm,
+    #[serde(default = "default_threshold")]
+    threshold: usize,
+    level: Option<i32>,
+}
+
+impl Compression {
+    pub fn new(algorithm: CompressionAlgorithm, threshold: usize) -> Self {
+        Self {
+            algorithm,
+            threshold,
+            level: None,
+        }
+    }
+
+    pub fn level(self, level: i32) -> Self {
+        Self {
+            level: Some(level),
+            ..self
+        }
+    }
+
+    pub fn algorithm(&self) -> CompressionAlgorithm {
+        self.algorithm
+    }
+
+    /// Compresses the payload if it's larger than the threshold.
+    ///
+    /// The compressed payload is encoded with base64 to fit into the envelope.
+    /// Returns `None` if the payload is small enough or compression doesn't make it smaller.
+    pub(crate) fn compress(&self, payload: &str) -> Result<Option<CompressedPayload>, Error> {
+        if payload.len() <= self.threshold {
+            return Ok(None);
+        }
+
+        let compressed = self.algorithm.compress(payload.as_bytes(), self.level)?;
+        let encoded = super::codec::encode_base64(&compressed);
+
+        if encoded.len() >= payload.len() {
+            return Ok(None);
+        }
+
+        Ok(Some(CompressedPayload {
+            content_encoding: self.algorithm.content_encoding(),
+            stats: CompressionStats {
+                original_size: payload.len(),
+                compressed_size: encoded.len(),
+            },
+            payload: encoded,
+        }))
+    }
+}
+
+pub(crate) struct CompressedPayload {
+    pub(crate) content_encoding: &'static str,
+    pub(crate) payload: String,
+    pub(crate) stats: CompressionStats,
+}
+
+/// Sizes of a payload before and after compression.
+#[derive(Debug, Clone, Copy)]
+pub struct CompressionStats {
+    original_size: usize,
+    compressed_size: usize,
+}
+
+impl CompressionStats {
+    pub fn original_size(&self) -> usize {
+        self.original_size
+    }
+
+    pub fn compressed_size(&self) -> usize {
+        self.compressed_size
+    }
+}
+
+/// Decompresses an incoming payload according to its `content_encoding` property.
+pub(crate) fn decompress(content_encoding: &str, payload: &str) -> Result<String, Error> {
+    let algorithm = CompressionAlgorithm::from_content_encoding(content_encoding)?;
+    let compressed = super::codec::decode_base64(payload)?;
+    let decompressed = algorithm.decompress(&compressed)?;
+
+    String::from_utf8(decompressed)
+        .map_err(|e| Error::new(&format!("decompressed payload is not UTF-8, {}", e)))
+}
+
+#[cfg(all(test, any(feature = "zstd", feature = "gzip")))]
+mod tests {
+    use std::io::Read;
+
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::mqtt::{
+        publishable::DumpOptions, testing::deliver, Address, IncomingEvent, IncomingMessage,
+        OutgoingEvent, OutgoingEventProperties, OutgoingShortTermTimingProperties,
+    };
+    use crate::{AccountId, AgentId};
+
+    fn algorithms() -> Vec<CompressionAlgorithm> {
+        vec![
+            #[cfg(feature = "zstd")]
+            CompressionAlgorithm::Zstd,
+            #[cfg(feature = "gzip")]
+            CompressionAlgorithm::Gzip,
+        ]
+    }
+
+    fn payload() -> String {
+        json!({ "text": "hello ".repeat(100) }).to_string()
+    }
+
+    #[test]
+    fn round_trips_payload() {
+        for algorithm in algorithms() {
+            let compressed = Compression::new(algorithm, 16)
+                .compress(&payload())
+                .unwrap()
+                .expect("payload is not compressed");
+
+            assert_eq!(compressed.content_encoding, algorithm.content_encoding());
+            assert!(compressed.stats.compressed_size() < compressed.stats.original_size());
+
+            let decompressed = decompress(compressed.content_encoding, &compressed.payload);
+            assert_eq!(decompressed.unwrap(), payload());
+        }
+    }
+
+    #[test]
+    fn keeps_payload_below_threshold() {
+        for algorithm in algorithms() {
+            let compression = Compression::new(algorithm, payload().len());
+            assert!(compression.compress(&payload()).unwrap().is_none());
+        }
+    }
+
+    #[test]
+    fn rejects_unsupported_content_encoding() {
+        assert!(decompress("br", "").is_err());
+    }
+
+    #[test]
+    fn rejects_payload_exceeding_max_decompressed_size() {
+        let reader = std::io::repeat(0).take(MAX_DECOMPRESSED_SIZE);
+        assert!(read_limited(reader).is_ok());
+
+        let reader = std::io::repeat(0).take(MAX_DECOMPRESSED_SIZE + 1);
+        assert!(read_limited(reader).is_err());
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn rejects_decompression_bomb() {
+        let bomb = vec![b' '; MAX_DECOMPRESSED_SIZE as usize + 1];
+        let compressed = CompressionAlgorithm::Zstd.compress(&bomb, None).unwrap();
+        assert!(CompressionAlgorithm::Zstd.decompress(&compressed).is_err());
+    }
+
+    #[test]
+    fn delivers_compressed_payload() {
+        let account_id = AccountId::new("sender", "svc.example.org");
+        let address = Address::new(AgentId::new("instance01", account_id), "v1");
+        let payload = serde_json::from_str::<Value>(&payload()).unwrap();
+
+        for algorithm in algorithms() {
+            let timing = OutgoingShortTermTimingProperties::new(chrono::Utc::now());
+            let props = OutgoingEventProperties::new("room.update", timing);
+            let mut message = OutgoingEvent::broadcast(payload.clone(), props, "rooms/123/events");
+            message.set_compression(Compression::new(algorithm, 16));
+
+            let dumps = message
+                .into_dumps(&address, &DumpOptions::default())
+                .unwrap();
+            assert!(!dumps[0].payload().contains("hello"));
+
+            let event = match deliver(&dumps[0]) {
+                IncomingMessage::Event(event) => event,
+                _ => panic!("expected an event"),
+            };
+
+            let event = IncomingEvent::convert::<Value>(event).unwrap();
+            assert_eq!(event.payload(), &payload);
+        }
+    }
+}
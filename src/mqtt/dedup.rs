@@ -0,0 +1,332 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, VecDeque};
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+use serde::Deserialize;
+
+use super::{IncomingMessage, IncomingPayload, PublishableMessage};
+use crate::{Addressable, AgentId};
+
+const DEFAULT_WINDOW: u64 = 60;
+const DEFAULT_CAPACITY: usize = 10_000;
+
+fn default_window() -> u64 {
+    DEFAULT_WINDOW
+}
+
+fn default_capacity() -> usize {
+    DEFAULT_CAPACITY
+}
+
+/// Deduplication settings of incoming messages.
+///
+/// With QoS 1 the broker may deliver a message more than once. Messages seen within the window
+/// are recognized as duplicates and never get to the application again. Requests are recognized
+/// by the sender agent and correlation data. Responses published to a request are remembered
+/// and replayed to its duplicates instead of handling the request again. Duplicates of requests
+/// still being handled are dropped. Requests rejected by the agent itself, e.g. with
+/// `429 Too Many Requests`, are forgotten so their retries get checked again. Events are
+/// recognized by the sender agent, tracking id, label, timestamp and payload. Events without
+/// a timestamp are never deduplicated since there's no telling a duplicate from another event
+/// with the same payload.
+///
+/// # Options
+///
+/// * `window` – seconds to remember a message for. Default: 60.
+/// * `capacity` – maximum number of remembered messages. The oldest ones are forgotten
+///   when exceeded. Default: 10 000.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Deduplication {
+    #[serde(default = "default_window")]
+    window: u64,
+    #[serde(default = "default_capacity")]
+    capacity: usize,
+}
+
+impl Default for Deduplication {
+    fn default() -> Self {
+        Self {
+            window: DEFAULT_WINDOW,
+            capacity: DEFAULT_CAPACITY,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum MessageKey {
+    Request(AgentId, String),
+    Event(AgentId, String),
+}
+
+impl MessageKey {
+    fn of(message: &IncomingMessage<IncomingPayload>) -> Option<Self> {
+        match message {
+            IncomingMessage::Request(req) => {
+                let props = req.properties();
+
+                Some(Self::Request(
+                    props.as_agent_id().to_owned(),
+                    props.correlation_data().to_owned(),
+                ))
+            }
+            IncomingMessage::Event(event) => {
+                let props = event.properties();
+                let timestamp = props.short_term_timing().timestamp()?;
+
+                // Tell apart events published within the same millisecond.
+                let mut hasher = DefaultHasher::new();
+                event.payload().hash(&mut hasher);
+
+                // A tracking id is shared by the whole message chain so it's not enough alone.
+                let id = format!(
+                    "{}/{}/{}/{:x}",
+                    props.tracking().tracking_id(),
+                    props.label().unwrap_or_default(),
+                    timestamp.timestamp_millis(),
+                    hasher.finish(),
+                );
+
+                Some(Self::Event(props.as_agent_id().to_owned(), id))
+            }
+            IncomingMessage::Response(_) => None,
+        }
+    }
+}
+
+/// Outcome of checking an incoming message against the ones seen before.
+pub(crate) enum Seen {
+    /// The message is new.
+    New,
+    /// The message is a duplicate to drop.
+    Duplicate,
+    /// The request is a duplicate to replay the responses to.
+    Replay(Vec<PublishableMessage>),
+}
+
+struct Entry {
+    seen_at: Instant,
+    // Responses published to the request. Always empty for events.
+    responses: Vec<PublishableMessage>,
+}
+
+#[derive(Default)]
+struct Window {
+    seen: HashMap<MessageKey, Entry>,
+    queue: VecDeque<(Instant, MessageKey)>,
+}
+
+impl Window {
+    fn evict(&mut self, window: Duration, capacity: usize) {
+        while let Some((seen_at, _)) = self.queue.front() {
+            if seen_at.elapsed() < window && self.queue.len() <= capacity {
+                break;
+            }
+
+            if let Some((seen_at, key)) = self.queue.pop_front() {
+                // The key may have been forgotten and seen again since then.
+                if self.seen.get(&key).map(|entry| entry.seen_at) == Some(seen_at) {
+                    self.seen.remove(&key);
+                }
+            }
+        }
+    }
+}
+
+/// Messages seen within the deduplication window.
+///
+/// It's shared between the agent's event loop checking incoming messages and the agent
+/// remembering published responses.
+#[derive(Clone)]
+pub(crate) struct Deduplicator {
+    config: Deduplication,
+    window: Arc<Mutex<Window>>,
+}
+
+impl Deduplicator {
+    pub(crate) fn new(config: Deduplication) -> Self {
+        Self {
+            config,
+            window: Arc::default(),
+        }
+    }
+
+    /// Remembers the incoming message and tells whether it has been seen before.
+    pub(crate) fn check(&self, message: &IncomingMessage<IncomingPayload>) -> Seen {
+        let key = match MessageKey::of(message) {
+            Some(key) => key,
+            None => return Seen::New,
+        };
+
+        let mut window = self.window.lock().unwrap_or_else(PoisonError::into_inner);
+        window.evict(
+            Duration::from_secs(self.config.window),
+            self.config.capacity,
+        );
+
+        match window.seen.get(&key) {
+            Some(entry) if entry.responses.is_empty() => Seen::Duplicate,
+            Some(entry) => Seen::Replay(entry.responses.to_owned()),
+            None => {
+                let seen_at = Instant::now();
+                let entry = Entry {
+                    seen_at,
+                    responses: vec![],
+                };
+
+                window.seen.insert(key.clone(), entry);
+                window.queue.push_back((seen_at, key));
+                Seen::New
+            }
+        }
+    }
+
+    /// Remembers the response published to the request of the agent with the correlation data.
+    pub(crate) fn record_response(
+        &self,
+        agent_id: &AgentId,
+        correlation_data: &str,
+        dumps: &[PublishableMessage],
+    ) {
+        let key = MessageKey::Request(agent_id.to_owned(), correlation_data.to_owned());
+        let mut window = self.window.lock().unwrap_or_else(PoisonError::into_inner);
+
+        // Forgotten already or not deduplicated in the first place.
+        if let Some(entry) = window.seen.get_mut(&key) {
+            entry.responses.extend_from_slice(dumps);
+        }
+    }
+
+    /// Forgets the request of the agent with the correlation data so its retries are new.
+    pub(crate) fn forget(&self, agent_id: &AgentId, correlation_data: &str) {
+        let key = MessageKey::Request(agent_id.to_owned(), correlation_data.to_owned());
+        let mut window = self.window.lock().unwrap_or_else(PoisonError::into_inner);
+        window.seen.remove(&key);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rumqttc::{Packet, Publish, QoS};
+    use serde_json::{json, Value};
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::mqtt::{testing::request, AgentNotification};
+
+    fn event(payload: &str, timestamp: Option<&str>) -> IncomingMessage<IncomingPayload> {
+        let session_id = format!("{}.{}", Uuid::nil(), Uuid::nil());
+
+        let mut properties = json!({
+            "type": "event",
+            "label": "room.update",
+            "agent_id": "instance01.sender.svc.example.org",
+            "connection_version": "v2",
+            "connection_mode": "service",
+            "broker_timestamp": "1700000000000",
+            "broker_processing_timestamp": "1700000000000",
+            "broker_initial_processing_timestamp": "1700000000000",
+            "tracking_id": format!("{}.{}", Uuid::nil(), session_id),
+            "session_tracking_label": session_id,
+        });
+
+        if let Some(timestamp) = timestamp {
+            properties["timestamp"] = Value::from(timestamp);
+        }
+
+        let envelope = json!({ "payload": payload, "properties": properties });
+        let publish = Publish::new("topic", QoS::AtLeastOnce, envelope.to_string());
+
+        match AgentNotification::from(Packet::Publish(publish)) {
+            AgentNotification::Message(Ok(message), _) => message,
+            _ => panic!("expected a message"),
+        }
+    }
+
+    #[test]
+    fn drops_duplicate_event() {
+        let deduplicator = Deduplicator::new(Deduplication::default());
+        let timestamp = Some("1700000000000");
+
+        assert!(matches!(
+            deduplicator.check(&event("{}", timestamp)),
+            Seen::New
+        ));
+        assert!(matches!(
+            deduplicator.check(&event("{}", timestamp)),
+            Seen::Duplicate
+        ));
+    }
+
+    #[test]
+    fn passes_distinct_events_without_timestamp() {
+        let deduplicator = Deduplicator::new(Deduplication::default());
+
+        assert!(matches!(
+            deduplicator.check(&event("{\"n\":1}", None)),
+            Seen::New
+        ));
+        assert!(matches!(
+            deduplicator.check(&event("{\"n\":2}", None)),
+            Seen::New
+        ));
+        assert!(matches!(
+            deduplicator.check(&event("{\"n\":2}", None)),
+            Seen::New
+        ));
+    }
+
+    #[test]
+    fn passes_distinct_events_within_same_millisecond() {
+        let deduplicator = Deduplicator::new(Deduplication::default());
+        let timestamp = Some("1700000000000");
+
+        assert!(matches!(
+            deduplicator.check(&event("{\"n\":1}", timestamp)),
+            Seen::New
+        ));
+        assert!(matches!(
+            deduplicator.check(&event("{\"n\":2}", timestamp)),
+            Seen::New
+        ));
+    }
+
+    #[test]
+    fn forgets_request() {
+        let deduplicator = Deduplicator::new(Deduplication::default());
+        let message = IncomingMessage::Request(request("room.enter", "corr", json!({})));
+
+        assert!(matches!(deduplicator.check(&message), Seen::New));
+        assert!(matches!(deduplicator.check(&message), Seen::Duplicate));
+
+        if let IncomingMessage::Request(ref req) = message {
+            let props = req.properties();
+            deduplicator.forget(props.as_agent_id(), props.correlation_data());
+        }
+
+        assert!(matches!(deduplicator.check(&message), Seen::New));
+    }
+
+    #[test]
+    fn keeps_request_seen_again_after_forgetting() {
+        let config = Deduplication {
+            window: DEFAULT_WINDOW,
+            capacity: 1,
+        };
+
+        let deduplicator = Deduplicator::new(config);
+        let message = IncomingMessage::Request(request("room.enter", "corr", json!({})));
+        assert!(matches!(deduplicator.check(&message), Seen::New));
+
+        if let IncomingMessage::Request(ref req) = message {
+            let props = req.properties();
+            deduplicator.forget(props.as_agent_id(), props.correlation_data());
+        }
+
+        assert!(matches!(deduplicator.check(&message), Seen::New));
+
+        // Evicting the first sighting mustn't forget the second one.
+        assert!(matches!(deduplicator.check(&message), Seen::Duplicate));
+    }
+}
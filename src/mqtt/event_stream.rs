@@ -0,0 +1,260 @@
+use std::{
+    collections::HashMap,
+    marker::PhantomData,
+    pin::Pin,
+    sync::{Arc, Mutex, PoisonError},
+    task::{Context, Poll},
+};
+
+use futures::Stream;
+use log::{error, warn};
+use serde::de::DeserializeOwned;
+use tokio::sync::mpsc;
+
+use super::{IncomingEvent, IncomingPayload, SubscriptionHandle};
+
+/// Returns whether the `topic` matches the subscription `filter` including `+` and `#`
+/// wildcards. Shared subscription prefix of the filter is ignored.
+pub(crate) fn topic_matches(filter: &str, topic: &str) -> bool {
+    let filter = match filter.strip_prefix("$share/") {
+        Some(rest) => rest.split_once('/').map_or("", |(_group, filter)| filter),
+        None => filter,
+    };
+
+    let mut filter_levels = filter.split('/');
+    let mut topic_levels = topic.split('/');
+
+    loop {
+        match (filter_levels.next(), topic_levels.next()) {
+            (Some("#"), _) => return true,
+            (Some("+"), Some(_)) => (),
+            (Some(filter_level), Some(topic_level)) if filter_level == topic_level => (),
+            (None, None) => return true,
+            _ => return false,
+        }
+    }
+}
+
+struct Registration {
+    filter: String,
+    tx: mpsc::UnboundedSender<IncomingEvent<IncomingPayload>>,
+}
+
+#[derive(Default)]
+struct Registry {
+    next_id: u64,
+    streams: HashMap<u64, Registration>,
+}
+
+/// Event streams of an agent.
+///
+/// It's shared between the agent's event loop and the agent's
+/// [EventStream](struct.EventStream.html) instances.
+#[derive(Clone, Default)]
+pub(crate) struct EventStreams(Arc<Mutex<Registry>>);
+
+impl EventStreams {
+    fn lock(&self) -> std::sync::MutexGuard<'_, Registry> {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub(crate) fn register(&self, filter: &str) -> StreamRegistration {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let mut registry = self.lock();
+        let id = registry.next_id;
+        registry.next_id += 1;
+
+        let registration = Registration {
+            filter: filter.to_owned(),
+            tx,
+        };
+
+        registry.streams.insert(id, registration);
+
+        StreamRegistration {
+            streams: self.clone(),
+            id,
+            rx,
+        }
+    }
+
+    /// Sends the event to all streams with filters matching the `topic`.
+    ///
+    /// Gives the event back if there are no such streams so it could be handled elsewhere.
+    pub(crate) fn dispatch(
+        &self,
+        event: IncomingEvent<IncomingPayload>,
+        topic: &str,
+    ) -> Option<IncomingEvent<IncomingPayload>> {
+        let registry = self.lock();
+
+        let mut matching = registry
+            .streams
+            .values()
+            .filter(|registration| topic_matches(&registration.filter, topic))
+            .peekable();
+
+        if matching.peek().is_none() {
+            return Some(event);
+        }
+
+        for registration in matching {
+            if registration.tx.send(event.clone()).is_err() {
+                warn!(
+                    "Failed to pass event with topic = '{}' to a dropped stream",
+                    topic
+                );
+            }
+        }
+
+        None
+    }
+}
+
+/// Receiving side of a registered stream. Deregisters the stream on drop.
+pub(crate) struct StreamRegistration {
+    streams: EventStreams,
+    id: u64,
+    rx: mpsc::UnboundedReceiver<IncomingEvent<IncomingPayload>>,
+}
+
+impl Drop for StreamRegistration {
+    fn drop(&mut self) {
+        self.streams.lock().streams.remove(&self.id);
+    }
+}
+
+/// A stream of events matching a single subscription.
+///
+/// See [Agent::subscribe_events](struct.Agent.html#method.subscribe_events) for details.
+pub struct EventStream<T> {
+    // Fields are dropped in order so the stream gets deregistered before unsubscribing.
+    registration: StreamRegistration,
+    subscription: SubscriptionHandle,
+    payload_type: PhantomData<fn() -> T>,
+}
+
+impl<T> EventStream<T> {
+    pub(crate) fn new(registration: StreamRegistration, subscription: SubscriptionHandle) -> Self {
+        Self {
+            registration,
+            subscription,
+            payload_type: PhantomData,
+        }
+    }
+
+    /// Returns the subscription topic of the stream.
+    pub fn topic(&self) -> &str {
+        self.subscription.topic()
+    }
+}
+
+impl<T: DeserializeOwned> Stream for EventStream<T> {
+    type Item = IncomingEvent<T>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            match self.registration.rx.poll_recv(cx) {
+                Poll::Ready(Some(event)) => match IncomingEvent::convert::<T>(event) {
+                    Ok(event) => return Poll::Ready(Some(event)),
+                    Err(err) => error!(
+                        "Failed to convert event with topic = '{}': {}",
+                        self.topic(),
+                        err
+                    ),
+                },
+                Poll::Ready(None) => return Poll::Ready(None),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::StreamExt;
+    use serde::Deserialize;
+    use serde_json::json;
+
+    use super::*;
+    use crate::mqtt::{testing::event, Agent};
+
+    const TOPIC: &str = "apps/sender.svc.example.org/api/v1/rooms/123/events";
+
+    #[derive(Debug, Deserialize, PartialEq)]
+    struct RoomEvent {
+        id: u64,
+    }
+
+    #[test]
+    fn matches_topic_with_wildcards() {
+        assert!(topic_matches(TOPIC, TOPIC));
+        assert!(topic_matches(
+            "apps/sender.svc.example.org/api/v1/rooms/+/events",
+            TOPIC
+        ));
+        assert!(topic_matches("apps/sender.svc.example.org/api/v1/#", TOPIC));
+        assert!(topic_matches("#", TOPIC));
+
+        assert!(!topic_matches(
+            "apps/sender.svc.example.org/api/v1/rooms/+",
+            TOPIC
+        ));
+        assert!(!topic_matches(
+            "apps/sender.svc.example.org/api/v1/rooms/123/events/+",
+            TOPIC
+        ));
+        assert!(!topic_matches(
+            "apps/sender.svc.example.org/api/v2/rooms/+/events",
+            TOPIC
+        ));
+    }
+
+    #[test]
+    fn ignores_shared_subscription_group() {
+        let filter = "$share/loadbalancer/apps/sender.svc.example.org/api/v1/rooms/+/events";
+        assert!(topic_matches(filter, TOPIC));
+        assert!(!topic_matches("$share/loadbalancer", TOPIC));
+    }
+
+    #[test]
+    fn gives_back_event_without_matching_streams() {
+        let streams = EventStreams::default();
+        let _registration = streams.register("apps/sender.svc.example.org/api/v1/users/+");
+
+        assert!(streams
+            .dispatch(event("room.update", json!({})), TOPIC)
+            .is_some());
+    }
+
+    #[tokio::test]
+    async fn streams_converted_events() {
+        let (agent, _rx) = Agent::stub();
+        let streams = EventStreams::default();
+
+        let registration = streams.register("apps/sender.svc.example.org/api/v1/rooms/+/events");
+        let subscription = SubscriptionHandle::new(agent, TOPIC);
+        let mut stream = EventStream::<RoomEvent>::new(registration, subscription);
+
+        assert!(streams
+            .dispatch(event("room.update", json!({ "id": "invalid" })), TOPIC)
+            .is_none());
+        assert!(streams
+            .dispatch(event("room.update", json!({ "id": 1 })), TOPIC)
+            .is_none());
+
+        // Events failed to convert are skipped.
+        let event = stream.next().await.unwrap();
+        assert_eq!(event.payload(), &RoomEvent { id: 1 });
+    }
+
+    #[test]
+    fn deregisters_dropped_stream() {
+        let streams = EventStreams::default();
+        drop(streams.register(TOPIC));
+
+        assert!(streams
+            .dispatch(event("room.update", json!({})), TOPIC)
+            .is_some());
+    }
+}
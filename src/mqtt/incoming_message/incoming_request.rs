@@ -1,5 +1,8 @@
+use chrono::{DateTime, Utc};
+
 use super::super::*;
 use crate::mqtt::ExtraTags;
+use crate::serde::ts_milliseconds_string_option;
 use crate::{AccountId, Addressable, AgentId, Authenticable};
 
 /// Properties of an incoming request.
@@ -19,6 +22,28 @@ pub struct IncomingRequestProperties {
     tracking: TrackingProperties,
     #[serde(default, skip_serializing_if = "Option::is_none")]
     local_tracking_label: Option<String>,
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        with = "ts_milliseconds_string_option"
+    )]
+    deadline: Option<DateTime<Utc>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    content_type: Option<String>,
+    #[serde(default, skip_serializing)]
+    content_encoding: Option<String>,
+    #[serde(default, skip_serializing)]
+    payload_format: Option<String>,
+    #[serde(skip)]
+    authorization_time: Option<chrono::Duration>,
+    #[cfg(feature = "tracing")]
+    #[serde(flatten)]
+    trace_context: Option<TraceContext>,
+    #[cfg(feature = "tracing")]
+    #[serde(skip, default = "tracing::Span::none")]
+    span: tracing::Span,
+    #[serde(skip)]
+    received_at: Option<DateTime<Utc>>,
     #[serde(flatten)]
     tags: ExtraTags,
 }
@@ -48,6 +73,17 @@ impl IncomingRequestProperties {
         &self.short_term_timing
     }
 
+    /// Returns [TimingReport](struct.TimingReport.html) of the message chain until the agent
+    /// has received the message.
+    pub fn timing_report(&self) -> TimingReport {
+        let received_at = self.received_at.unwrap_or_else(Utc::now);
+        TimingReport::new(&self.long_term_timing, received_at)
+    }
+
+    pub(crate) fn set_received_at(&mut self, received_at: DateTime<Utc>) {
+        self.received_at = Some(received_at);
+    }
+
     pub fn tracking(&self) -> &TrackingProperties {
         &self.tracking
     }
@@ -56,18 +92,84 @@ impl IncomingRequestProperties {
         &self.local_tracking_label
     }
 
+    /// Returns the time after which the response to the request is of no use to the caller.
+    pub fn deadline(&self) -> Option<DateTime<Utc>> {
+        self.deadline
+    }
+
+    /// Returns the time left until the deadline, zero if it has expired.
+    /// `None` if the request has no deadline.
+    pub fn remaining_time(&self) -> Option<chrono::Duration> {
+        self.deadline
+            .map(|deadline| std::cmp::max(deadline - Utc::now(), chrono::Duration::zero()))
+    }
+
+    /// Returns whether the request has got its deadline expired.
+    pub fn is_expired(&self) -> bool {
+        matches!(self.deadline, Some(deadline) if deadline <= Utc::now())
+    }
+
     pub fn to_connection(&self) -> Connection {
         self.conn.to_connection()
     }
 
+    /// Returns the [TraceContext](struct.TraceContext.html) the sender has published
+    /// the message with.
+    #[cfg(feature = "tracing")]
+    pub fn trace_context(&self) -> Option<&TraceContext> {
+        self.trace_context.as_ref()
+    }
+
+    /// Returns the span opened for the message by the agent.
+    #[cfg(feature = "tracing")]
+    pub fn span(&self) -> &tracing::Span {
+        &self.span
+    }
+
+    #[cfg(feature = "tracing")]
+    pub(crate) fn set_span(&mut self, span: tracing::Span) {
+        self.span = span;
+    }
+
     pub fn tags(&self) -> &ExtraTags {
         &self.tags
     }
 
+    pub fn content_type(&self) -> Option<&str> {
+        self.content_type.as_deref()
+    }
+
+    /// Returns the compression algorithm the payload has been transferred with.
+    /// The payload is decompressed by the agent before it gets to the application.
+    pub fn content_encoding(&self) -> Option<&str> {
+        self.content_encoding.as_deref()
+    }
+
+    pub(crate) fn payload_format(&self) -> Option<&str> {
+        self.payload_format.as_deref()
+    }
+
+    /// Returns the payload [Codec](enum.Codec.html) according to `content_type` property.
+    pub fn codec(&self) -> Result<Codec, Error> {
+        Codec::from_content_type(self.content_type())
+    }
+
     pub fn set_method(&mut self, method: &str) {
         self.tags.set_method(method);
     }
 
+    /// Returns the time the agent has spent on authorizing the request.
+    ///
+    /// It's set only if the request has been authorized by the agent.
+    /// See [Authorization](../authz/struct.Authorization.html) for details.
+    pub fn authorization_time(&self) -> Option<chrono::Duration> {
+        self.authorization_time
+    }
+
+    pub(crate) fn set_authorization_time(&mut self, authorization_time: chrono::Duration) {
+        self.authorization_time = Some(authorization_time);
+    }
+
     /// Builds [OutgoingEventProperties](struct.OutgoingEventProperties.html) based on the
     /// [IncomingRequestProperties](struct.IncomingRequestProperties.html).
     ///
@@ -90,6 +192,7 @@ impl IncomingRequestProperties {
         label: &'static str,
         short_term_timing: OutgoingShortTermTimingProperties,
     ) -> OutgoingEventProperties {
+        let short_term_timing = self.with_authorization_time(short_term_timing);
         let long_term_timing = self.update_long_term_timing(&short_term_timing);
         let mut props = OutgoingEventProperties::new(label, short_term_timing);
 
@@ -100,6 +203,13 @@ impl IncomingRequestProperties {
         if let Some(ref label) = self.local_tracking_label {
             props.set_local_tracking_label(label.to_owned());
         }
+
+        #[cfg(feature = "tracing")]
+        if let Some(trace_context) =
+            trace_context::outgoing(&self.span, self.trace_context.as_ref())
+        {
+            props.set_trace_context(trace_context);
+        }
         props
     }
 
@@ -107,6 +217,7 @@ impl IncomingRequestProperties {
     /// [IncomingRequestProperties](struct.IncomingRequestProperties.html).
     ///
     /// Use it to send a request to another service while handling a request.
+    /// The [deadline](#method.deadline) of the request is carried over.
     ///
     /// # Arguments
     ///
@@ -131,6 +242,7 @@ impl IncomingRequestProperties {
         correlation_data: &str,
         short_term_timing: OutgoingShortTermTimingProperties,
     ) -> OutgoingRequestProperties {
+        let short_term_timing = self.with_authorization_time(short_term_timing);
         let long_term_timing = self.update_long_term_timing(&short_term_timing);
 
         let mut props = OutgoingRequestProperties::new(
@@ -147,6 +259,17 @@ impl IncomingRequestProperties {
         if let Some(ref label) = self.local_tracking_label {
             props.set_local_tracking_label(label.to_owned());
         }
+
+        if let Some(deadline) = self.deadline {
+            props.set_deadline(deadline);
+        }
+
+        #[cfg(feature = "tracing")]
+        if let Some(trace_context) =
+            trace_context::outgoing(&self.span, self.trace_context.as_ref())
+        {
+            props.set_trace_context(trace_context);
+        }
         props
     }
 
@@ -171,6 +294,8 @@ impl IncomingRequestProperties {
         status: ResponseStatus,
         short_term_timing: OutgoingShortTermTimingProperties,
     ) -> OutgoingResponseProperties {
+        let short_term_timing = self.with_authorization_time(short_term_timing);
+
         let mut props = OutgoingResponseProperties::new(
             status,
             &self.correlation_data,
@@ -183,9 +308,30 @@ impl IncomingRequestProperties {
         props.set_response_topic(&self.response_topic);
         props.set_tags(self.tags.clone());
 
+        #[cfg(feature = "tracing")]
+        if let Some(trace_context) =
+            trace_context::outgoing(&self.span, self.trace_context.as_ref())
+        {
+            props.set_trace_context(trace_context);
+        }
+
         props
     }
 
+    /// Adds the time spent on authorizing the request by the agent unless it's already set.
+    fn with_authorization_time(
+        &self,
+        mut short_term_timing: OutgoingShortTermTimingProperties,
+    ) -> OutgoingShortTermTimingProperties {
+        if let Some(authorization_time) = self.authorization_time {
+            if short_term_timing.authorization_time().is_none() {
+                short_term_timing.set_authorization_time(authorization_time);
+            }
+        }
+
+        short_term_timing
+    }
+
     fn update_long_term_timing(
         &self,
         short_term_timing: &OutgoingShortTermTimingProperties,
@@ -269,12 +415,10 @@ impl<String: std::ops::Deref<Target = str>> IncomingRequest<String> {
     where
         T: serde::de::DeserializeOwned,
     {
-        let payload = serde_json::from_str::<T>(message.payload()).map_err(|e| {
-            Error::new(&format!(
-                "error deserializing payload of an envelope, {}",
-                &e
-            ))
-        })?;
+        let payload = message
+            .properties()
+            .codec()?
+            .decode::<T>(message.payload())?;
         Ok(payload)
     }
 
@@ -282,13 +426,64 @@ impl<String: std::ops::Deref<Target = str>> IncomingRequest<String> {
     where
         T: serde::de::DeserializeOwned,
     {
-        let props = message.properties().to_owned();
-        let payload = serde_json::from_str::<T>(message.payload()).map_err(|e| {
-            Error::new(&format!(
-                "error deserializing payload of an envelope, {}",
-                &e
-            ))
-        })?;
-        Ok(IncomingRequest::new(payload, props))
+        let payload = message.properties.codec()?.decode::<T>(&message.payload)?;
+        Ok(IncomingRequest::new(payload, message.properties))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::mqtt::testing::{packet, receive, request};
+
+    fn request_with_deadline(deadline: DateTime<Utc>) -> IncomingRequest<IncomingPayload> {
+        let properties = json!({
+            "type": "request",
+            "method": "room.enter",
+            "correlation_data": "corr",
+            "response_topic": "agents/instance01.sender.svc.example.org/api/v1/in/receiver",
+            "deadline": deadline.timestamp_millis().to_string(),
+        });
+
+        match receive(packet(properties, &json!({}))) {
+            IncomingMessage::Request(req) => req,
+            _ => panic!("expected a request"),
+        }
+    }
+
+    #[test]
+    fn reports_remaining_time() {
+        let req = request("room.enter", "corr", json!({}));
+        assert_eq!(req.properties().remaining_time(), None);
+        assert!(!req.properties().is_expired());
+
+        let req = request_with_deadline(Utc::now() + chrono::Duration::seconds(10));
+        assert!(req.properties().remaining_time().unwrap() > chrono::Duration::seconds(5));
+        assert!(!req.properties().is_expired());
+
+        let req = request_with_deadline(Utc::now() - chrono::Duration::seconds(10));
+        assert_eq!(
+            req.properties().remaining_time(),
+            Some(chrono::Duration::zero())
+        );
+        assert!(req.properties().is_expired());
+    }
+
+    #[test]
+    fn passes_deadline_to_downstream_request() {
+        let deadline = Utc::now() + chrono::Duration::seconds(10);
+        let req = request_with_deadline(deadline);
+
+        let timing = OutgoingShortTermTimingProperties::new(Utc::now());
+        let props = req
+            .properties()
+            .to_request("room.read", "response/topic", "corr", timing);
+
+        assert_eq!(
+            props.deadline().map(|deadline| deadline.timestamp_millis()),
+            Some(deadline.timestamp_millis())
+        );
     }
 }
@@ -19,6 +19,20 @@ pub struct IncomingResponseProperties {
     tracking: TrackingProperties,
     #[serde(default, skip_serializing_if = "Option::is_none")]
     local_tracking_label: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    content_type: Option<String>,
+    #[serde(default, skip_serializing)]
+    content_encoding: Option<String>,
+    #[serde(default, skip_serializing)]
+    payload_format: Option<String>,
+    #[cfg(feature = "tracing")]
+    #[serde(flatten)]
+    trace_context: Option<TraceContext>,
+    #[cfg(feature = "tracing")]
+    #[serde(skip, default = "tracing::Span::none")]
+    span: tracing::Span,
+    #[serde(skip)]
+    received_at: Option<chrono::DateTime<chrono::Utc>>,
     #[serde(flatten)]
     tags: ExtraTags,
 }
@@ -40,6 +54,17 @@ impl IncomingResponseProperties {
         &self.short_term_timing
     }
 
+    /// Returns [TimingReport](struct.TimingReport.html) of the message chain until the agent
+    /// has received the message.
+    pub fn timing_report(&self) -> TimingReport {
+        let received_at = self.received_at.unwrap_or_else(chrono::Utc::now);
+        TimingReport::new(&self.long_term_timing, received_at)
+    }
+
+    pub(crate) fn set_received_at(&mut self, received_at: chrono::DateTime<chrono::Utc>) {
+        self.received_at = Some(received_at);
+    }
+
     pub fn tracking(&self) -> &TrackingProperties {
         &self.tracking
     }
@@ -52,9 +77,46 @@ impl IncomingResponseProperties {
         self.conn.to_connection()
     }
 
+    /// Returns the [TraceContext](struct.TraceContext.html) the sender has published
+    /// the message with.
+    #[cfg(feature = "tracing")]
+    pub fn trace_context(&self) -> Option<&TraceContext> {
+        self.trace_context.as_ref()
+    }
+
+    /// Returns the span opened for the message by the agent.
+    #[cfg(feature = "tracing")]
+    pub fn span(&self) -> &tracing::Span {
+        &self.span
+    }
+
+    #[cfg(feature = "tracing")]
+    pub(crate) fn set_span(&mut self, span: tracing::Span) {
+        self.span = span;
+    }
+
     pub fn tags(&self) -> &ExtraTags {
         &self.tags
     }
+
+    pub fn content_type(&self) -> Option<&str> {
+        self.content_type.as_deref()
+    }
+
+    /// Returns the compression algorithm the payload has been transferred with.
+    /// The payload is decompressed by the agent before it gets to the application.
+    pub fn content_encoding(&self) -> Option<&str> {
+        self.content_encoding.as_deref()
+    }
+
+    pub(crate) fn payload_format(&self) -> Option<&str> {
+        self.payload_format.as_deref()
+    }
+
+    /// Returns the payload [Codec](enum.Codec.html) according to `content_type` property.
+    pub fn codec(&self) -> Result<Codec, Error> {
+        Codec::from_content_type(self.content_type())
+    }
 }
 
 impl Authenticable for IncomingResponseProperties {
@@ -88,12 +150,10 @@ impl<String: std::ops::Deref<Target = str>> IncomingResponse<String> {
     where
         T: serde::de::DeserializeOwned,
     {
-        let payload = serde_json::from_str::<T>(message.payload()).map_err(|e| {
-            Error::new(&format!(
-                "error deserializing payload of an envelope, {}",
-                &e
-            ))
-        })?;
+        let payload = message
+            .properties()
+            .codec()?
+            .decode::<T>(message.payload())?;
         Ok(payload)
     }
 
@@ -101,13 +161,7 @@ impl<String: std::ops::Deref<Target = str>> IncomingResponse<String> {
     where
         T: serde::de::DeserializeOwned,
     {
-        let props = message.properties().to_owned();
-        let payload = serde_json::from_str::<T>(message.payload()).map_err(|e| {
-            Error::new(&format!(
-                "error deserializing payload of an envelope, {}",
-                &e
-            ))
-        })?;
-        Ok(IncomingResponse::new(payload, props))
+        let payload = message.properties.codec()?.decode::<T>(&message.payload)?;
+        Ok(IncomingResponse::new(payload, message.properties))
     }
 }
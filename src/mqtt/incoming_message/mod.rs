@@ -1,4 +1,7 @@
+use serde_json::value::RawValue;
+
 use super::*;
+use crate::Error;
 
 /// A generic received message.
 #[derive(Debug, Clone)]
@@ -8,6 +11,27 @@ pub enum IncomingMessage<T> {
     Response(IncomingResponse<T>),
 }
 
+impl<T> IncomingMessage<T> {
+    /// Returns [TimingReport](struct.TimingReport.html) of the message chain until the agent
+    /// has received the message.
+    pub fn timing_report(&self) -> TimingReport {
+        match self {
+            IncomingMessage::Event(event) => event.properties().timing_report(),
+            IncomingMessage::Request(req) => req.properties().timing_report(),
+            IncomingMessage::Response(resp) => resp.properties().timing_report(),
+        }
+    }
+
+    /// Records the time the agent has received the message at.
+    pub(crate) fn set_received_at(&mut self, received_at: chrono::DateTime<chrono::Utc>) {
+        match self {
+            IncomingMessage::Event(event) => event.properties_mut().set_received_at(received_at),
+            IncomingMessage::Request(req) => req.properties_mut().set_received_at(received_at),
+            IncomingMessage::Response(resp) => resp.properties_mut().set_received_at(received_at),
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct IncomingMessageContent<T, P>
 where
@@ -45,10 +69,27 @@ where
     }
 }
 
+impl<P> IncomingMessageContent<IncomingPayload, P>
+where
+    P: Addressable + serde::Serialize + Clone,
+{
+    /// Returns a view of the message with the JSON payload as a raw value.
+    ///
+    /// The payload is borrowed from the message and isn't parsed beyond validation.
+    pub fn to_raw(&self) -> Result<IncomingMessageContent<&RawValue, P>, Error> {
+        Ok(IncomingMessageContent::new(
+            self.payload.raw()?,
+            self.properties.clone(),
+        ))
+    }
+}
+
 pub use incoming_event::*;
 pub use incoming_request::*;
 pub use incoming_response::*;
+pub use payload::IncomingPayload;
 
 mod incoming_event;
 mod incoming_request;
 mod incoming_response;
+mod payload;
@@ -0,0 +1,186 @@
+use std::convert::TryFrom;
+use std::fmt;
+use std::ops::Deref;
+
+use bytes::Bytes;
+use bytestring::ByteString;
+use serde::{Deserialize, Serialize, Serializer};
+use serde_json::value::RawValue;
+
+use crate::Error;
+
+/// Payload of an incoming message.
+///
+/// Whenever possible it's a view into the buffer of the received packet so no copying
+/// happens on receipt. Cloning is cheap since the buffer is reference counted.
+/// An owned copy is made only if the payload had to be unescaped or decompressed.
+///
+/// It dereferences to `str` so it may be used wherever the payload string is expected.
+#[derive(Clone, Default, PartialEq, Eq, Hash)]
+pub struct IncomingPayload(ByteString);
+
+impl IncomingPayload {
+    /// Makes a payload of `payload` string slice within the packet `buffer` without copying.
+    pub(crate) fn from_buffer(buffer: &Bytes, payload: &str) -> Result<Self, Error> {
+        ByteString::try_from(buffer.slice_ref(payload.as_bytes()))
+            .map(Self)
+            .map_err(|e| Error::new(&format!("invalid UTF-8 in payload, {}", e)))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// Returns the underlying buffer.
+    pub fn as_bytes(&self) -> &Bytes {
+        self.0.as_bytes()
+    }
+
+    pub fn into_bytes(self) -> Bytes {
+        self.0.into_bytes()
+    }
+
+    /// Returns the JSON payload as a raw value without parsing it.
+    pub fn raw(&self) -> Result<&RawValue, Error> {
+        serde_json::from_str::<&RawValue>(self.as_str())
+            .map_err(|e| Error::new(&format!("error parsing payload, {}", e)))
+    }
+
+    /// Deserializes the JSON payload borrowing strings from the buffer where possible.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// #[derive(Deserialize)]
+    /// struct Message<'a> {
+    ///     room_id: &'a str,
+    ///     #[serde(borrow)]
+    ///     data: &'a RawValue,
+    /// }
+    ///
+    /// let message = event.payload().deserialize::<Message>()?;
+    /// ```
+    pub fn deserialize<'a, T: Deserialize<'a>>(&'a self) -> Result<T, Error> {
+        serde_json::from_str::<T>(self.as_str())
+            .map_err(|e| Error::new(&format!("error deserializing payload, {}", e)))
+    }
+}
+
+impl Deref for IncomingPayload {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl AsRef<str> for IncomingPayload {
+    fn as_ref(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl From<String> for IncomingPayload {
+    fn from(value: String) -> Self {
+        Self(ByteString::from(value))
+    }
+}
+
+impl From<&str> for IncomingPayload {
+    fn from(value: &str) -> Self {
+        Self(ByteString::from(value))
+    }
+}
+
+impl PartialEq<str> for IncomingPayload {
+    fn eq(&self, other: &str) -> bool {
+        self.as_str() == other
+    }
+}
+
+impl PartialEq<&str> for IncomingPayload {
+    fn eq(&self, other: &&str) -> bool {
+        self.as_str() == *other
+    }
+}
+
+impl fmt::Debug for IncomingPayload {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self.as_str(), f)
+    }
+}
+
+impl fmt::Display for IncomingPayload {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self.as_str(), f)
+    }
+}
+
+impl Serialize for IncomingPayload {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::mqtt::{
+        testing::{packet, receive},
+        IncomingMessage,
+    };
+
+    /// Returns the received payload and whether it points into the packet buffer.
+    fn receive_payload(payload: &Value) -> (IncomingPayload, bool) {
+        let publish = packet(json!({ "type": "event", "label": "room.update" }), payload);
+        let buffer = publish.payload.as_ptr_range();
+
+        let payload = match receive(publish) {
+            IncomingMessage::Event(event) => event.extract_payload(),
+            _ => panic!("expected an event"),
+        };
+
+        let is_borrowed = buffer.contains(&payload.as_bytes().as_ptr());
+        (payload, is_borrowed)
+    }
+
+    #[test]
+    fn keeps_payload_in_packet_buffer() {
+        let (payload, is_borrowed) = receive_payload(&json!(12345));
+        assert_eq!(payload, "12345");
+        assert!(is_borrowed);
+    }
+
+    #[test]
+    fn copies_unescaped_payload() {
+        let (payload, is_borrowed) = receive_payload(&json!({ "id": 1 }));
+        assert_eq!(payload, r#"{"id":1}"#);
+        assert!(!is_borrowed);
+    }
+
+    #[test]
+    fn deserializes_borrowing_from_payload() {
+        #[derive(Deserialize)]
+        struct Message<'a> {
+            room_id: &'a str,
+            #[serde(borrow)]
+            data: &'a RawValue,
+        }
+
+        let payload = IncomingPayload::from(r#"{"room_id":"123","data":{"text":"hello"}}"#);
+        let message = payload.deserialize::<Message>().unwrap();
+
+        assert_eq!(message.room_id, "123");
+        assert_eq!(message.data.get(), r#"{"text":"hello"}"#);
+        assert_eq!(payload.raw().unwrap().get(), payload.as_str());
+    }
+
+    #[test]
+    fn rejects_invalid_json() {
+        let payload = IncomingPayload::from("{");
+        assert!(payload.raw().is_err());
+        assert!(payload.deserialize::<Value>().is_err());
+    }
+}
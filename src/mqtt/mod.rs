@@ -154,6 +154,16 @@ pub use rumqttc::QoS;
 
 pub use agent::Address;
 pub use agent::Agent;
+pub use chunking::Chunking;
+pub use codec::Codec;
+pub use compression::{Compression, CompressionAlgorithm, CompressionStats};
+pub use dedup::Deduplication;
+pub(crate) use event_stream::topic_matches;
+pub use event_stream::EventStream;
+pub use rate_limit::{Limit, RateLimit};
+pub use subscription_handle::SubscriptionHandle;
+#[cfg(feature = "tracing")]
+pub use trace_context::TraceContext;
 
 pub use incoming_message::*;
 pub use outgoing_message::*;
@@ -168,8 +178,19 @@ pub mod agent;
 pub mod compat;
 pub mod publishable;
 
+mod chunking;
+mod codec;
+mod compression;
+mod dedup;
+mod event_stream;
 mod incoming_message;
 mod outgoing_message;
+mod rate_limit;
+mod subscription_handle;
+#[cfg(test)]
+pub(crate) mod testing;
 
 mod timing_properties;
+#[cfg(feature = "tracing")]
+mod trace_context;
 mod tracking_properties;
@@ -50,6 +50,75 @@ impl<T: serde::Serialize> OutgoingMessage<T> {
             OutgoingMessage::Request(v) => v.properties().tags(),
         }
     }
+
+    /// Sets the payload codec unless it has already been set for the message.
+    pub(crate) fn set_default_codec(&mut self, codec: Codec) {
+        match self {
+            OutgoingMessage::Event(v) if v.properties.codec().is_none() => {
+                v.properties.set_codec(codec);
+            }
+            OutgoingMessage::Response(v) if v.properties.codec().is_none() => {
+                v.properties.set_codec(codec);
+            }
+            OutgoingMessage::Request(v) if v.properties.codec().is_none() => {
+                v.properties.set_codec(codec);
+            }
+            _ => (),
+        }
+    }
+
+    /// Sets payload compression settings to apply on dumping the message.
+    pub(crate) fn set_compression(&mut self, compression: Compression) {
+        match self {
+            OutgoingMessage::Event(v) => {
+                v.properties.set_compression(compression);
+            }
+            OutgoingMessage::Response(v) => {
+                v.properties.set_compression(compression);
+            }
+            OutgoingMessage::Request(v) => {
+                v.properties.set_compression(compression);
+            }
+        }
+    }
+
+    /// Sets the trace context of the current span unless it has already been set for the message.
+    #[cfg(feature = "tracing")]
+    pub(crate) fn set_default_trace_context(&mut self) {
+        let trace_context = match TraceContext::current() {
+            Some(trace_context) => trace_context,
+            None => return,
+        };
+
+        match self {
+            OutgoingMessage::Event(v) if v.properties.trace_context().is_none() => {
+                v.properties.set_trace_context(trace_context);
+            }
+            OutgoingMessage::Response(v) if v.properties.trace_context().is_none() => {
+                v.properties.set_trace_context(trace_context);
+            }
+            OutgoingMessage::Request(v) if v.properties.trace_context().is_none() => {
+                v.properties.set_trace_context(trace_context);
+            }
+            _ => (),
+        }
+    }
+
+    /// Makes the message embed its JSON payload into the envelope as is instead of
+    /// serializing it to a string.
+    pub(crate) fn set_raw_payload(&mut self) {
+        match self {
+            OutgoingMessage::Event(v) => {
+                v.properties.set_raw_payload(true);
+            }
+            OutgoingMessage::Response(v) => {
+                v.properties.set_raw_payload(true);
+            }
+            OutgoingMessage::Request(v) => {
+                v.properties.set_raw_payload(true);
+            }
+        }
+    }
 }
 
 impl<T: serde::Serialize> Publishable for OutgoingMessage<T> {
@@ -3,6 +3,8 @@ use serde::Serialize;
 use crate::AgentId;
 
 use super::*;
+#[cfg(feature = "encryption")]
+use crate::encryption::Recipient;
 use crate::Authenticable;
 
 /// Properties of an outgoing event.
@@ -19,6 +21,22 @@ pub struct OutgoingEventProperties {
     tracking: Option<TrackingProperties>,
     #[serde(default, skip_serializing_if = "Option::is_none")]
     local_tracking_label: Option<String>,
+    #[serde(rename = "content_type", skip_serializing_if = "Option::is_none")]
+    codec: Option<Codec>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    content_encoding: Option<&'static str>,
+    #[serde(skip)]
+    compression: Option<Compression>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    payload_format: Option<&'static str>,
+    #[serde(skip)]
+    is_raw_payload: bool,
+    #[cfg(feature = "encryption")]
+    #[serde(skip)]
+    recipient: Option<Recipient>,
+    #[cfg(feature = "tracing")]
+    #[serde(flatten)]
+    trace_context: Option<TraceContext>,
     #[serde(skip)]
     tags: ExtraTags,
 }
@@ -54,6 +72,15 @@ impl OutgoingEventProperties {
             tracking: None,
             agent_id: None,
             local_tracking_label: None,
+            codec: None,
+            content_encoding: None,
+            compression: None,
+            payload_format: None,
+            is_raw_payload: false,
+            #[cfg(feature = "encryption")]
+            recipient: None,
+            #[cfg(feature = "tracing")]
+            trace_context: None,
             tags: Default::default(),
         }
     }
@@ -78,6 +105,10 @@ impl OutgoingEventProperties {
         self
     }
 
+    pub fn label(&self) -> &'static str {
+        self.label
+    }
+
     pub fn tags(&self) -> &ExtraTags {
         &self.tags
     }
@@ -86,6 +117,74 @@ impl OutgoingEventProperties {
         self.tags = tags;
         self
     }
+
+    pub fn codec(&self) -> Option<Codec> {
+        self.codec
+    }
+
+    /// Sets the payload [Codec](enum.Codec.html).
+    /// The agent's [default one](struct.AgentBuilder.html#method.codec) is used if not set.
+    pub fn set_codec(&mut self, codec: Codec) -> &mut Self {
+        self.codec = Some(codec);
+        self
+    }
+
+    pub(crate) fn compression(&self) -> Option<&Compression> {
+        self.compression.as_ref()
+    }
+
+    pub(crate) fn set_compression(&mut self, compression: Compression) -> &mut Self {
+        self.compression = Some(compression);
+        self
+    }
+
+    pub(crate) fn set_content_encoding(
+        &mut self,
+        content_encoding: Option<&'static str>,
+    ) -> &mut Self {
+        self.content_encoding = content_encoding;
+        self
+    }
+
+    pub(crate) fn is_raw_payload(&self) -> bool {
+        self.is_raw_payload
+    }
+
+    pub(crate) fn set_raw_payload(&mut self, is_raw_payload: bool) -> &mut Self {
+        self.is_raw_payload = is_raw_payload;
+        self
+    }
+
+    pub(crate) fn set_payload_format(&mut self, payload_format: Option<&'static str>) -> &mut Self {
+        self.payload_format = payload_format;
+        self
+    }
+
+    /// Encrypts the payload for the `recipient` so only it could read the payload.
+    /// See [Encryption](../encryption/struct.Encryption.html) for details.
+    #[cfg(feature = "encryption")]
+    pub fn encrypt_for(&mut self, recipient: Recipient) -> &mut Self {
+        self.recipient = Some(recipient);
+        self
+    }
+
+    #[cfg(feature = "encryption")]
+    pub(crate) fn recipient(&self) -> Option<&Recipient> {
+        self.recipient.as_ref()
+    }
+
+    /// Sets the [TraceContext](struct.TraceContext.html) to publish the message with.
+    /// The one of the current span is used if not set.
+    #[cfg(feature = "tracing")]
+    pub fn set_trace_context(&mut self, trace_context: TraceContext) -> &mut Self {
+        self.trace_context = Some(trace_context);
+        self
+    }
+
+    #[cfg(feature = "tracing")]
+    pub fn trace_context(&self) -> Option<&TraceContext> {
+        self.trace_context.as_ref()
+    }
 }
 
 pub type OutgoingEvent<T> = OutgoingMessageContent<T, OutgoingEventProperties>;
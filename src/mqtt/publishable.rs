@@ -26,6 +26,7 @@ pub struct PublishableDump {
     qos: QoS,
     payload: String,
     tags: ExtraTags,
+    compression: Option<CompressionStats>,
 }
 
 impl PublishableDump {
@@ -44,8 +45,14 @@ impl PublishableDump {
     pub fn tags(&self) -> &ExtraTags {
         &self.tags
     }
+
+    /// Returns payload compression stats if the payload has been compressed.
+    pub fn compression(&self) -> Option<CompressionStats> {
+        self.compression
+    }
 }
 
+#[derive(Clone)]
 pub enum PublishableMessage {
     Event(PublishableDump),
     Request(PublishableDump),
@@ -84,38 +91,210 @@ impl PublishableMessage {
             Self::Response(v) => v.tags(),
         }
     }
+
+    pub fn compression(&self) -> Option<CompressionStats> {
+        match self {
+            Self::Event(v) => v.compression(),
+            Self::Request(v) => v.compression(),
+            Self::Response(v) => v.compression(),
+        }
+    }
 }
 
 pub trait IntoPublishableMessage {
     /// Serializes the object into dump that can be directly published.
+    ///
+    /// The agent's defaults such as codec, compression, encryption, signing and chunking
+    /// are not applied to the dump.
     fn into_dump(self: Box<Self>, publisher: &Address) -> Result<PublishableMessage, Error>;
+
+    /// Serializes the object into dumps to be published by the agent.
+    ///
+    /// Falls back to [into_dump](#tymethod.into_dump) by default.
+    fn into_dumps_for(self: Box<Self>, agent: &Agent) -> Result<Vec<PublishableMessage>, Error> {
+        Ok(vec![self.into_dump(agent.address())?])
+    }
 }
 
 impl<T: serde::Serialize> IntoPublishableMessage for OutgoingMessage<T> {
+    fn into_dumps_for(self: Box<Self>, agent: &Agent) -> Result<Vec<PublishableMessage>, Error> {
+        agent.dump(*self)
+    }
+
     fn into_dump(self: Box<Self>, publisher: &Address) -> Result<PublishableMessage, Error> {
+        let mut dumps = self.into_dumps(publisher, &DumpOptions::default())?;
+        dumps
+            .pop()
+            .ok_or_else(|| Error::new("error serializing an envelope"))
+    }
+}
+
+/// Agent-wide settings applied to envelopes of published messages.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct DumpOptions {
+    pub(crate) chunking: Option<Chunking>,
+    #[cfg(feature = "encryption")]
+    pub(crate) encryption: Option<std::sync::Arc<crate::encryption::Encryption>>,
+    #[cfg(feature = "signing")]
+    pub(crate) signing: Option<std::sync::Arc<crate::signing::Signing>>,
+}
+
+impl<T: serde::Serialize> OutgoingMessage<T> {
+    /// Serializes the message into dumps encrypting, signing and splitting it into chunks
+    /// according to the `options`.
+    pub(crate) fn into_dumps(
+        self,
+        publisher: &Address,
+        options: &DumpOptions,
+    ) -> Result<Vec<PublishableMessage>, Error> {
         use crate::mqtt::compat::{IntoEnvelope, OutgoingEnvelopeProperties};
 
         let topic = self.destination_topic(publisher)?;
         let qos = self.qos();
         let tags = self.tags().to_owned();
 
-        let envelope = &self.into_envelope()?;
-        let payload = serde_json::to_string(envelope)
-            .map_err(|e| Error::new(&format!("error serializing an envelope, {}", &e)))?;
+        #[cfg_attr(
+            not(any(feature = "encryption", feature = "signing")),
+            allow(unused_mut)
+        )]
+        let mut envelope = self.into_envelope()?;
+
+        // Sign the ciphertext so the signature could be verified without decrypting.
+        #[cfg(feature = "encryption")]
+        if envelope.properties.recipient().is_some() {
+            match options.encryption {
+                Some(ref encryption) => encryption.encrypt(&mut envelope)?,
+                None => return Err(Error::new("payload encryption is not configured")),
+            }
+        }
+
+        #[cfg(feature = "signing")]
+        if let Some(ref signing) = options.signing {
+            use crate::Authenticable;
+            signing.sign(&mut envelope, publisher.id().as_account_id())?;
+        }
+
+        let envelope = &envelope;
+
+        let chunks = match options.chunking.as_ref() {
+            Some(chunking) => chunking.split(envelope)?,
+            None => None,
+        };
 
-        let dump = PublishableDump {
-            topic,
-            qos,
-            payload,
-            tags,
+        let payloads = match chunks {
+            Some(chunks) => chunks,
+            None => vec![serde_json::to_string(envelope)
+                .map_err(|e| Error::new(&format!("error serializing an envelope, {}", &e)))?],
         };
 
-        let message = match envelope.properties {
-            OutgoingEnvelopeProperties::Event(_) => PublishableMessage::Event(dump),
-            OutgoingEnvelopeProperties::Request(_) => PublishableMessage::Request(dump),
-            OutgoingEnvelopeProperties::Response(_) => PublishableMessage::Response(dump),
+        let dumps = payloads
+            .into_iter()
+            .enumerate()
+            .map(|(index, payload)| {
+                let dump = PublishableDump {
+                    topic: topic.clone(),
+                    qos,
+                    payload,
+                    tags: tags.clone(),
+                    // Count the compression once per message rather than for each chunk.
+                    compression: envelope.compression().filter(|_| index == 0),
+                };
+
+                match envelope.properties {
+                    OutgoingEnvelopeProperties::Event(_) => PublishableMessage::Event(dump),
+                    OutgoingEnvelopeProperties::Request(_) => PublishableMessage::Request(dump),
+                    OutgoingEnvelopeProperties::Response(_) => PublishableMessage::Response(dump),
+                }
+            })
+            .collect();
+
+        Ok(dumps)
+    }
+}
+
+#[cfg(all(test, feature = "encryption", feature = "signing"))]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::Arc;
+
+    use chrono::Utc;
+    use rumqttc::{Packet, Publish};
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::encryption::{Encryption, Recipient};
+    use crate::mqtt::chunking::Reassembler;
+    use crate::mqtt::testing::broker_properties;
+    use crate::signing::{Signing, SigningKey};
+    use crate::{AccountId, AgentId};
+
+    #[test]
+    fn delivers_encrypted_signed_raw_payload_in_chunks() {
+        let account_id = AccountId::new("sender", "svc.example.org");
+        let address = Address::new(AgentId::new("instance01", account_id.clone()), "v1");
+
+        let key = SigningKey::Hmac(b"secret".to_vec());
+        let mut keys = HashMap::new();
+        keys.insert(account_id.clone(), key.verifying_key());
+
+        let signing = Signing::new()
+            .sign_with(key)
+            .verify_with(keys)
+            .require_signatures();
+
+        let encryption = Encryption::new().group_key("rooms", [7; 32]);
+        let chunking = serde_json::from_value::<Chunking>(json!({ "chunk_size": 16 })).unwrap();
+
+        let options = DumpOptions {
+            chunking: Some(chunking.clone()),
+            encryption: Some(Arc::new(encryption.clone())),
+            signing: Some(Arc::new(signing.clone())),
         };
 
-        Ok(message)
+        let timing = OutgoingShortTermTimingProperties::new(Utc::now());
+        let mut props = OutgoingEventProperties::new("room.update", timing);
+        props.encrypt_for(Recipient::Group("rooms".to_owned()));
+
+        let payload = json!({ "room_id": "123", "text": "hello" });
+        let mut message = OutgoingEvent::broadcast(payload.clone(), props, "rooms/123/events");
+        message.set_raw_payload();
+
+        let dumps = message.into_dumps(&address, &options).unwrap();
+        assert!(dumps.len() > 1);
+
+        let mut reassembler = Reassembler::new(chunking);
+        let mut reassembled = None;
+
+        // The broker tells the sender to the receiver in each chunk.
+        for dump in dumps {
+            let mut envelope = serde_json::from_str::<Value>(dump.payload()).unwrap();
+            let properties = envelope["properties"].as_object_mut().unwrap();
+
+            if let Value::Object(broker_properties) = broker_properties(&address.id().to_string()) {
+                properties.extend(broker_properties);
+            }
+
+            let publish = Publish::new(dump.topic(), dump.qos(), envelope.to_string());
+            reassembled = reassembler.push(publish).unwrap();
+        }
+
+        let publish = reassembled.expect("incomplete transfer");
+
+        signing.verify(&publish.payload).unwrap();
+        let publish = encryption.decrypt(publish, &account_id).unwrap();
+
+        let envelope = serde_json::from_slice::<Value>(&publish.payload).unwrap();
+        assert_eq!(envelope["properties"]["payload_format"], "raw");
+        assert_eq!(envelope["payload"], payload);
+
+        match AgentNotification::from(Packet::Publish(publish)) {
+            AgentNotification::Message(Ok(IncomingMessage::Event(event)), _) => {
+                assert_eq!(event.payload().deserialize::<Value>().unwrap(), payload);
+            }
+            AgentNotification::Message(result, _) => {
+                panic!("expected an event, got {:?}", result.err())
+            }
+            _ => panic!("expected a message"),
+        }
     }
 }
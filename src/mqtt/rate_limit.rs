@@ -0,0 +1,282 @@
+use std::collections::HashMap;
+use std::time::Instant;
+
+use serde::Deserialize;
+
+use crate::AccountId;
+
+/// Maximum number of buckets kept before the full and the least recently used ones get dropped.
+const MAX_BUCKETS: usize = 10_000;
+/// Number of the least recently used buckets dropped at once when there're no full ones.
+const EVICTION_BATCH: usize = MAX_BUCKETS / 10;
+
+/// Token bucket limit.
+///
+/// # Options
+///
+/// * `rate` – requests per second the bucket gets refilled with.
+/// * `burst` – bucket capacity, i.e. the number of requests allowed at once.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+pub struct Limit {
+    rate: f64,
+    burst: u32,
+}
+
+impl Limit {
+    pub fn new(rate: f64, burst: u32) -> Self {
+        Self { rate, burst }
+    }
+
+    pub fn rate(&self) -> f64 {
+        self.rate
+    }
+
+    pub fn burst(&self) -> u32 {
+        self.burst
+    }
+}
+
+/// Rate limiting settings of incoming requests.
+///
+/// Each account gets its own token bucket. Requests exceeding the limit get answered with
+/// `429 Too Many Requests` and never reach the application.
+///
+/// # Options
+///
+/// * `rate`, `burst` – default [Limit](struct.Limit.html) of an account.
+/// * `per_method` – whether to limit each method of an account separately. Default: `false`.
+/// * `accounts` – limits overriding the default one by account id.
+///
+/// # Example
+///
+/// ```toml
+/// [mqtt.rate_limit]
+/// rate = 10.0
+/// burst = 20
+/// per_method = true
+///
+/// [mqtt.rate_limit.accounts."conference.svc.example.org"]
+/// rate = 1000.0
+/// burst = 2000
+/// ```
+#[derive(Debug, Clone, Deserialize)]
+pub struct RateLimit {
+    #[serde(flatten)]
+    limit: Limit,
+    #[serde(default)]
+    per_method: bool,
+    #[serde(default)]
+    accounts: HashMap<AccountId, Limit>,
+}
+
+impl RateLimit {
+    pub fn new(limit: Limit) -> Self {
+        Self {
+            limit,
+            per_method: false,
+            accounts: HashMap::new(),
+        }
+    }
+
+    /// Limits each method of an account separately.
+    pub fn per_method(self) -> Self {
+        Self {
+            per_method: true,
+            ..self
+        }
+    }
+
+    /// Overrides the default limit for the account.
+    pub fn account(mut self, account_id: AccountId, limit: Limit) -> Self {
+        self.accounts.insert(account_id, limit);
+        self
+    }
+
+    /// Returns the limit of the account.
+    pub fn limit(&self, account_id: &AccountId) -> Limit {
+        self.accounts.get(account_id).copied().unwrap_or(self.limit)
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    updated_at: Instant,
+    // Number of the check the bucket has been used by last time.
+    used_at: u64,
+}
+
+impl Bucket {
+    fn refill(&mut self, limit: Limit) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.updated_at).as_secs_f64();
+
+        self.tokens = (self.tokens + elapsed * limit.rate).min(f64::from(limit.burst));
+        self.updated_at = now;
+    }
+}
+
+/// Token buckets of accounts sending requests.
+pub(crate) struct RateLimiter {
+    config: RateLimit,
+    buckets: HashMap<(AccountId, Option<String>), Bucket>,
+    checks: u64,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(config: RateLimit) -> Self {
+        Self {
+            config,
+            buckets: HashMap::new(),
+            checks: 0,
+        }
+    }
+
+    /// Takes a token from the bucket of the account and method.
+    ///
+    /// Returns `false` if the bucket is empty, i.e. the request exceeds the limit.
+    pub(crate) fn check(&mut self, account_id: &AccountId, method: &str) -> bool {
+        let limit = self.config.limit(account_id);
+        let method = Some(method.to_owned()).filter(|_| self.config.per_method);
+        let key = (account_id.to_owned(), method);
+
+        if !self.buckets.contains_key(&key) && self.buckets.len() >= MAX_BUCKETS {
+            self.evict();
+        }
+
+        self.checks += 1;
+
+        let bucket = self.buckets.entry(key).or_insert_with(|| Bucket {
+            tokens: f64::from(limit.burst),
+            updated_at: Instant::now(),
+            used_at: 0,
+        });
+
+        bucket.refill(limit);
+        bucket.used_at = self.checks;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Drops the buckets that have been refilled since they're the same as new ones.
+    /// If all of them are partly drained, drops the least recently used ones.
+    fn evict(&mut self) {
+        let config = &self.config;
+
+        self.buckets.retain(|(account_id, _), bucket| {
+            let limit = config.limit(account_id);
+            bucket.refill(limit);
+            bucket.tokens < f64::from(limit.burst)
+        });
+
+        if self.buckets.len() < MAX_BUCKETS {
+            return;
+        }
+
+        let mut used_at = self
+            .buckets
+            .values()
+            .map(|bucket| bucket.used_at)
+            .collect::<Vec<_>>();
+
+        let (_, threshold, _) = used_at.select_nth_unstable(EVICTION_BATCH - 1);
+        let threshold = *threshold;
+
+        self.buckets.retain(|_, bucket| bucket.used_at > threshold);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn account(index: usize) -> AccountId {
+        AccountId::new(&format!("account{}", index), "svc.example.org")
+    }
+
+    #[test]
+    fn rejects_requests_exceeding_burst() {
+        let mut limiter = RateLimiter::new(RateLimit::new(Limit::new(0.0, 2)));
+
+        assert!(limiter.check(&account(0), "ping"));
+        assert!(limiter.check(&account(0), "pong"));
+        assert!(!limiter.check(&account(0), "ping"));
+        assert!(limiter.check(&account(1), "ping"));
+    }
+
+    #[test]
+    fn refills_bucket_over_time() {
+        let mut limiter = RateLimiter::new(RateLimit::new(Limit::new(100.0, 1)));
+
+        assert!(limiter.check(&account(0), "ping"));
+        assert!(!limiter.check(&account(0), "ping"));
+
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        assert!(limiter.check(&account(0), "ping"));
+    }
+
+    #[test]
+    fn limits_methods_separately() {
+        let config = RateLimit::new(Limit::new(0.0, 1)).per_method();
+        let mut limiter = RateLimiter::new(config);
+
+        assert!(limiter.check(&account(0), "ping"));
+        assert!(limiter.check(&account(0), "pong"));
+        assert!(!limiter.check(&account(0), "ping"));
+    }
+
+    #[test]
+    fn overrides_limit_of_account() {
+        let config = RateLimit::new(Limit::new(0.0, 1)).account(account(1), Limit::new(0.0, 2));
+        let mut limiter = RateLimiter::new(config);
+
+        assert!(limiter.check(&account(0), "ping"));
+        assert!(!limiter.check(&account(0), "ping"));
+        assert!(limiter.check(&account(1), "ping"));
+        assert!(limiter.check(&account(1), "ping"));
+        assert!(!limiter.check(&account(1), "ping"));
+    }
+
+    #[test]
+    fn parses_config() {
+        let config = serde_json::from_value::<RateLimit>(serde_json::json!({
+            "rate": 10.0,
+            "burst": 20,
+            "per_method": true,
+            "accounts": {
+                "conference.svc.example.org": { "rate": 1000.0, "burst": 2000 },
+            },
+        }))
+        .unwrap();
+
+        let conference = AccountId::new("conference", "svc.example.org");
+        assert!(config.per_method);
+        assert_eq!(config.limit(&account(0)), Limit::new(10.0, 20));
+        assert_eq!(config.limit(&conference), Limit::new(1000.0, 2000));
+    }
+
+    #[test]
+    fn evicts_least_recently_used_drained_buckets() {
+        let mut limiter = RateLimiter::new(RateLimit::new(Limit::new(0.0, 2)));
+
+        for index in 0..MAX_BUCKETS {
+            assert!(limiter.check(&account(index), "ping"));
+        }
+
+        // Keep the oldest bucket in use.
+        assert!(limiter.check(&account(0), "ping"));
+        assert!(limiter.check(&account(MAX_BUCKETS), "ping"));
+
+        assert_eq!(limiter.buckets.len(), MAX_BUCKETS - EVICTION_BATCH + 1);
+        assert!(limiter.buckets.contains_key(&(account(0), None)));
+        assert!(!limiter.buckets.contains_key(&(account(1), None)));
+        assert!(limiter
+            .buckets
+            .contains_key(&(account(MAX_BUCKETS - 1), None)));
+        assert!(!limiter.check(&account(0), "ping"));
+    }
+}
@@ -0,0 +1,169 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+};
+
+use log::error;
+
+use super::Agent;
+
+#[derive(Default)]
+struct TopicSubscription {
+    handles: usize,
+    is_detached: bool,
+}
+
+/// Numbers of live [SubscriptionHandle](struct.SubscriptionHandle.html) instances per topic.
+///
+/// The topic includes the shared group prefix so subscriptions of different groups
+/// are counted separately. Topics with detached handles stay subscribed when the other
+/// handles get dropped.
+#[derive(Clone, Default)]
+pub(crate) struct SubscriptionCounter(Arc<Mutex<HashMap<String, TopicSubscription>>>);
+
+impl SubscriptionCounter {
+    fn lock(&self) -> MutexGuard<'_, HashMap<String, TopicSubscription>> {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn increment(&self, topic: &str) {
+        self.lock().entry(topic.to_owned()).or_default().handles += 1;
+    }
+
+    /// Forgets the topic unless it has live handles.
+    ///
+    /// Returns the number of live handles otherwise.
+    pub(crate) fn reset(&self, topic: &str) -> Result<(), usize> {
+        let mut counter_lock = self.lock();
+
+        match counter_lock.get(topic) {
+            Some(subscription) if subscription.handles > 0 => Err(subscription.handles),
+            _ => {
+                counter_lock.remove(topic);
+                Ok(())
+            }
+        }
+    }
+
+    /// Forgets the handle keeping the topic subscribed.
+    fn detach(&self, topic: &str) {
+        if let Some(subscription) = self.lock().get_mut(topic) {
+            subscription.handles = subscription.handles.saturating_sub(1);
+            subscription.is_detached = true;
+        }
+    }
+
+    /// Returns whether it was the last handle of the topic and the topic has to be unsubscribed.
+    fn decrement(&self, topic: &str) -> bool {
+        let mut counter_lock = self.lock();
+
+        let subscription = match counter_lock.get_mut(topic) {
+            Some(subscription) => subscription,
+            None => return false,
+        };
+
+        subscription.handles = subscription.handles.saturating_sub(1);
+
+        if subscription.handles > 0 || subscription.is_detached {
+            return false;
+        }
+
+        counter_lock.remove(topic);
+        true
+    }
+}
+
+/// A subscription of an agent.
+///
+/// Subscriptions to the same topic are reference-counted by the agent so the topic gets
+/// unsubscribed only when the last handle of it is dropped.
+/// Call [detach](#method.detach) to keep the subscription for the agent's lifetime.
+///
+/// See [Agent::subscribe](struct.Agent.html#method.subscribe) for details.
+#[must_use = "dropping the handle unsubscribes from the topic"]
+pub struct SubscriptionHandle {
+    agent: Agent,
+    topic: String,
+    is_detached: bool,
+}
+
+impl SubscriptionHandle {
+    pub(crate) fn new(agent: Agent, topic: &str) -> Self {
+        agent.subscription_counter().increment(topic);
+
+        Self {
+            agent,
+            topic: topic.to_owned(),
+            is_detached: false,
+        }
+    }
+
+    /// Returns the subscription topic including the shared group prefix if any.
+    pub fn topic(&self) -> &str {
+        &self.topic
+    }
+
+    /// Drops the handle without unsubscribing.
+    ///
+    /// The topic stays subscribed even after the other handles of it get dropped until explicit
+    /// [Agent::unsubscribe](struct.Agent.html#method.unsubscribe) call.
+    pub fn detach(mut self) {
+        self.agent.subscription_counter().detach(&self.topic);
+        self.is_detached = true;
+    }
+}
+
+impl Drop for SubscriptionHandle {
+    fn drop(&mut self) {
+        if self.is_detached || !self.agent.subscription_counter().decrement(&self.topic) {
+            return;
+        }
+
+        if let Err(err) = self.agent.unsubscribe_topic(&self.topic) {
+            error!(
+                "Failed to unsubscribe from topic = '{}' on dropping subscription handle: {}",
+                self.topic, err
+            );
+        }
+    }
+}
+
+impl std::fmt::Debug for SubscriptionHandle {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        fmt.debug_struct("SubscriptionHandle")
+            .field("topic", &self.topic)
+            .field("is_detached", &self.is_detached)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TOPIC: &str = "agents/a.svc.example.org/api/v1/in/b.svc.example.org";
+
+    #[test]
+    fn unsubscribes_on_last_handle() {
+        let counter = SubscriptionCounter::default();
+        counter.increment(TOPIC);
+        counter.increment(TOPIC);
+
+        assert_eq!(counter.reset(TOPIC), Err(2));
+        assert!(!counter.decrement(TOPIC));
+        assert!(counter.decrement(TOPIC));
+        assert_eq!(counter.reset(TOPIC), Ok(()));
+    }
+
+    #[test]
+    fn keeps_detached_topic_subscribed() {
+        let counter = SubscriptionCounter::default();
+        counter.increment(TOPIC);
+        counter.detach(TOPIC);
+        counter.increment(TOPIC);
+
+        assert!(!counter.decrement(TOPIC));
+        assert_eq!(counter.reset(TOPIC), Ok(()));
+        assert!(!counter.decrement(TOPIC));
+    }
+}
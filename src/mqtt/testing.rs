@@ -0,0 +1,133 @@
+//! Helpers for unit tests of the agent and the dispatcher.
+
+use async_channel::Receiver;
+use rumqttc::{Packet, Publish, QoS, Request};
+use serde_json::{json, Value};
+use uuid::Uuid;
+
+use super::*;
+
+/// Agent the test messages come from.
+pub(crate) const SENDER: &str = "instance01.sender.svc.example.org";
+
+/// Returns envelope properties the broker adds to a message from the `agent_id`.
+pub(crate) fn broker_properties(agent_id: &str) -> Value {
+    let session_id = format!("{}.{}", Uuid::nil(), Uuid::nil());
+
+    json!({
+        "agent_id": agent_id,
+        "broker_agent_id": "alpha.mqtt-gateway.svc.example.org",
+        "connection_version": "v2",
+        "connection_mode": "service",
+        "broker_timestamp": "1700000000000",
+        "broker_processing_timestamp": "1700000000000",
+        "broker_initial_processing_timestamp": "1700000000000",
+        "tracking_id": format!("{}.{}", Uuid::nil(), session_id),
+        "session_tracking_label": session_id,
+    })
+}
+
+/// Builds a packet delivered by the broker with the `properties` merged over the broker's ones.
+pub(crate) fn packet(properties: Value, payload: &Value) -> Publish {
+    let mut merged = broker_properties(SENDER);
+
+    if let (Some(merged), Value::Object(properties)) = (merged.as_object_mut(), properties) {
+        merged.extend(properties);
+    }
+
+    let envelope = json!({ "payload": payload.to_string(), "properties": merged });
+    Publish::new("topic", QoS::AtLeastOnce, envelope.to_string())
+}
+
+/// Parses the packet the same way the agent does on receiving it.
+pub(crate) fn receive(publish: Publish) -> IncomingMessage<IncomingPayload> {
+    match AgentNotification::from(Packet::Publish(publish)) {
+        AgentNotification::Message(Ok(message), _) => message,
+        AgentNotification::Message(Err(err), _) => panic!("failed to parse message: {}", err),
+        _ => panic!("expected a message"),
+    }
+}
+
+/// Delivers a message published by an agent adding the broker's properties to its envelope.
+pub(crate) fn deliver(dump: &PublishableMessage) -> IncomingMessage<IncomingPayload> {
+    let mut envelope = serde_json::from_str::<Value>(dump.payload()).unwrap();
+
+    if let (Some(properties), Value::Object(broker_properties)) = (
+        envelope["properties"].as_object_mut(),
+        broker_properties(SENDER),
+    ) {
+        for (key, value) in broker_properties {
+            properties.entry(key).or_insert(value);
+        }
+    }
+
+    receive(Publish::new(dump.topic(), dump.qos(), envelope.to_string()))
+}
+
+pub(crate) fn event(label: &str, payload: Value) -> IncomingEvent<IncomingPayload> {
+    let properties = json!({ "type": "event", "label": label });
+
+    match receive(packet(properties, &payload)) {
+        IncomingMessage::Event(event) => event,
+        _ => panic!("expected an event"),
+    }
+}
+
+pub(crate) fn request(
+    method: &str,
+    correlation_data: &str,
+    payload: Value,
+) -> IncomingRequest<IncomingPayload> {
+    let properties = json!({
+        "type": "request",
+        "method": method,
+        "correlation_data": correlation_data,
+        "response_topic": format!("agents/{}/api/v1/in/receiver.svc.example.org", SENDER),
+    });
+
+    match receive(packet(properties, &payload)) {
+        IncomingMessage::Request(req) => req,
+        _ => panic!("expected a request"),
+    }
+}
+
+pub(crate) fn response(
+    status: u16,
+    correlation_data: &str,
+    payload: Value,
+) -> IncomingResponse<IncomingPayload> {
+    let properties = json!({
+        "type": "response",
+        "status": status.to_string(),
+        "correlation_data": correlation_data,
+    });
+
+    match receive(packet(properties, &payload)) {
+        IncomingMessage::Response(resp) => resp,
+        _ => panic!("expected a response"),
+    }
+}
+
+/// Returns the topics and the envelopes of the messages published by the agent so far.
+pub(crate) fn published(rx: &Receiver<Request>) -> Vec<(String, Value)> {
+    let mut messages = Vec::new();
+
+    while let Ok(request) = rx.try_recv() {
+        if let Request::Publish(publish) = request {
+            let envelope = serde_json::from_slice::<Value>(&publish.payload).unwrap();
+            messages.push((publish.topic, envelope));
+        }
+    }
+
+    messages
+}
+
+/// Waits for the next message published by the agent.
+pub(crate) async fn next_published(rx: &Receiver<Request>) -> (String, Value) {
+    loop {
+        if let Request::Publish(publish) = rx.recv().await.unwrap() {
+            let envelope = serde_json::from_slice::<Value>(&publish.payload).unwrap();
+            return (publish.topic, envelope);
+        }
+    }
+}
@@ -1,5 +1,5 @@
 use chrono::{DateTime, Duration, Utc};
-use serde::{Deserialize, Serialize};
+use serde::{ser::SerializeMap, Deserialize, Serialize, Serializer};
 
 use crate::serde::{
     duration_milliseconds_string_option, ts_milliseconds_string, ts_milliseconds_string_option,
@@ -54,6 +54,40 @@ pub struct LongTermTimingProperties {
 }
 
 impl LongTermTimingProperties {
+    /// Returns the difference between the client's clock and the server's one as reported
+    /// by the client starting the chain.
+    pub fn local_initial_timediff(&self) -> Option<Duration> {
+        self.local_initial_timediff
+    }
+
+    /// Returns the client's time of starting the chain.
+    pub fn initial_timestamp(&self) -> Option<DateTime<Utc>> {
+        self.initial_timestamp
+    }
+
+    /// Returns the time the broker has received the message.
+    pub fn broker_timestamp(&self) -> DateTime<Utc> {
+        self.broker_timestamp
+    }
+
+    /// Returns the time the broker has processed the message.
+    pub fn broker_processing_timestamp(&self) -> DateTime<Utc> {
+        self.broker_processing_timestamp
+    }
+
+    /// Returns the time the broker has processed the first message of the chain.
+    pub fn broker_initial_processing_timestamp(&self) -> DateTime<Utc> {
+        self.broker_initial_processing_timestamp
+    }
+
+    pub fn cumulative_authorization_time(&self) -> Option<Duration> {
+        self.cumulative_authorization_time
+    }
+
+    pub fn cumulative_processing_time(&self) -> Option<Duration> {
+        self.cumulative_processing_time
+    }
+
     /// Updates cumulative values with the given
     /// [OutgoingShortTermTimingProperties](struct.OutgoingShortTermTimingProperties.html) values.
     ///
@@ -224,6 +258,10 @@ impl OutgoingShortTermTimingProperties {
         self.authorization_time = Some(authorization_time);
         self
     }
+
+    pub(crate) fn authorization_time(&self) -> Option<Duration> {
+        self.authorization_time
+    }
 }
 
 pub type ShortTermTimingProperties = OutgoingShortTermTimingProperties;
@@ -251,3 +289,248 @@ pub struct IncomingShortTermTimingProperties {
     )]
     authorization_time: Option<Duration>,
 }
+
+impl IncomingShortTermTimingProperties {
+    /// Returns the sender's timestamp of the message.
+    pub fn timestamp(&self) -> Option<DateTime<Utc>> {
+        self.timestamp
+    }
+
+    /// Returns the time the sender has spent on processing before publishing the message.
+    pub fn processing_time(&self) -> Option<Duration> {
+        self.processing_time
+    }
+
+    /// Returns the time the sender has spent on authorization before publishing the message.
+    pub fn authorization_time(&self) -> Option<Duration> {
+        self.authorization_time
+    }
+}
+
+/// Latency breakdown of a message chain.
+///
+/// Tells where the time has gone from the start of the chain until the incoming message
+/// has been received. Durations are measured with the broker's clock except for the clock skew.
+///
+/// * `end_to_end` – time since the broker has processed the first message of the chain.
+/// * `broker` – time the broker has spent on the incoming message.
+/// * `processing` – cumulative processing time of the agents in the chain.
+/// * `authorization` – cumulative authorization time of the agents in the chain.
+/// * `transit` – the rest of the time: network, queues and the broker on the previous hops.
+/// * `clock_skew` – estimated difference between the clock of the client starting the chain
+///   and the broker's one. It's reported by the client or estimated by the client's timestamp
+///   of starting the chain which makes it include the first hop latency.
+///
+/// Serializes to a map of milliseconds to be logged or exported as metrics.
+///
+/// # Example
+///
+/// ```
+/// let report = request.properties().timing_report();
+///
+/// info!(
+///     "Request handled, end to end = {} ms, processing = {} ms",
+///     report.end_to_end().num_milliseconds(),
+///     report.processing().num_milliseconds(),
+/// );
+///
+/// for (name, value) in report.fields() {
+///     metrics.record(name, value);
+/// }
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TimingReport {
+    end_to_end: Duration,
+    broker: Duration,
+    transit: Duration,
+    processing: Duration,
+    authorization: Duration,
+    clock_skew: Option<Duration>,
+}
+
+impl TimingReport {
+    /// Builds [TimingReport](struct.TimingReport.html) of a message received at `received_at`.
+    ///
+    /// # Arguments
+    ///
+    /// * `timing` – [LongTermTimingProperties](struct.LongTermTimingProperties.html)
+    ///   of the message.
+    /// * `received_at` – UTC timestamp of receiving the message.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let report = TimingReport::new(request.properties().long_term_timing(), Utc::now());
+    /// ```
+    pub fn new(timing: &LongTermTimingProperties, received_at: DateTime<Utc>) -> Self {
+        let zero = Duration::zero();
+        let end_to_end = std::cmp::max(
+            received_at - timing.broker_initial_processing_timestamp,
+            zero,
+        );
+        let broker = std::cmp::max(
+            timing.broker_processing_timestamp - timing.broker_timestamp,
+            zero,
+        );
+        let processing = timing.cumulative_processing_time.unwrap_or(zero);
+        let authorization = timing.cumulative_authorization_time.unwrap_or(zero);
+        let transit = std::cmp::max(end_to_end - broker - processing - authorization, zero);
+
+        let clock_skew = timing.local_initial_timediff.or_else(|| {
+            timing
+                .initial_timestamp
+                .map(|initial| initial - timing.broker_initial_processing_timestamp)
+        });
+
+        Self {
+            end_to_end,
+            broker,
+            transit,
+            processing,
+            authorization,
+            clock_skew,
+        }
+    }
+
+    pub fn end_to_end(&self) -> Duration {
+        self.end_to_end
+    }
+
+    pub fn broker(&self) -> Duration {
+        self.broker
+    }
+
+    pub fn transit(&self) -> Duration {
+        self.transit
+    }
+
+    pub fn processing(&self) -> Duration {
+        self.processing
+    }
+
+    pub fn authorization(&self) -> Duration {
+        self.authorization
+    }
+
+    pub fn clock_skew(&self) -> Option<Duration> {
+        self.clock_skew
+    }
+
+    /// Returns the durations in milliseconds by their names. The clock skew is omitted
+    /// if unknown.
+    pub fn fields(&self) -> Vec<(&'static str, i64)> {
+        let mut fields = vec![
+            ("end_to_end", self.end_to_end.num_milliseconds()),
+            ("broker", self.broker.num_milliseconds()),
+            ("transit", self.transit.num_milliseconds()),
+            ("processing", self.processing.num_milliseconds()),
+            ("authorization", self.authorization.num_milliseconds()),
+        ];
+
+        if let Some(clock_skew) = self.clock_skew {
+            fields.push(("clock_skew", clock_skew.num_milliseconds()));
+        }
+
+        fields
+    }
+}
+
+impl Serialize for TimingReport {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let fields = self.fields();
+        let mut map = serializer.serialize_map(Some(fields.len()))?;
+
+        for (name, value) in fields {
+            map.serialize_entry(name, &value)?;
+        }
+
+        map.end()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+    use serde_json::{json, Value};
+
+    use super::*;
+
+    const START: i64 = 1_700_000_000_000;
+
+    /// Timing of a message the broker has received 700 ms after the start of the chain
+    /// and processed in 20 ms.
+    fn timing(extra: Value) -> LongTermTimingProperties {
+        let mut timing = json!({
+            "broker_initial_processing_timestamp": START.to_string(),
+            "broker_timestamp": (START + 700).to_string(),
+            "broker_processing_timestamp": (START + 720).to_string(),
+            "cumulative_processing_time": "300",
+            "cumulative_authorization_time": "50",
+        });
+
+        if let (Some(timing), Value::Object(extra)) = (timing.as_object_mut(), extra) {
+            timing.extend(extra);
+        }
+
+        serde_json::from_value(timing).unwrap()
+    }
+
+    fn at(millis: i64) -> DateTime<Utc> {
+        Utc.timestamp_millis_opt(START + millis).unwrap()
+    }
+
+    #[test]
+    fn breaks_down_latency() {
+        let report = TimingReport::new(&timing(json!({})), at(800));
+
+        assert_eq!(report.end_to_end(), Duration::milliseconds(800));
+        assert_eq!(report.broker(), Duration::milliseconds(20));
+        assert_eq!(report.processing(), Duration::milliseconds(300));
+        assert_eq!(report.authorization(), Duration::milliseconds(50));
+        assert_eq!(report.transit(), Duration::milliseconds(430));
+        assert_eq!(report.clock_skew(), None);
+
+        assert_eq!(
+            serde_json::to_value(report).unwrap(),
+            json!({
+                "end_to_end": 800,
+                "broker": 20,
+                "transit": 430,
+                "processing": 300,
+                "authorization": 50,
+            })
+        );
+    }
+
+    #[test]
+    fn clamps_negative_durations() {
+        // The receiver's clock is behind the broker's one.
+        let report = TimingReport::new(&timing(json!({})), at(-100));
+        assert_eq!(report.end_to_end(), Duration::zero());
+        assert_eq!(report.transit(), Duration::zero());
+
+        let timing = timing(json!({ "broker_processing_timestamp": (START + 600).to_string() }));
+        let report = TimingReport::new(&timing, at(800));
+        assert_eq!(report.broker(), Duration::zero());
+    }
+
+    #[test]
+    fn estimates_clock_skew() {
+        let reported = timing(json!({
+            "local_initial_timediff": "-150",
+            "initial_timestamp": (START + 200).to_string(),
+        }));
+
+        let report = TimingReport::new(&reported, at(800));
+        assert_eq!(report.clock_skew(), Some(Duration::milliseconds(-150)));
+
+        // Falls back to the client's timestamp of starting the chain.
+        let estimated = timing(json!({ "initial_timestamp": (START + 200).to_string() }));
+        let report = TimingReport::new(&estimated, at(800));
+        assert_eq!(report.clock_skew(), Some(Duration::milliseconds(200)));
+        assert_eq!(report.fields().last(), Some(&("clock_skew", 200)));
+    }
+}
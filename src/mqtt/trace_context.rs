@@ -0,0 +1,303 @@
+use std::str::FromStr;
+
+use opentelemetry::trace::{SpanContext, SpanId, TraceContextExt, TraceFlags, TraceId, TraceState};
+use serde::{Deserialize, Serialize};
+use tracing::Span;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+use super::{IncomingMessage, IncomingPayload};
+use crate::Error;
+
+const SUPPORTED_VERSION: &str = "00";
+
+/// [W3C trace context](https://www.w3.org/TR/trace-context/) of a message.
+///
+/// It's carried in `traceparent` and `tracestate` envelope properties so MQTT hops show up
+/// in the same traces as HTTP services.
+///
+/// A span gets opened for each incoming message as a child of the sender's span. It's available
+/// with `span` method of the message properties. Messages built with
+/// [to_request](struct.IncomingRequestProperties.html#method.to_request),
+/// [to_response](struct.IncomingRequestProperties.html#method.to_response),
+/// [to_event](struct.IncomingRequestProperties.html#method.to_event) and similar methods
+/// carry on the trace as children of that span. Other messages get the context of the current
+/// span on [publishing](struct.Agent.html#method.publish) unless it's set explicitly.
+///
+/// Spans get exported only if the application has installed the
+/// [tracing-opentelemetry](https://docs.rs/tracing-opentelemetry) layer.
+/// Otherwise the incoming trace context is passed through as is.
+///
+/// # Example
+///
+/// ```
+/// let span = request.properties().span().clone();
+///
+/// async move {
+///     let response = handle(request).await;
+///     agent.publish(response)
+/// }
+/// .instrument(span)
+/// .await?;
+/// ```
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct TraceContext {
+    traceparent: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    tracestate: Option<String>,
+}
+
+impl TraceContext {
+    /// Builds [TraceContext](struct.TraceContext.html) of `traceparent` and `tracestate`
+    /// header values.
+    pub fn new(traceparent: &str, tracestate: Option<&str>) -> Result<Self, Error> {
+        let trace_context = Self {
+            traceparent: traceparent.to_owned(),
+            tracestate: tracestate.map(|value| value.to_owned()),
+        };
+
+        trace_context.span_context()?;
+        Ok(trace_context)
+    }
+
+    /// Returns the trace context of the span or `None` if it's not being traced.
+    pub fn from_span(span: &Span) -> Option<Self> {
+        let context = span.context();
+        let span_context = context.span().span_context().to_owned();
+
+        if !span_context.is_valid() {
+            return None;
+        }
+
+        let traceparent = format!(
+            "{}-{:032x}-{:016x}-{:02x}",
+            SUPPORTED_VERSION,
+            span_context.trace_id(),
+            span_context.span_id(),
+            span_context.trace_flags().to_u8(),
+        );
+
+        let tracestate = Some(span_context.trace_state().header()).filter(|s| !s.is_empty());
+
+        Some(Self {
+            traceparent,
+            tracestate,
+        })
+    }
+
+    /// Returns the trace context of the current span or `None` if it's not being traced.
+    pub fn current() -> Option<Self> {
+        Self::from_span(&Span::current())
+    }
+
+    pub fn traceparent(&self) -> &str {
+        &self.traceparent
+    }
+
+    pub fn tracestate(&self) -> Option<&str> {
+        self.tracestate.as_deref()
+    }
+
+    /// Parses `traceparent` and `tracestate` into the remote span context.
+    fn span_context(&self) -> Result<SpanContext, Error> {
+        let malformed = |detail: &str| {
+            Error::new(&format!(
+                "malformed traceparent = '{}', {}",
+                self.traceparent, detail
+            ))
+        };
+
+        let parts = self.traceparent.split('-').collect::<Vec<&str>>();
+
+        let (trace_id, span_id, flags) = match parts[..] {
+            [SUPPORTED_VERSION, trace_id, span_id, flags]
+                if trace_id.len() == 32 && span_id.len() == 16 && flags.len() == 2 =>
+            {
+                (trace_id, span_id, flags)
+            }
+            _ => {
+                return Err(malformed(
+                    "expected 00-<trace-id>-<parent-id>-<trace-flags>",
+                ))
+            }
+        };
+
+        let trace_id = TraceId::from_hex(trace_id).map_err(|e| malformed(&e.to_string()))?;
+        let span_id = SpanId::from_hex(span_id).map_err(|e| malformed(&e.to_string()))?;
+        let flags = u8::from_str_radix(flags, 16).map_err(|e| malformed(&e.to_string()))?;
+
+        let trace_state = match self.tracestate {
+            Some(ref tracestate) => TraceState::from_str(tracestate).map_err(|e| {
+                Error::new(&format!("malformed tracestate = '{}', {}", tracestate, e))
+            })?,
+            None => TraceState::default(),
+        };
+
+        let span_context =
+            SpanContext::new(trace_id, span_id, TraceFlags::new(flags), true, trace_state);
+
+        if span_context.is_valid() {
+            Ok(span_context)
+        } else {
+            Err(malformed("trace-id and parent-id must not be zeros"))
+        }
+    }
+}
+
+/// Returns the trace context to carry on to the messages built of an incoming one.
+///
+/// It's the context of the message's span if it's being traced. Otherwise the incoming context
+/// is passed through as is.
+pub(crate) fn outgoing(span: &Span, incoming: Option<&TraceContext>) -> Option<TraceContext> {
+    TraceContext::from_span(span).or_else(|| incoming.cloned())
+}
+
+/// Opens a span for the incoming message as a child of the sender's one.
+pub(crate) fn open_span(message: &mut IncomingMessage<IncomingPayload>) {
+    let (span, trace_context) = match message {
+        IncomingMessage::Request(req) => {
+            let props = req.properties();
+
+            let span = tracing::info_span!(
+                "mqtt.request",
+                otel.kind = "server",
+                method = props.method(),
+                tracking_id = %props.tracking().tracking_id(),
+            );
+
+            (span, props.trace_context().cloned())
+        }
+        IncomingMessage::Response(resp) => {
+            let props = resp.properties();
+
+            let span = tracing::info_span!(
+                "mqtt.response",
+                otel.kind = "consumer",
+                status = props.status().as_u16(),
+                tracking_id = %props.tracking().tracking_id(),
+            );
+
+            (span, props.trace_context().cloned())
+        }
+        IncomingMessage::Event(event) => {
+            let props = event.properties();
+
+            let span = tracing::info_span!(
+                "mqtt.event",
+                otel.kind = "consumer",
+                label = props.label().unwrap_or_default(),
+                tracking_id = %props.tracking().tracking_id(),
+            );
+
+            (span, props.trace_context().cloned())
+        }
+    };
+
+    if let Some(trace_context) = trace_context {
+        match trace_context.span_context() {
+            Ok(span_context) => {
+                let parent = opentelemetry::Context::new().with_remote_span_context(span_context);
+                span.set_parent(parent);
+            }
+            Err(err) => log::warn!("Ignoring incoming trace context: {}", err),
+        }
+    }
+
+    match message {
+        IncomingMessage::Request(req) => req.properties_mut().set_span(span),
+        IncomingMessage::Response(resp) => resp.properties_mut().set_span(span),
+        IncomingMessage::Event(event) => event.properties_mut().set_span(span),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+    use serde_json::json;
+
+    use super::*;
+    use crate::mqtt::{
+        testing::{packet, receive},
+        OutgoingShortTermTimingProperties,
+    };
+
+    const TRACEPARENT: &str = "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01";
+
+    #[test]
+    fn parses_traceparent() {
+        let trace_context = TraceContext::new(TRACEPARENT, Some("vendor=value")).unwrap();
+        let span_context = trace_context.span_context().unwrap();
+
+        assert_eq!(
+            format!("{:032x}", span_context.trace_id()),
+            "0af7651916cd43dd8448eb211c80319c"
+        );
+        assert_eq!(
+            format!("{:016x}", span_context.span_id()),
+            "b7ad6b7169203331"
+        );
+        assert!(span_context.is_sampled());
+        assert!(span_context.is_remote());
+        assert_eq!(span_context.trace_state().get("vendor"), Some("value"));
+    }
+
+    #[test]
+    fn rejects_malformed_trace_context() {
+        let malformed = [
+            "01-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01",
+            "00-0af7651916cd43dd8448eb211c80319c-b7ad6b71692033-01",
+            "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331",
+            "00-0af7651916cd43dd8448eb211c80319z-b7ad6b7169203331-01",
+            "00-00000000000000000000000000000000-b7ad6b7169203331-01",
+            "00-0af7651916cd43dd8448eb211c80319c-0000000000000000-01",
+        ];
+
+        for traceparent in malformed.iter() {
+            assert!(
+                TraceContext::new(traceparent, None).is_err(),
+                "{}",
+                traceparent
+            );
+        }
+
+        assert!(TraceContext::new(TRACEPARENT, Some("Vendor=value")).is_err());
+    }
+
+    #[test]
+    fn passes_trace_context_through_untraced_message() {
+        let properties = json!({
+            "type": "request",
+            "method": "room.enter",
+            "correlation_data": "corr",
+            "response_topic": "agents/instance01.sender.svc.example.org/api/v1/in/receiver",
+            "traceparent": TRACEPARENT,
+            "tracestate": "vendor=value",
+        });
+
+        let mut message = receive(packet(properties, &json!({})));
+        open_span(&mut message);
+
+        let req = match message {
+            IncomingMessage::Request(req) => req,
+            _ => panic!("expected a request"),
+        };
+
+        let expected = TraceContext::new(TRACEPARENT, Some("vendor=value")).unwrap();
+        assert_eq!(req.properties().trace_context(), Some(&expected));
+
+        // There's no OpenTelemetry layer so the context is passed on as is.
+        let timing = OutgoingShortTermTimingProperties::new(Utc::now());
+        let props = req
+            .properties()
+            .to_request("room.read", "response/topic", "corr", timing);
+
+        let props = serde_json::to_value(&props).unwrap();
+        assert_eq!(props["traceparent"], TRACEPARENT);
+        assert_eq!(props["tracestate"], "vendor=value");
+    }
+
+    #[test]
+    fn has_no_context_of_untraced_span() {
+        assert_eq!(TraceContext::current(), None);
+        assert_eq!(outgoing(&Span::none(), None), None);
+    }
+}
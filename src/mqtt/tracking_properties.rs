@@ -7,12 +7,29 @@ use uuid::Uuid;
 use crate::{serde::session_ids_list, Error};
 
 /// Tracking session ID.
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub struct SessionId {
     agent_session_label: Uuid,
     broker_session_label: Uuid,
 }
 
+impl SessionId {
+    pub fn new(agent_session_label: Uuid, broker_session_label: Uuid) -> Self {
+        Self {
+            agent_session_label,
+            broker_session_label,
+        }
+    }
+
+    pub fn agent_session_label(&self) -> Uuid {
+        self.agent_session_label
+    }
+
+    pub fn broker_session_label(&self) -> Uuid {
+        self.broker_session_label
+    }
+}
+
 impl FromStr for SessionId {
     type Err = Error;
 
@@ -59,12 +76,32 @@ impl fmt::Display for SessionId {
 }
 
 /// Message chain ID.
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub struct TrackingId {
     label: Uuid,
     session_id: SessionId,
 }
 
+impl TrackingId {
+    pub fn new(label: Uuid, session_id: SessionId) -> Self {
+        Self { label, session_id }
+    }
+
+    /// Starts a new chain in the session with a random label.
+    pub fn start(session_id: SessionId) -> Self {
+        Self::new(Uuid::new_v4(), session_id)
+    }
+
+    pub fn label(&self) -> Uuid {
+        self.label
+    }
+
+    /// Returns the session the chain has been started in.
+    pub fn session_id(&self) -> &SessionId {
+        &self.session_id
+    }
+}
+
 impl FromStr for TrackingId {
     type Err = Error;
 
@@ -110,9 +147,123 @@ impl fmt::Display for TrackingId {
 ///
 /// Proxying is performed by [to_response](type.IncomingRequest.html#method.to_response) and
 /// the like methods.
+///
+/// A chain started by the agent itself, e.g. on a timer, gets its tracking properties from
+/// [Agent::start_tracking](struct.Agent.html#method.start_tracking).
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct TrackingProperties {
     tracking_id: TrackingId,
     #[serde(with = "session_ids_list")]
     session_tracking_label: Vec<SessionId>,
 }
+
+impl TrackingProperties {
+    pub fn new(tracking_id: TrackingId, session_tracking_label: Vec<SessionId>) -> Self {
+        Self {
+            tracking_id,
+            session_tracking_label,
+        }
+    }
+
+    /// Starts a new chain in the session.
+    ///
+    /// # Arguments
+    ///
+    /// * `session_id` – [SessionId](struct.SessionId.html) of the agent starting the chain.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let mut props = OutgoingEventProperties::new("room.tick", short_term_timing);
+    /// props.set_tracking(TrackingProperties::start(session_id));
+    /// ```
+    pub fn start(session_id: SessionId) -> Self {
+        Self::new(TrackingId::start(session_id.clone()), vec![session_id])
+    }
+
+    pub fn tracking_id(&self) -> &TrackingId {
+        &self.tracking_id
+    }
+
+    /// Returns the sessions the chain has passed through starting from the root one.
+    pub fn session_tracking_label(&self) -> &[SessionId] {
+        &self.session_tracking_label
+    }
+
+    /// Returns the number of sessions the chain has passed through.
+    pub fn depth(&self) -> usize {
+        self.session_tracking_label.len()
+    }
+
+    /// Returns the session the chain has been started in.
+    pub fn root_session_id(&self) -> &SessionId {
+        self.tracking_id.session_id()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn session_id() -> SessionId {
+        SessionId::new(Uuid::new_v4(), Uuid::new_v4())
+    }
+
+    #[test]
+    fn parses_displayed_ids() {
+        let session_id = session_id();
+        let tracking_id = TrackingId::start(session_id.clone());
+
+        assert_eq!(
+            SessionId::from_str(&session_id.to_string()).unwrap(),
+            session_id
+        );
+        assert_eq!(
+            TrackingId::from_str(&tracking_id.to_string()).unwrap(),
+            tracking_id
+        );
+
+        assert!(SessionId::from_str("not-a-uuid").is_err());
+        assert!(TrackingId::from_str(&Uuid::new_v4().to_string()).is_err());
+    }
+
+    #[test]
+    fn starts_chain_in_session() {
+        let session_id = session_id();
+        let tracking = TrackingProperties::start(session_id.clone());
+
+        assert_eq!(tracking.depth(), 1);
+        assert_eq!(tracking.root_session_id(), &session_id);
+        assert_eq!(
+            tracking.session_tracking_label(),
+            std::slice::from_ref(&session_id)
+        );
+        assert_eq!(tracking.tracking_id().session_id(), &session_id);
+
+        // Every new chain gets its own label.
+        let another = TrackingProperties::start(session_id);
+        assert_ne!(tracking.tracking_id(), another.tracking_id());
+    }
+
+    #[test]
+    fn serializes_session_tracking_label() {
+        let (root, next) = (session_id(), session_id());
+        let tracking = TrackingProperties::new(
+            TrackingId::start(root.clone()),
+            vec![root.clone(), next.clone()],
+        );
+
+        let value = serde_json::to_value(&tracking).unwrap();
+        assert_eq!(
+            value["session_tracking_label"],
+            json!(format!("{} {}", root, next))
+        );
+
+        let tracking = serde_json::from_value::<TrackingProperties>(value).unwrap();
+        assert_eq!(tracking.depth(), 2);
+        assert_eq!(tracking.root_session_id(), &root);
+        assert_eq!(tracking.session_tracking_label()[1], next);
+    }
+}
@@ -9,7 +9,9 @@ use tokio::sync::{
 };
 
 use crate::mqtt::ExtraTags;
-use crate::mqtt::{IncomingMessage, PublishableMessage};
+use crate::mqtt::{
+    CompressionStats, IncomingMessage, IncomingPayload, IncomingRequest, PublishableMessage,
+};
 
 struct QueueCounter {
     cmd_rx: UnboundedReceiver<TimestampedCommand>,
@@ -25,6 +27,21 @@ pub struct QueuesCounter {
     pub outgoing_responses: u64,
     pub outgoing_events: u64,
     pub incoming_bytes: u64,
+    pub rate_limited_requests: u64,
+    pub outgoing_compressed_messages: u64,
+    pub outgoing_uncompressed_bytes: u64,
+    pub outgoing_compressed_bytes: u64,
+}
+
+impl QueuesCounter {
+    /// Returns the ratio of original to compressed payload size of outgoing compressed messages.
+    pub fn compression_ratio(&self) -> Option<f64> {
+        if self.outgoing_compressed_bytes == 0 {
+            return None;
+        }
+
+        Some(self.outgoing_uncompressed_bytes as f64 / self.outgoing_compressed_bytes as f64)
+    }
 }
 
 #[derive(Clone)]
@@ -47,9 +64,11 @@ enum Command {
     IncomingRequest(ExtraTags, u64),
     IncomingResponse(ExtraTags),
     IncomingEvent(ExtraTags),
+    RateLimitedRequest(ExtraTags),
     OutgoingRequest(ExtraTags),
     OutgoingResponse(ExtraTags),
     OutgoingEvent(ExtraTags),
+    OutgoingCompression(ExtraTags, CompressionStats),
     GetThroughput(oneshot::Sender<HashMap<ExtraTags, QueuesCounter>>),
 }
 
@@ -77,7 +96,7 @@ impl QueueCounterHandle {
         Self { cmd_tx }
     }
 
-    pub(crate) fn add_incoming_message(&self, msg: &IncomingMessage<String>) {
+    pub(crate) fn add_incoming_message(&self, msg: &IncomingMessage<IncomingPayload>) {
         let command = match msg {
             IncomingMessage::Event(ev) => {
                 let tags = ev.properties().tags().to_owned();
@@ -99,6 +118,13 @@ impl QueueCounterHandle {
         self.send_command(command);
     }
 
+    /// Counts a request rejected for exceeding the rate limit.
+    pub(crate) fn add_rate_limited_request(&self, req: &IncomingRequest<IncomingPayload>) {
+        let mut tags = req.properties().tags().to_owned();
+        tags.set_method(req.properties().method());
+        self.send_command(Command::RateLimitedRequest(tags));
+    }
+
     pub(crate) fn add_outgoing_message(&self, dump: &PublishableMessage) {
         let command = match dump {
             PublishableMessage::Event(ev) => {
@@ -115,6 +141,11 @@ impl QueueCounterHandle {
             }
         };
         self.send_command(command);
+
+        if let Some(stats) = dump.compression() {
+            let command = Command::OutgoingCompression(dump.tags().to_owned(), stats);
+            self.send_command(command);
+        }
     }
 
     fn send_command(&self, command: Command) {
@@ -173,6 +204,11 @@ impl QueueCounter {
                     c.result.incoming_events += 1;
                     c.updated_at = Instant::now();
                 }
+                Command::RateLimitedRequest(tags) => {
+                    let c = self.counters.entry(tags).or_default();
+                    c.result.rate_limited_requests += 1;
+                    c.updated_at = Instant::now();
+                }
                 Command::OutgoingRequest(tags) => {
                     let c = self.counters.entry(tags).or_default();
                     c.result.outgoing_requests += 1;
@@ -188,6 +224,13 @@ impl QueueCounter {
                     c.result.outgoing_events += 1;
                     c.updated_at = Instant::now();
                 }
+                Command::OutgoingCompression(tags, stats) => {
+                    let c = self.counters.entry(tags).or_default();
+                    c.result.outgoing_compressed_messages += 1;
+                    c.result.outgoing_uncompressed_bytes += stats.original_size() as u64;
+                    c.result.outgoing_compressed_bytes += stats.compressed_size() as u64;
+                    c.updated_at = Instant::now();
+                }
             }
         }
     }
@@ -0,0 +1,177 @@
+use std::{
+    collections::HashMap,
+    sync::{Mutex, PoisonError},
+    time::{Duration, Instant},
+};
+
+use log::warn;
+
+use crate::{AccountId, Error};
+
+#[derive(Debug, Clone, Copy)]
+enum State {
+    Closed { failures: u32 },
+    Open { until: Instant },
+    HalfOpen { until: Instant },
+}
+
+/// Per destination circuit breaker of [Dispatcher](struct.Dispatcher.html).
+///
+/// When requests to some account keep timing out `failure_threshold` times in a row the circuit
+/// opens and requests to the account fail fast without being published during `open_duration`.
+/// After that a single trial request is allowed. If it succeeds the circuit closes otherwise
+/// it opens again. If the trial request's outcome is unknown for another `open_duration`
+/// then one more trial request is allowed.
+///
+/// # Example
+///
+/// ```
+/// let breaker = CircuitBreaker::new(5, Duration::from_secs(30));
+/// let dispatcher = Dispatcher::new(&agent).circuit_breaker(breaker);
+/// ```
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    failure_threshold: u32,
+    open_duration: Duration,
+    states: Mutex<HashMap<AccountId, State>>,
+}
+
+impl CircuitBreaker {
+    /// Builds a [CircuitBreaker](struct.CircuitBreaker.html).
+    ///
+    /// # Arguments
+    ///
+    /// * `failure_threshold` – number of consecutive timeouts to open the circuit.
+    /// * `open_duration` – how long the circuit stays open.
+    pub fn new(failure_threshold: u32, open_duration: Duration) -> Self {
+        Self {
+            failure_threshold: failure_threshold.max(1),
+            open_duration,
+            states: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Checks whether a request to the `account_id` is allowed.
+    pub(crate) fn check(&self, account_id: &AccountId) -> Result<(), Error> {
+        let mut states_lock = self.states.lock().unwrap_or_else(PoisonError::into_inner);
+
+        let now = Instant::now();
+
+        match states_lock.get(account_id).copied() {
+            Some(State::Open { until }) if until > now => Err(Error::new(&format!(
+                "Circuit is open for account = '{}'",
+                account_id
+            ))),
+            Some(State::HalfOpen { until }) if until > now => Err(Error::new(&format!(
+                "Circuit is half-open for account = '{}' and awaits for the trial request",
+                account_id
+            ))),
+            Some(State::Open { .. }) | Some(State::HalfOpen { .. }) => {
+                let state = State::HalfOpen {
+                    until: now + self.open_duration,
+                };
+
+                states_lock.insert(account_id.to_owned(), state);
+                Ok(())
+            }
+            Some(State::Closed { .. }) | None => Ok(()),
+        }
+    }
+
+    pub(crate) fn record_success(&self, account_id: &AccountId) {
+        self.states
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(account_id);
+    }
+
+    pub(crate) fn record_failure(&self, account_id: &AccountId) {
+        let mut states_lock = self.states.lock().unwrap_or_else(PoisonError::into_inner);
+
+        let failures = match states_lock.get(account_id) {
+            Some(State::Closed { failures }) => failures + 1,
+            Some(State::HalfOpen { .. }) => self.failure_threshold,
+            Some(State::Open { .. }) => return,
+            None => 1,
+        };
+
+        let state = if failures >= self.failure_threshold {
+            warn!("Opening circuit for account = '{}'", account_id);
+
+            State::Open {
+                until: Instant::now() + self.open_duration,
+            }
+        } else {
+            State::Closed { failures }
+        };
+
+        states_lock.insert(account_id.to_owned(), state);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    const OPEN_DURATION: Duration = Duration::from_millis(50);
+
+    fn account_id() -> AccountId {
+        AccountId::new("sender", "svc.example.org")
+    }
+
+    #[test]
+    fn opens_after_consecutive_failures() {
+        let breaker = CircuitBreaker::new(2, OPEN_DURATION);
+        let account_id = account_id();
+
+        breaker.record_failure(&account_id);
+        assert!(breaker.check(&account_id).is_ok());
+
+        breaker.record_failure(&account_id);
+        assert!(breaker.check(&account_id).is_err());
+    }
+
+    #[test]
+    fn success_resets_failures() {
+        let breaker = CircuitBreaker::new(2, OPEN_DURATION);
+        let account_id = account_id();
+
+        breaker.record_failure(&account_id);
+        breaker.record_success(&account_id);
+        breaker.record_failure(&account_id);
+        assert!(breaker.check(&account_id).is_ok());
+    }
+
+    #[test]
+    fn allows_single_trial_request_after_open_duration() {
+        let breaker = CircuitBreaker::new(1, OPEN_DURATION);
+        let account_id = account_id();
+
+        breaker.record_failure(&account_id);
+        thread::sleep(OPEN_DURATION);
+
+        assert!(breaker.check(&account_id).is_ok());
+        assert!(breaker.check(&account_id).is_err());
+
+        breaker.record_success(&account_id);
+        assert!(breaker.check(&account_id).is_ok());
+    }
+
+    #[test]
+    fn reopens_on_failed_trial_request() {
+        let breaker = CircuitBreaker::new(3, OPEN_DURATION);
+        let account_id = account_id();
+
+        for _ in 0..3 {
+            breaker.record_failure(&account_id);
+        }
+
+        thread::sleep(OPEN_DURATION);
+        assert!(breaker.check(&account_id).is_ok());
+
+        breaker.record_failure(&account_id);
+        assert!(breaker.check(&account_id).is_err());
+    }
+}
@@ -0,0 +1,254 @@
+use std::{
+    collections::HashMap,
+    sync::{Mutex, PoisonError},
+    time::{Duration, Instant},
+};
+
+use serde::ser::Serialize;
+use tokio::sync::oneshot;
+
+use super::RawResponse;
+use crate::{Destination, Error};
+
+/// Identity of a [call](struct.Dispatcher.html#method.call) to tell identical ones apart.
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub(super) struct CallKey {
+    method: String,
+    destination: Destination,
+    payload: String,
+}
+
+impl CallKey {
+    pub(super) fn new<T: Serialize>(
+        method: &str,
+        payload: &T,
+        destination: &Destination,
+    ) -> Result<Self, Error> {
+        let payload = serde_json::to_string(payload)
+            .map_err(|err| Error::new(&format!("Failed to serialize payload: {}", err)))?;
+
+        Ok(Self {
+            method: method.to_owned(),
+            destination: destination.to_owned(),
+            payload,
+        })
+    }
+
+    pub(super) fn method(&self) -> &str {
+        &self.method
+    }
+
+    pub(super) fn destination(&self) -> &Destination {
+        &self.destination
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+type Waiters = Vec<oneshot::Sender<Result<RawResponse, String>>>;
+
+/// Calls being made right now which identical calls may join to.
+#[derive(Default)]
+pub(super) struct InFlightCalls(Mutex<HashMap<CallKey, Waiters>>);
+
+pub(super) enum Flight<'a> {
+    /// The first one of identical calls which actually makes the request.
+    Leader(LeaderGuard<'a>),
+    /// Any subsequent identical call which awaits for the leader's result.
+    Follower(oneshot::Receiver<Result<RawResponse, String>>),
+}
+
+impl InFlightCalls {
+    pub(super) fn join(&self, key: &CallKey) -> Flight<'_> {
+        let mut calls_lock = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+
+        match calls_lock.get_mut(key) {
+            Some(waiters) => {
+                let (tx, rx) = oneshot::channel();
+                waiters.push(tx);
+                Flight::Follower(rx)
+            }
+            None => {
+                calls_lock.insert(key.to_owned(), Vec::new());
+
+                Flight::Leader(LeaderGuard {
+                    calls: self,
+                    key: Some(key.to_owned()),
+                })
+            }
+        }
+    }
+
+    #[cfg(test)]
+    pub(super) fn followers(&self, key: &CallKey) -> usize {
+        let calls_lock = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+        calls_lock.get(key).map_or(0, Vec::len)
+    }
+}
+
+/// Shares the result of the leading call with the followers.
+///
+/// If the leading call gets dropped before completion the followers get notified
+/// so one of them could take the lead.
+pub(super) struct LeaderGuard<'a> {
+    calls: &'a InFlightCalls,
+    key: Option<CallKey>,
+}
+
+impl<'a> LeaderGuard<'a> {
+    pub(super) fn complete(mut self, result: &Result<RawResponse, Error>) {
+        for tx in self.take_waiters() {
+            let result = match result {
+                Ok(resp) => Ok(resp.to_owned()),
+                Err(err) => Err(err.to_string()),
+            };
+
+            // The follower may have already gone.
+            let _ = tx.send(result);
+        }
+    }
+
+    fn take_waiters(&mut self) -> Waiters {
+        match self.key.take() {
+            Some(key) => self
+                .calls
+                .0
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .remove(&key)
+                .unwrap_or_default(),
+            None => Vec::new(),
+        }
+    }
+}
+
+impl<'a> Drop for LeaderGuard<'a> {
+    fn drop(&mut self) {
+        self.take_waiters();
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Successful responses to the methods marked cacheable.
+#[derive(Default)]
+pub(super) struct ResponseCache {
+    ttls: HashMap<String, Duration>,
+    entries: Mutex<HashMap<CallKey, (Instant, RawResponse)>>,
+}
+
+impl ResponseCache {
+    pub(super) fn set_ttl(&mut self, method: &str, ttl: Duration) {
+        self.ttls.insert(method.to_owned(), ttl);
+    }
+
+    pub(super) fn is_cacheable(&self, method: &str) -> bool {
+        self.ttls.contains_key(method)
+    }
+
+    pub(super) fn get(&self, key: &CallKey) -> Option<RawResponse> {
+        let mut entries_lock = self.entries.lock().unwrap_or_else(PoisonError::into_inner);
+
+        match entries_lock.get(key) {
+            Some((expires_at, resp)) if *expires_at > Instant::now() => Some(resp.to_owned()),
+            Some(_) => {
+                entries_lock.remove(key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    pub(super) fn insert(&self, key: &CallKey, resp: &RawResponse) {
+        let ttl = match self.ttls.get(key.method()) {
+            Some(ttl) if resp.properties().status().is_success() => *ttl,
+            _ => return,
+        };
+
+        let now = Instant::now();
+        let mut entries_lock = self.entries.lock().unwrap_or_else(PoisonError::into_inner);
+        entries_lock.retain(|_, (expires_at, _)| *expires_at > now);
+        entries_lock.insert(key.to_owned(), (now + ttl, resp.to_owned()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::{mqtt::testing::response, AccountId};
+
+    fn key(method: &str) -> CallKey {
+        let account_id = AccountId::new("sender", "svc.example.org");
+        let destination = Destination::Multicast(account_id, "v1".to_owned());
+        CallKey::new(method, &json!({ "id": 1 }), &destination).unwrap()
+    }
+
+    #[tokio::test]
+    async fn followers_share_leader_result() {
+        let calls = InFlightCalls::default();
+        let key = key("room.read");
+
+        let leader = match calls.join(&key) {
+            Flight::Leader(guard) => guard,
+            Flight::Follower(_) => panic!("expected the leader"),
+        };
+
+        let follower = match calls.join(&key) {
+            Flight::Follower(rx) => rx,
+            Flight::Leader(_) => panic!("expected a follower"),
+        };
+
+        leader.complete(&Ok(response(200, "corr", json!({}))));
+
+        let resp = follower.await.unwrap().unwrap();
+        assert_eq!(resp.properties().correlation_data(), "corr");
+        assert!(matches!(calls.join(&key), Flight::Leader(_)));
+    }
+
+    #[tokio::test]
+    async fn follower_takes_lead_after_leader_dropped() {
+        let calls = InFlightCalls::default();
+        let key = key("room.read");
+
+        let leader = calls.join(&key);
+
+        let follower = match calls.join(&key) {
+            Flight::Follower(rx) => rx,
+            Flight::Leader(_) => panic!("expected a follower"),
+        };
+
+        drop(leader);
+
+        assert!(follower.await.is_err());
+        assert!(matches!(calls.join(&key), Flight::Leader(_)));
+    }
+
+    #[test]
+    fn caches_successful_responses_until_ttl() {
+        let mut cache = ResponseCache::default();
+        cache.set_ttl("room.read", Duration::from_millis(20));
+        let key = key("room.read");
+
+        cache.insert(&key, &response(500, "failed", json!({})));
+        assert!(cache.get(&key).is_none());
+
+        cache.insert(&key, &response(200, "succeeded", json!({})));
+        let resp = cache.get(&key).unwrap();
+        assert_eq!(resp.properties().correlation_data(), "succeeded");
+
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(cache.get(&key).is_none());
+    }
+
+    #[test]
+    fn ignores_methods_not_marked_cacheable() {
+        let cache = ResponseCache::default();
+        let key = key("room.update");
+
+        assert!(!cache.is_cacheable("room.update"));
+        cache.insert(&key, &response(200, "corr", json!({})));
+        assert!(cache.get(&key).is_none());
+    }
+}
@@ -0,0 +1,808 @@
+use std::{
+    collections::HashMap,
+    sync::{Mutex, PoisonError},
+    time::Duration,
+};
+
+use chrono::{DateTime, Utc};
+use log::warn;
+use serde::{de::DeserializeOwned, ser::Serialize};
+use tokio::sync::mpsc;
+use uuid::Uuid;
+
+use crate::{
+    mqtt::{
+        Agent, IncomingPayload, IncomingResponse, OutgoingMessage, OutgoingRequest,
+        OutgoingRequestProperties, OutgoingShortTermTimingProperties, QoS, SubscriptionHandle,
+        SubscriptionTopic,
+    },
+    AccountId, Authenticable, Destination, Error, Subscription,
+};
+
+pub use self::circuit_breaker::CircuitBreaker;
+pub use self::retry::RetryPolicy;
+pub use self::scatter::{GatherPolicy, ScatterResult, TargetOutcome};
+pub(crate) use self::store::PendingResponses;
+pub use self::stream::ResponseStream;
+
+use self::coalesce::{CallKey, Flight, InFlightCalls, ResponseCache};
+use self::store::{AttemptError, RawResponse, ResponseSender};
+
+mod circuit_breaker;
+mod coalesce;
+mod retry;
+mod scatter;
+mod store;
+mod stream;
+
+////////////////////////////////////////////////////////////////////////////////
+
+pub struct Dispatcher {
+    agent: Agent,
+    store: PendingResponses,
+    response_topics: Mutex<HashMap<String, SubscriptionHandle>>,
+    retry_policy: RetryPolicy,
+    circuit_breaker: Option<CircuitBreaker>,
+    coalescing: bool,
+    in_flight: InFlightCalls,
+    cache: ResponseCache,
+}
+
+impl Dispatcher {
+    /// Builds a [Dispatcher](struct.Dispatcher.html) for the agent.
+    ///
+    /// If the agent has been started
+    /// [with dispatcher](../mqtt/struct.AgentBuilder.html#method.with_dispatcher) then responses
+    /// to the dispatcher's requests get routed to it automatically. Otherwise one has to pass
+    /// them to [response](#method.response) from the message handling loop.
+    pub fn new(agent: &Agent) -> Self {
+        Self {
+            agent: agent.to_owned(),
+            store: agent.pending_responses().cloned().unwrap_or_default(),
+            response_topics: Mutex::new(HashMap::new()),
+            retry_policy: RetryPolicy::default(),
+            circuit_breaker: None,
+            coalescing: false,
+            in_flight: InFlightCalls::default(),
+            cache: ResponseCache::default(),
+        }
+    }
+
+    /// Sets a [RetryPolicy](struct.RetryPolicy.html) for [call](#method.call).
+    ///
+    /// Only the attempt timeout of the policy applies to [request](#method.request) since
+    /// it can't regenerate the correlation data of a request built by the caller.
+    pub fn retry_policy(self, retry_policy: RetryPolicy) -> Self {
+        Self {
+            retry_policy,
+            ..self
+        }
+    }
+
+    /// Sets a per destination account [CircuitBreaker](struct.CircuitBreaker.html).
+    pub fn circuit_breaker(self, circuit_breaker: CircuitBreaker) -> Self {
+        Self {
+            circuit_breaker: Some(circuit_breaker),
+            ..self
+        }
+    }
+
+    /// Sets whether concurrent identical [calls](#method.call) share a single request.
+    ///
+    /// Calls are identical when they have the same method, destination and payload.
+    /// While the first of them awaits for the response the others join it instead of making
+    /// their own requests and get the same response or error. Use it for idempotent methods only.
+    pub fn coalesce_calls(self, value: bool) -> Self {
+        Self {
+            coalescing: value,
+            ..self
+        }
+    }
+
+    /// Marks the `method` cacheable so successful responses to [calls](#method.call) of it
+    /// get reused by identical calls during `ttl`.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let dispatcher = Dispatcher::new(&agent)
+    ///     .coalesce_calls(true)
+    ///     .cache_responses("room.read", Duration::from_secs(5));
+    /// ```
+    pub fn cache_responses(mut self, method: &str, ttl: Duration) -> Self {
+        self.cache.set_ttl(method, ttl);
+        self
+    }
+
+    /// Makes a request and awaits for the response.
+    ///
+    /// Unlike [request](#method.request) it takes care of the request properties by itself:
+    /// generates unique correlation data, sets the response topic and timings. It also
+    /// subscribes to the responses from the destination account on the first call to it.
+    ///
+    /// The request gets retried according to the [retry policy](#method.retry_policy).
+    /// Each attempt has its own correlation data so late responses to the previous attempts
+    /// are dropped.
+    ///
+    /// Identical calls may be [coalesced](#method.coalesce_calls) and their responses
+    /// [cached](#method.cache_responses).
+    ///
+    /// # Arguments
+    ///
+    /// * `method` – request method.
+    /// * `payload` – any serializable value.
+    /// * `destination` – multicast or unicast [Destination](../enum.Destination.html).
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let to = AccountId::new("service_name", "svc.example.org");
+    ///
+    /// let response = dispatcher
+    ///     .call::<_, JsonValue>(
+    ///         "room.read",
+    ///         json!({ "id": room_id }),
+    ///         Destination::Multicast(to, "v1".to_owned()),
+    ///     )
+    ///     .await?;
+    /// ```
+    pub async fn call<Req, Resp>(
+        &self,
+        method: &str,
+        payload: Req,
+        destination: Destination,
+    ) -> Result<IncomingResponse<Resp>, Error>
+    where
+        Req: Serialize,
+        Resp: DeserializeOwned,
+    {
+        self.call_with_deadline(method, payload, destination, None)
+            .await
+    }
+
+    /// Makes a [call](#method.call) that gives up once the `deadline` passes.
+    ///
+    /// The deadline is set on the request so the callee drops it if it arrives too late and
+    /// passes it further down the chain. Each attempt is awaited no longer than the time left
+    /// and no attempt is made after the deadline.
+    ///
+    /// # Arguments
+    ///
+    /// * `method` – request method.
+    /// * `payload` – any serializable value.
+    /// * `destination` – multicast or unicast [Destination](../enum.Destination.html).
+    /// * `deadline` – time to give up at. No deadline makes it the same as [call](#method.call).
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// // Spend no more than the budget of the request being handled.
+    /// let response = dispatcher
+    ///     .call_with_deadline::<_, JsonValue>(
+    ///         "room.read",
+    ///         json!({ "id": room_id }),
+    ///         Destination::Multicast(to, "v1".to_owned()),
+    ///         request.properties().deadline(),
+    ///     )
+    ///     .await?;
+    /// ```
+    pub async fn call_with_deadline<Req, Resp>(
+        &self,
+        method: &str,
+        payload: Req,
+        destination: Destination,
+        deadline: Option<DateTime<Utc>>,
+    ) -> Result<IncomingResponse<Resp>, Error>
+    where
+        Req: Serialize,
+        Resp: DeserializeOwned,
+    {
+        if !self.coalescing && !self.cache.is_cacheable(method) {
+            return self
+                .call_raw(method, &payload, &destination, deadline)
+                .await
+                .and_then(convert_response);
+        }
+
+        let key = CallKey::new(method, &payload, &destination)?;
+
+        if let Some(resp) = self.cache.get(&key) {
+            return convert_response(resp);
+        }
+
+        if !self.coalescing {
+            return self
+                .fetch(&key, &payload, deadline)
+                .await
+                .and_then(convert_response);
+        }
+
+        loop {
+            match self.in_flight.join(&key) {
+                Flight::Leader(guard) => {
+                    let result = self.fetch(&key, &payload, deadline).await;
+                    guard.complete(&result);
+                    return result.and_then(convert_response);
+                }
+                Flight::Follower(rx) => {
+                    // The leading call may have a later deadline than this one.
+                    let result = match remaining_until(deadline) {
+                        Some(remaining) => match tokio::time::timeout(remaining, rx).await {
+                            Ok(result) => result,
+                            Err(_) => {
+                                let account_id = destination_account_id(&destination)?;
+                                return Err(deadline_error(method, account_id));
+                            }
+                        },
+                        None => rx.await,
+                    };
+
+                    match result {
+                        Ok(result) => {
+                            return result
+                                .map_err(|err| Error::new(&err))
+                                .and_then(convert_response)
+                        }
+                        // The leading call has been dropped so take the lead.
+                        Err(_) => continue,
+                    }
+                }
+            }
+        }
+    }
+
+    /// Makes a call caching the response if the method is cacheable.
+    async fn fetch<Req>(
+        &self,
+        key: &CallKey,
+        payload: &Req,
+        deadline: Option<DateTime<Utc>>,
+    ) -> Result<RawResponse, Error>
+    where
+        Req: Serialize,
+    {
+        let result = self
+            .call_raw(key.method(), payload, key.destination(), deadline)
+            .await;
+
+        if let Ok(ref resp) = result {
+            self.cache.insert(key, resp);
+        }
+
+        result
+    }
+
+    async fn call_raw<Req>(
+        &self,
+        method: &str,
+        payload: &Req,
+        destination: &Destination,
+        deadline: Option<DateTime<Utc>>,
+    ) -> Result<RawResponse, Error>
+    where
+        Req: Serialize,
+    {
+        let account_id = destination_account_id(destination)?.to_owned();
+        let response_topic = self.ensure_response_topic(&account_id)?;
+        let policy = &self.retry_policy;
+        let mut attempt = 1;
+
+        loop {
+            if remaining_until(deadline) == Some(Duration::ZERO) {
+                return Err(deadline_error(method, &account_id));
+            }
+
+            let now = Utc::now();
+            let correlation_data = Uuid::new_v4().to_string();
+
+            let mut props = OutgoingRequestProperties::new(
+                method,
+                &response_topic,
+                &correlation_data,
+                OutgoingShortTermTimingProperties::new(now),
+            );
+
+            props.set_local_timestamp(now);
+
+            if let Some(deadline) = deadline {
+                props.set_deadline(deadline);
+            }
+
+            let req = OutgoingRequest::new(payload, props, destination.clone());
+            let result = self
+                .attempt(req, &account_id, self.timeout_until(deadline))
+                .await;
+
+            let is_last_attempt = attempt >= policy.max_attempts()
+                || remaining_until(deadline) == Some(Duration::ZERO);
+
+            match result {
+                Ok(resp)
+                    if !is_last_attempt
+                        && policy.is_retryable_status(resp.properties().status()) =>
+                {
+                    warn!(
+                        "Retrying '{}' request to '{}' after attempt {} with status = '{}'",
+                        method,
+                        account_id,
+                        attempt,
+                        resp.properties().status(),
+                    );
+                }
+                Ok(resp) => return Ok(resp),
+                Err(AttemptError::Timeout) if !is_last_attempt && policy.is_retryable_timeout() => {
+                    warn!(
+                        "Retrying '{}' request to '{}' after attempt {} timed out",
+                        method, account_id, attempt,
+                    );
+                }
+                Err(AttemptError::Timeout) => {
+                    return Err(Error::new(&format!(
+                        "Timed out awaiting response to '{}' request to '{}' after {} attempt(s)",
+                        method, account_id, attempt,
+                    )))
+                }
+                Err(AttemptError::Failed(err)) => return Err(err),
+            }
+
+            let backoff = policy.backoff_delay(attempt);
+            let backoff =
+                remaining_until(deadline).map_or(backoff, |remaining| backoff.min(remaining));
+            tokio::time::sleep(backoff).await;
+            attempt += 1;
+        }
+    }
+
+    /// Returns a topic to receive responses from the `account_id` on subscribing to it
+    /// unless it has already been done.
+    fn ensure_response_topic(&self, account_id: &AccountId) -> Result<String, Error> {
+        let subscription = Subscription::unicast_responses_from(account_id);
+        let topic =
+            subscription.subscription_topic(self.agent.id(), self.agent.address().version())?;
+        let mut topics_lock = self
+            .response_topics
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+
+        if !topics_lock.contains_key(&topic) {
+            // The broker processes packets of a connection in order so the subscription
+            // takes effect before the request gets published.
+            let handle = self
+                .agent
+                .clone()
+                .subscribe(&subscription, QoS::AtLeastOnce, None)?;
+
+            topics_lock.insert(topic.clone(), handle);
+        }
+
+        Ok(topic)
+    }
+
+    /// Makes a request built by the caller and awaits for the response.
+    ///
+    /// The response is awaited until the [attempt timeout](struct.RetryPolicy.html#method.timeout)
+    /// or the [deadline](../mqtt/struct.OutgoingRequestProperties.html#method.set_deadline)
+    /// of the request expires whichever comes first.
+    pub async fn request<Req, Resp>(
+        &self,
+        req: OutgoingRequest<Req>,
+    ) -> Result<IncomingResponse<Resp>, Error>
+    where
+        Req: 'static + Serialize,
+        Resp: DeserializeOwned,
+    {
+        let account_id = destination_account_id(&req.destination)?.to_owned();
+        let corr_data = req.properties().correlation_data().to_owned();
+        let timeout = self.timeout_until(req.properties().deadline());
+
+        match self.attempt(req, &account_id, timeout).await {
+            Ok(resp) => convert_response(resp),
+            Err(AttemptError::Timeout) => Err(Error::new(&format!(
+                "Timed out awaiting response with correlation data = '{}'",
+                corr_data
+            ))),
+            Err(AttemptError::Failed(err)) => Err(err),
+        }
+    }
+
+    /// Makes a request which is being responded with multiple responses.
+    ///
+    /// Returns a [ResponseStream](struct.ResponseStream.html) of the responses. Progress
+    /// responses have `202 Accepted` status and the stream ends after the final response
+    /// with any other status. It also ends when the
+    /// [attempt timeout](struct.RetryPolicy.html#method.timeout) expires before the next
+    /// response arrives or the request gets [cancelled](#method.cancel_request). The stream
+    /// never lasts past the
+    /// [deadline](../mqtt/struct.OutgoingRequestProperties.html#method.set_deadline) of the request.
+    /// Dropping the stream cancels the request.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let mut stream = dispatcher.request_stream::<_, JsonValue>(request)?;
+    ///
+    /// while let Some(result) = stream.next().await {
+    ///     let response = result?;
+    ///     println!("Status: {}", response.properties().status());
+    /// }
+    /// ```
+    pub fn request_stream<Req, Resp>(
+        &self,
+        req: OutgoingRequest<Req>,
+    ) -> Result<ResponseStream<Resp>, Error>
+    where
+        Req: Serialize,
+        Resp: DeserializeOwned,
+    {
+        let corr_data = req.properties().correlation_data().to_owned();
+        let deadline = remaining_until(req.properties().deadline())
+            .map(|remaining| tokio::time::Instant::now() + remaining);
+        let (tx, rx) = mpsc::unbounded_channel::<RawResponse>();
+        self.store.insert(&corr_data, ResponseSender::Stream(tx))?;
+
+        let stream = ResponseStream::new(
+            self.store.clone(),
+            &corr_data,
+            rx,
+            self.retry_policy.attempt_timeout(),
+            deadline,
+        );
+
+        self.agent.clone().publish(OutgoingMessage::Request(req))?;
+        Ok(stream)
+    }
+
+    /// Returns the attempt timeout bounded by the time left until the `deadline`.
+    fn timeout_until(&self, deadline: Option<DateTime<Utc>>) -> Option<Duration> {
+        match (
+            self.retry_policy.attempt_timeout(),
+            remaining_until(deadline),
+        ) {
+            (Some(timeout), Some(remaining)) => Some(timeout.min(remaining)),
+            (timeout, remaining) => timeout.or(remaining),
+        }
+    }
+
+    async fn attempt<Req>(
+        &self,
+        req: OutgoingRequest<Req>,
+        account_id: &AccountId,
+        timeout: Option<Duration>,
+    ) -> Result<RawResponse, AttemptError>
+    where
+        Req: Serialize,
+    {
+        if let Some(ref circuit_breaker) = self.circuit_breaker {
+            circuit_breaker
+                .check(account_id)
+                .map_err(AttemptError::Failed)?;
+        }
+
+        let pending = self
+            .store
+            .register(req.properties().correlation_data())
+            .map_err(AttemptError::Failed)?;
+
+        self.agent
+            .clone()
+            .publish(OutgoingMessage::Request(req))
+            .map_err(AttemptError::Failed)?;
+
+        let result = pending.wait(timeout).await;
+
+        if let Some(ref circuit_breaker) = self.circuit_breaker {
+            match result {
+                Ok(_) => circuit_breaker.record_success(account_id),
+                Err(AttemptError::Timeout) => circuit_breaker.record_failure(account_id),
+                Err(AttemptError::Failed(_)) => (),
+            }
+        }
+
+        result
+    }
+
+    /// Passes the response to the request awaiting for it.
+    ///
+    /// Only needed when the agent has been started without
+    /// [dispatcher](../mqtt/struct.AgentBuilder.html#method.with_dispatcher). The payload is
+    /// kept raw until the awaiting side deserializes it into the expected type.
+    /// Late responses to timed out or cancelled requests are dropped.
+    pub fn response(&self, resp: IncomingResponse<IncomingPayload>) -> Result<(), Error> {
+        self.store.send(resp).map(|_| ())
+    }
+
+    pub fn cancel_request(&self, corr_data: &str) -> Result<(), Error> {
+        self.store.cancel(corr_data)
+    }
+}
+
+/// Returns the time left until the `deadline`, zero if it has expired.
+fn remaining_until(deadline: Option<DateTime<Utc>>) -> Option<Duration> {
+    deadline.map(|deadline| (deadline - Utc::now()).to_std().unwrap_or_default())
+}
+
+fn deadline_error(method: &str, account_id: &AccountId) -> Error {
+    Error::new(&format!(
+        "Deadline expired awaiting response to '{}' request to '{}'",
+        method, account_id
+    ))
+}
+
+fn destination_account_id(destination: &Destination) -> Result<&AccountId, Error> {
+    match destination {
+        Destination::Multicast(ref account_id, _) => Ok(account_id),
+        Destination::Unicast(ref agent_id, _) => Ok(agent_id.as_account_id()),
+        Destination::Broadcast(_) => Err(Error::new(&format!(
+            "destination = '{:?}' is incompatible with request message type",
+            destination
+        ))),
+    }
+}
+
+fn convert_response<Resp>(resp: RawResponse) -> Result<IncomingResponse<Resp>, Error>
+where
+    Resp: DeserializeOwned,
+{
+    IncomingResponse::convert::<Resp>(resp)
+        .map_err(|err| Error::new(&format!("Failed to parse response payload: {}", err)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use rumqttc::Request;
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::mqtt::{
+        testing::{next_published, published, response},
+        ResponseStatus,
+    };
+
+    fn destination() -> Destination {
+        Destination::Multicast(AccountId::new("sender", "svc.example.org"), "v1".to_owned())
+    }
+
+    #[tokio::test]
+    async fn call_generates_request_properties() {
+        let (agent, rx) = Agent::stub();
+        let dispatcher = Arc::new(Dispatcher::new(&agent));
+
+        let call = tokio::spawn({
+            let dispatcher = dispatcher.clone();
+            async move {
+                dispatcher
+                    .call::<_, Value>("room.read", json!({ "id": 1 }), destination())
+                    .await
+            }
+        });
+
+        match rx.recv().await.unwrap() {
+            Request::Subscribe(subscribe) => assert_eq!(
+                subscribe.filters[0].path,
+                "agents/instance01.receiver.svc.example.org/api/v1/in/sender.svc.example.org"
+            ),
+            _ => panic!("expected a subscription to responses"),
+        }
+
+        let (topic, envelope) = next_published(&rx).await;
+        let props = &envelope["properties"];
+        assert_eq!(
+            topic,
+            "agents/instance01.receiver.svc.example.org/api/v1/out/sender.svc.example.org"
+        );
+        assert_eq!(props["method"], "room.read");
+        assert_eq!(
+            props["response_topic"],
+            "agents/instance01.receiver.svc.example.org/api/v1/in/sender.svc.example.org"
+        );
+
+        let corr_data = props["correlation_data"].as_str().unwrap();
+        assert!(Uuid::parse_str(corr_data).is_ok());
+
+        dispatcher
+            .response(response(200, corr_data, json!({ "name": "room" })))
+            .unwrap();
+
+        let resp = call.await.unwrap().unwrap();
+        assert_eq!(resp.properties().status(), 200);
+        assert_eq!(resp.payload(), &json!({ "name": "room" }));
+    }
+
+    #[tokio::test]
+    async fn call_subscribes_to_responses_once() {
+        let (agent, rx) = Agent::stub();
+        let dispatcher = Arc::new(Dispatcher::new(&agent));
+        let mut correlation_data = Vec::new();
+
+        for _ in 0..2 {
+            let call = tokio::spawn({
+                let dispatcher = dispatcher.clone();
+                async move {
+                    dispatcher
+                        .call::<_, Value>("room.read", json!({}), destination())
+                        .await
+                }
+            });
+
+            let (_, envelope) = next_published(&rx).await;
+            let corr_data = envelope["properties"]["correlation_data"].as_str().unwrap();
+            dispatcher
+                .response(response(200, corr_data, json!({})))
+                .unwrap();
+            call.await.unwrap().unwrap();
+            correlation_data.push(corr_data.to_owned());
+        }
+
+        assert_ne!(correlation_data[0], correlation_data[1]);
+        assert_eq!(dispatcher.response_topics.lock().unwrap().len(), 1);
+    }
+    #[tokio::test]
+    async fn call_retries_retryable_status() {
+        let (agent, rx) = Agent::stub();
+        let policy = RetryPolicy::new(2).retry_on_status(ResponseStatus::SERVICE_UNAVAILABLE);
+        let dispatcher = Arc::new(Dispatcher::new(&agent).retry_policy(policy));
+
+        let call = tokio::spawn({
+            let dispatcher = dispatcher.clone();
+            async move {
+                dispatcher
+                    .call::<_, Value>("room.read", json!({}), destination())
+                    .await
+            }
+        });
+
+        let (_, first) = next_published(&rx).await;
+        let first = first["properties"]["correlation_data"].as_str().unwrap();
+        dispatcher
+            .response(response(503, first, json!({})))
+            .unwrap();
+
+        let (_, second) = next_published(&rx).await;
+        let second = second["properties"]["correlation_data"].as_str().unwrap();
+        assert_ne!(first, second);
+
+        // The late response to the first attempt is dropped.
+        dispatcher
+            .response(response(503, first, json!({})))
+            .unwrap();
+        dispatcher
+            .response(response(200, second, json!({})))
+            .unwrap();
+
+        let resp = call.await.unwrap().unwrap();
+        assert_eq!(resp.properties().status(), ResponseStatus::OK);
+    }
+
+    #[tokio::test]
+    async fn call_fails_fast_on_open_circuit() {
+        let (agent, rx) = Agent::stub();
+
+        let dispatcher = Dispatcher::new(&agent)
+            .retry_policy(RetryPolicy::new(1).timeout(Duration::from_millis(10)))
+            .circuit_breaker(CircuitBreaker::new(1, Duration::from_secs(60)));
+
+        let result = dispatcher
+            .call::<_, Value>("room.read", json!({}), destination())
+            .await;
+
+        assert!(result.is_err());
+        assert_eq!(published(&rx).len(), 1);
+
+        let result = dispatcher
+            .call::<_, Value>("room.read", json!({}), destination())
+            .await;
+
+        assert!(result.is_err());
+        assert!(published(&rx).is_empty());
+    }
+
+    #[tokio::test]
+    async fn call_with_deadline_sets_deadline() {
+        let (agent, rx) = Agent::stub();
+        let dispatcher = Arc::new(Dispatcher::new(&agent));
+        let deadline = Utc::now() + chrono::Duration::seconds(10);
+
+        let call = tokio::spawn({
+            let dispatcher = dispatcher.clone();
+            async move {
+                dispatcher
+                    .call_with_deadline::<_, Value>(
+                        "room.read",
+                        json!({}),
+                        destination(),
+                        Some(deadline),
+                    )
+                    .await
+            }
+        });
+
+        let (_, envelope) = next_published(&rx).await;
+        let props = &envelope["properties"];
+        assert_eq!(props["deadline"], deadline.timestamp_millis().to_string());
+
+        let corr_data = props["correlation_data"].as_str().unwrap();
+        dispatcher
+            .response(response(200, corr_data, json!({})))
+            .unwrap();
+
+        assert!(call.await.unwrap().is_ok());
+    }
+
+    #[tokio::test]
+    async fn call_with_deadline_stops_retrying_at_deadline() {
+        let (agent, rx) = Agent::stub();
+
+        let policy = RetryPolicy::new(10)
+            .timeout(Duration::from_secs(10))
+            .retry_on_timeout(true);
+
+        let dispatcher = Dispatcher::new(&agent).retry_policy(policy);
+        let deadline = Utc::now() + chrono::Duration::milliseconds(50);
+        let start = std::time::Instant::now();
+
+        let result = dispatcher
+            .call_with_deadline::<_, Value>("room.read", json!({}), destination(), Some(deadline))
+            .await;
+
+        assert!(result.is_err());
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert_eq!(published(&rx).len(), 1);
+    }
+
+    #[tokio::test]
+    async fn call_with_expired_deadline_fails_without_request() {
+        let (agent, rx) = Agent::stub();
+        let dispatcher = Dispatcher::new(&agent);
+        let deadline = Utc::now() - chrono::Duration::seconds(1);
+
+        let result = dispatcher
+            .call_with_deadline::<_, Value>("room.read", json!({}), destination(), Some(deadline))
+            .await;
+
+        assert!(result.is_err());
+        assert!(published(&rx).is_empty());
+    }
+
+    #[tokio::test]
+    async fn coalesced_follower_takes_lead_after_leader_dropped() {
+        let (agent, rx) = Agent::stub();
+        let dispatcher = Arc::new(Dispatcher::new(&agent).coalesce_calls(true));
+
+        let call = || {
+            let dispatcher = dispatcher.clone();
+
+            tokio::spawn(async move {
+                dispatcher
+                    .call::<_, Value>("room.read", json!({ "id": 1 }), destination())
+                    .await
+            })
+        };
+
+        let leader = call();
+        let (_, envelope) = next_published(&rx).await;
+        let leader_corr_data = envelope["properties"]["correlation_data"].as_str().unwrap();
+
+        let follower = call();
+        let key = CallKey::new("room.read", &json!({ "id": 1 }), &destination()).unwrap();
+
+        while dispatcher.in_flight.followers(&key) == 0 {
+            tokio::task::yield_now().await;
+        }
+
+        assert!(published(&rx).is_empty());
+
+        leader.abort();
+        assert!(leader.await.unwrap_err().is_cancelled());
+
+        let (_, envelope) = next_published(&rx).await;
+        let corr_data = envelope["properties"]["correlation_data"].as_str().unwrap();
+        assert_ne!(corr_data, leader_corr_data);
+
+        dispatcher
+            .response(response(200, corr_data, json!({})))
+            .unwrap();
+        assert!(follower.await.unwrap().is_ok());
+    }
+}
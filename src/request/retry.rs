@@ -0,0 +1,139 @@
+use std::time::Duration;
+
+use crate::mqtt::ResponseStatus;
+
+/// Retry policy of [Dispatcher](struct.Dispatcher.html) calls.
+///
+/// The default policy makes a single attempt without a timeout which is the same as
+/// [Dispatcher::request](struct.Dispatcher.html#method.request) does.
+///
+/// # Example
+///
+/// ```
+/// let policy = RetryPolicy::new(3)
+///     .timeout(Duration::from_secs(5))
+///     .backoff(Duration::from_millis(100), Duration::from_secs(2))
+///     .retry_on_status(ResponseStatus::SERVICE_UNAVAILABLE)
+///     .retry_on_timeout(true);
+///
+/// let dispatcher = Dispatcher::new(&agent).retry_policy(policy);
+/// ```
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    max_attempts: u32,
+    timeout: Option<Duration>,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+    retryable_statuses: Vec<ResponseStatus>,
+    retry_on_timeout: bool,
+}
+
+impl RetryPolicy {
+    /// Builds a [RetryPolicy](struct.RetryPolicy.html).
+    ///
+    /// # Arguments
+    ///
+    /// * `max_attempts` – maximum number of attempts including the first one.
+    pub fn new(max_attempts: u32) -> Self {
+        Self {
+            max_attempts: max_attempts.max(1),
+            ..Default::default()
+        }
+    }
+
+    /// Sets a timeout for a single attempt to await for the response.
+    pub fn timeout(self, timeout: Duration) -> Self {
+        Self {
+            timeout: Some(timeout),
+            ..self
+        }
+    }
+
+    /// Sets an exponential backoff between attempts.
+    ///
+    /// The delay starts with `initial` and doubles after each attempt but never exceeds `max`.
+    pub fn backoff(self, initial: Duration, max: Duration) -> Self {
+        Self {
+            initial_backoff: initial,
+            max_backoff: max,
+            ..self
+        }
+    }
+
+    /// Makes responses with the `status` to be retried.
+    pub fn retry_on_status(mut self, status: ResponseStatus) -> Self {
+        self.retryable_statuses.push(status);
+        self
+    }
+
+    /// Sets whether to retry when the attempt's timeout expires.
+    pub fn retry_on_timeout(self, value: bool) -> Self {
+        Self {
+            retry_on_timeout: value,
+            ..self
+        }
+    }
+
+    pub fn max_attempts(&self) -> u32 {
+        self.max_attempts
+    }
+
+    pub fn attempt_timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+
+    pub(crate) fn is_retryable_status(&self, status: ResponseStatus) -> bool {
+        self.retryable_statuses.contains(&status)
+    }
+
+    pub(crate) fn is_retryable_timeout(&self) -> bool {
+        self.retry_on_timeout
+    }
+
+    /// Returns a delay before the next attempt after the `attempt` one.
+    pub(crate) fn backoff_delay(&self, attempt: u32) -> Duration {
+        let factor = 2u32.saturating_pow(attempt.saturating_sub(1));
+        self.initial_backoff
+            .checked_mul(factor)
+            .map_or(self.max_backoff, |delay| delay.min(self.max_backoff))
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 1,
+            timeout: None,
+            initial_backoff: Duration::from_secs(0),
+            max_backoff: Duration::from_secs(0),
+            retryable_statuses: vec![],
+            retry_on_timeout: false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn doubles_backoff_up_to_max() {
+        let policy =
+            RetryPolicy::new(5).backoff(Duration::from_millis(100), Duration::from_millis(300));
+
+        assert_eq!(policy.backoff_delay(1), Duration::from_millis(100));
+        assert_eq!(policy.backoff_delay(2), Duration::from_millis(200));
+        assert_eq!(policy.backoff_delay(3), Duration::from_millis(300));
+        assert_eq!(policy.backoff_delay(100), Duration::from_millis(300));
+    }
+
+    #[test]
+    fn retries_only_configured_outcomes() {
+        let policy = RetryPolicy::new(0).retry_on_status(ResponseStatus::SERVICE_UNAVAILABLE);
+
+        assert_eq!(policy.max_attempts(), 1);
+        assert!(policy.is_retryable_status(ResponseStatus::SERVICE_UNAVAILABLE));
+        assert!(!policy.is_retryable_status(ResponseStatus::INTERNAL_SERVER_ERROR));
+        assert!(!policy.is_retryable_timeout());
+    }
+}
@@ -0,0 +1,317 @@
+use std::time::Duration;
+
+use futures::{stream::FuturesUnordered, StreamExt};
+use serde::{de::DeserializeOwned, ser::Serialize};
+use tokio::time::Instant;
+use uuid::Uuid;
+
+use super::{convert_response, AttemptError, Dispatcher};
+use crate::{
+    mqtt::{
+        IncomingResponse, OutgoingRequest, OutgoingRequestProperties,
+        OutgoingShortTermTimingProperties,
+    },
+    AgentId, Authenticable, Destination, Error,
+};
+
+/// Defines when to stop gathering responses of a
+/// [scatter](struct.Dispatcher.html#method.scatter) request.
+///
+/// # Example
+///
+/// ```
+/// // Await for the majority of three instances but no longer than a second.
+/// let gather = GatherPolicy::new(Duration::from_secs(1)).quorum(2);
+/// ```
+#[derive(Debug, Clone)]
+pub struct GatherPolicy {
+    deadline: Duration,
+    quorum: Option<usize>,
+}
+
+impl GatherPolicy {
+    /// Builds a [GatherPolicy](struct.GatherPolicy.html) awaiting for all the targets.
+    ///
+    /// # Arguments
+    ///
+    /// * `deadline` – maximum time to await for responses.
+    pub fn new(deadline: Duration) -> Self {
+        Self {
+            deadline,
+            quorum: None,
+        }
+    }
+
+    /// Stops gathering when the given number of successful responses has been received.
+    pub fn quorum(self, quorum: usize) -> Self {
+        Self {
+            quorum: Some(quorum),
+            ..self
+        }
+    }
+}
+
+/// An outcome of a [scatter](struct.Dispatcher.html#method.scatter) request to a single target.
+#[derive(Debug)]
+#[allow(clippy::large_enum_variant)]
+pub enum TargetOutcome<T> {
+    /// The target has responded.
+    Responded(IncomingResponse<T>),
+    /// The request to the target has failed.
+    Failed(Error),
+    /// The deadline has passed before the target responded.
+    TimedOut,
+    /// The quorum has been reached before the target responded.
+    Abandoned,
+}
+
+/// Per target outcomes of a [scatter](struct.Dispatcher.html#method.scatter) request.
+#[derive(Debug)]
+pub struct ScatterResult<T> {
+    outcomes: Vec<(AgentId, TargetOutcome<T>)>,
+    quorum_reached: bool,
+}
+
+impl<T> ScatterResult<T> {
+    /// Returns outcomes in the same order as the targets have been passed.
+    pub fn outcomes(&self) -> &[(AgentId, TargetOutcome<T>)] {
+        &self.outcomes
+    }
+
+    pub fn into_outcomes(self) -> Vec<(AgentId, TargetOutcome<T>)> {
+        self.outcomes
+    }
+
+    /// Returns responses with successful status.
+    pub fn successful_responses(&self) -> impl Iterator<Item = (&AgentId, &IncomingResponse<T>)> {
+        self.outcomes
+            .iter()
+            .filter_map(|(agent_id, outcome)| match outcome {
+                TargetOutcome::Responded(resp) if resp.properties().status().is_success() => {
+                    Some((agent_id, resp))
+                }
+                _ => None,
+            })
+    }
+
+    /// Returns whether the quorum has been reached or all the targets have responded
+    /// successfully when no quorum is set.
+    pub fn is_quorum_reached(&self) -> bool {
+        self.quorum_reached
+    }
+}
+
+impl Dispatcher {
+    /// Makes the same request to multiple agents and gathers their responses.
+    ///
+    /// Each target gets a [unicast](../enum.Destination.html#variant.Unicast) request with its
+    /// own correlation data. Responses are being gathered until all of the targets have
+    /// responded, the [quorum](struct.GatherPolicy.html#method.quorum) of successful responses
+    /// is reached or the deadline passes. Late responses are dropped.
+    ///
+    /// # Arguments
+    ///
+    /// * `method` – request method.
+    /// * `payload` – any serializable value.
+    /// * `targets` – agents to send the request to.
+    /// * `version` – targets' API version.
+    /// * `gather` – [GatherPolicy](struct.GatherPolicy.html).
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let result = dispatcher
+    ///     .scatter::<_, JsonValue>(
+    ///         "room.stats",
+    ///         json!({ "id": room_id }),
+    ///         &instances,
+    ///         "v1",
+    ///         GatherPolicy::new(Duration::from_secs(1)),
+    ///     )
+    ///     .await?;
+    ///
+    /// for (agent_id, outcome) in result.outcomes() {
+    ///     println!("{}: {:?}", agent_id, outcome);
+    /// }
+    /// ```
+    pub async fn scatter<Req, Resp>(
+        &self,
+        method: &str,
+        payload: Req,
+        targets: &[AgentId],
+        version: &str,
+        gather: GatherPolicy,
+    ) -> Result<ScatterResult<Resp>, Error>
+    where
+        Req: Serialize,
+        Resp: DeserializeOwned,
+    {
+        let deadline = Instant::now() + gather.deadline;
+        let quorum = gather.quorum.unwrap_or(targets.len());
+        let mut attempts = FuturesUnordered::new();
+
+        for (idx, agent_id) in targets.iter().enumerate() {
+            let account_id = agent_id.as_account_id();
+            let response_topic = self.ensure_response_topic(account_id)?;
+            let correlation_data = Uuid::new_v4().to_string();
+            let now = chrono::Utc::now();
+
+            let mut props = OutgoingRequestProperties::new(
+                method,
+                &response_topic,
+                &correlation_data,
+                OutgoingShortTermTimingProperties::new(now),
+            );
+
+            props.set_local_timestamp(now);
+            let destination = Destination::Unicast(agent_id.to_owned(), version.to_owned());
+            let req = OutgoingRequest::new(&payload, props, destination);
+
+            attempts.push(async move { (idx, self.attempt(req, account_id, None).await) });
+        }
+
+        let mut outcomes = targets
+            .iter()
+            .map(|agent_id| (agent_id.to_owned(), None))
+            .collect::<Vec<(AgentId, Option<TargetOutcome<Resp>>)>>();
+
+        let mut successes = 0;
+        let mut timed_out = false;
+
+        while successes < quorum {
+            let (idx, result) = match tokio::time::timeout_at(deadline, attempts.next()).await {
+                Ok(Some(item)) => item,
+                Ok(None) => break,
+                Err(_) => {
+                    timed_out = true;
+                    break;
+                }
+            };
+
+            let outcome = match result.map(convert_response::<Resp>) {
+                Ok(Ok(resp)) => {
+                    if resp.properties().status().is_success() {
+                        successes += 1;
+                    }
+
+                    TargetOutcome::Responded(resp)
+                }
+                Ok(Err(err)) | Err(AttemptError::Failed(err)) => TargetOutcome::Failed(err),
+                Err(AttemptError::Timeout) => TargetOutcome::TimedOut,
+            };
+
+            outcomes[idx].1 = Some(outcome);
+        }
+
+        // Dropping the rest of attempts retires their correlation data.
+        drop(attempts);
+
+        let outcomes = outcomes
+            .into_iter()
+            .map(|(agent_id, outcome)| {
+                let outcome = outcome.unwrap_or(if timed_out {
+                    TargetOutcome::TimedOut
+                } else {
+                    TargetOutcome::Abandoned
+                });
+
+                (agent_id, outcome)
+            })
+            .collect();
+
+        Ok(ScatterResult {
+            outcomes,
+            quorum_reached: successes >= quorum,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::{
+        mqtt::{
+            testing::{next_published, response},
+            Agent,
+        },
+        AccountId,
+    };
+
+    fn targets() -> Vec<AgentId> {
+        let account_id = AccountId::new("sender", "svc.example.org");
+
+        ["instance01", "instance02", "instance03"]
+            .iter()
+            .map(|&label| AgentId::new(label, account_id.clone()))
+            .collect()
+    }
+
+    async fn scatter(gather: GatherPolicy, respond_to: &[usize]) -> ScatterResult<Value> {
+        let (agent, rx) = Agent::stub();
+        let dispatcher = Arc::new(Dispatcher::new(&agent));
+        let targets = targets();
+
+        let scatter = tokio::spawn({
+            let dispatcher = dispatcher.clone();
+            let targets = targets.clone();
+
+            async move {
+                dispatcher
+                    .scatter::<_, Value>("room.stats", json!({}), &targets, "v1", gather)
+                    .await
+            }
+        });
+
+        let mut correlation_data = vec![String::new(); targets.len()];
+
+        for _ in 0..targets.len() {
+            let (topic, envelope) = next_published(&rx).await;
+            let corr_data = envelope["properties"]["correlation_data"].as_str().unwrap();
+
+            let idx = targets
+                .iter()
+                .position(|agent_id| topic.starts_with(&format!("agents/{}/", agent_id)))
+                .unwrap();
+
+            correlation_data[idx] = corr_data.to_owned();
+        }
+
+        for &idx in respond_to {
+            let resp = response(200, &correlation_data[idx], json!({}));
+            dispatcher.response(resp).unwrap();
+        }
+
+        scatter.await.unwrap().unwrap()
+    }
+
+    #[tokio::test]
+    async fn abandons_targets_after_quorum() {
+        let gather = GatherPolicy::new(Duration::from_secs(60)).quorum(2);
+        let result = scatter(gather, &[0, 2]).await;
+
+        assert!(result.is_quorum_reached());
+        assert_eq!(result.successful_responses().count(), 2);
+
+        let outcomes = result.outcomes();
+        assert!(matches!(outcomes[0].1, TargetOutcome::Responded(_)));
+        assert!(matches!(outcomes[1].1, TargetOutcome::Abandoned));
+        assert!(matches!(outcomes[2].1, TargetOutcome::Responded(_)));
+    }
+
+    #[tokio::test]
+    async fn times_out_targets_after_deadline() {
+        let gather = GatherPolicy::new(Duration::from_millis(50));
+        let result = scatter(gather, &[1]).await;
+
+        assert!(!result.is_quorum_reached());
+
+        let outcomes = result.outcomes();
+        assert!(matches!(outcomes[0].1, TargetOutcome::TimedOut));
+        assert!(matches!(outcomes[1].1, TargetOutcome::Responded(_)));
+        assert!(matches!(outcomes[2].1, TargetOutcome::TimedOut));
+    }
+}
@@ -0,0 +1,355 @@
+use std::{
+    collections::{hash_map::RandomState, HashMap, HashSet, VecDeque},
+    hash::BuildHasher,
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+    time::{Duration, Instant},
+};
+
+use log::{debug, error};
+use tokio::sync::{mpsc, oneshot};
+
+use crate::{
+    mqtt::{IncomingPayload, IncomingResponse, ResponseStatus},
+    Error,
+};
+
+/// Number of independently locked shards of the store.
+const SHARDS: usize = 32;
+/// How long to remember correlation data of requests that are not awaited anymore.
+const RETIRED_TTL: Duration = Duration::from_secs(300);
+/// Maximum number of remembered correlation data of requests that are not awaited anymore
+/// per shard.
+const RETIRED_CAPACITY: usize = 10_000 / SHARDS;
+
+/// Responses are passed to the awaiting side with the raw payload which gets deserialized
+/// only once straight into the type requested by the caller.
+pub(super) type RawResponse = IncomingResponse<IncomingPayload>;
+
+pub(super) enum ResponseSender {
+    Once(oneshot::Sender<RawResponse>),
+    Stream(mpsc::UnboundedSender<RawResponse>),
+}
+
+impl ResponseSender {
+    fn send(self, resp: RawResponse) -> Result<(), Error> {
+        let corr_data = resp.properties().correlation_data().to_owned();
+
+        let result = match self {
+            Self::Once(tx) => tx.send(resp).map_err(|_| ()),
+            Self::Stream(tx) => tx.send(resp).map_err(|_| ()),
+        };
+
+        result.map_err(|()| {
+            Error::new(&format!(
+                "Failed to commit response with correlation data = '{}': receiver has been dropped",
+                corr_data,
+            ))
+        })
+    }
+}
+
+/// Returns whether the response is the last one for the request.
+///
+/// Long operations may be responded with a number of `202 Accepted` progress responses
+/// before the final one.
+fn is_final_status(status: ResponseStatus) -> bool {
+    !(status.is_informational() || status == ResponseStatus::ACCEPTED)
+}
+
+#[derive(Default)]
+struct Shard {
+    awaiting: HashMap<String, ResponseSender>,
+    // Correlation data of timed out, retried or cancelled requests.
+    // Late responses to them get dropped silently.
+    retired: HashSet<String>,
+    retired_queue: VecDeque<(Instant, String)>,
+}
+
+impl Shard {
+    fn retire(&mut self, corr_data: &str) {
+        self.awaiting.remove(corr_data);
+
+        if self.retired.insert(corr_data.to_owned()) {
+            self.retired_queue
+                .push_back((Instant::now(), corr_data.to_owned()));
+        }
+
+        while let Some((retired_at, _)) = self.retired_queue.front() {
+            if retired_at.elapsed() < RETIRED_TTL && self.retired_queue.len() <= RETIRED_CAPACITY {
+                break;
+            }
+
+            if let Some((_, corr_data)) = self.retired_queue.pop_front() {
+                self.retired.remove(&corr_data);
+            }
+        }
+    }
+}
+
+struct Shards {
+    hasher: RandomState,
+    shards: Vec<Mutex<Shard>>,
+}
+
+impl Default for Shards {
+    fn default() -> Self {
+        Self {
+            hasher: RandomState::new(),
+            shards: (0..SHARDS).map(|_| Mutex::default()).collect(),
+        }
+    }
+}
+
+/// Responses being awaited by dispatchers.
+///
+/// It's shared between the agent's event loop and the dispatchers built for the agent
+/// when the agent is started [with dispatcher](../mqtt/struct.AgentBuilder.html#method.with_dispatcher).
+///
+/// Correlation data is spread over a number of shards each guarded by its own lock so
+/// concurrent requests rarely contend. A shard never gets left in an inconsistent state
+/// while locked so the store keeps working even if some thread has panicked holding the lock.
+#[derive(Clone, Default)]
+pub(crate) struct PendingResponses(Arc<Shards>);
+
+impl PendingResponses {
+    fn shard(&self, corr_data: &str) -> MutexGuard<'_, Shard> {
+        let index = self.0.hasher.hash_one(corr_data) as usize % self.0.shards.len();
+
+        self.0.shards[index]
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub(super) fn register(&self, corr_data: &str) -> Result<PendingResponse, Error> {
+        let (tx, rx) = oneshot::channel::<RawResponse>();
+        self.insert(corr_data, ResponseSender::Once(tx))?;
+
+        Ok(PendingResponse {
+            store: self.clone(),
+            corr_data: corr_data.to_owned(),
+            rx,
+        })
+    }
+
+    pub(super) fn insert(&self, corr_data: &str, tx: ResponseSender) -> Result<(), Error> {
+        let mut shard = self.shard(corr_data);
+
+        if shard.awaiting.contains_key(corr_data) {
+            let err = format!(
+                "Already awaiting response with correlation data = '{}'",
+                corr_data
+            );
+
+            return Err(Error::new(&err));
+        }
+
+        if shard.retired.remove(corr_data) {
+            shard
+                .retired_queue
+                .retain(|(_, retired)| retired != corr_data);
+        }
+
+        shard.awaiting.insert(corr_data.to_owned(), tx);
+        Ok(())
+    }
+
+    /// Returns the sender of a response with the given properties.
+    ///
+    /// Streamed requests keep being awaited until the final response.
+    fn take(
+        &self,
+        corr_data: &str,
+        status: ResponseStatus,
+    ) -> Result<Option<ResponseSender>, Error> {
+        let mut shard = self.shard(corr_data);
+
+        if let Some(ResponseSender::Stream(tx)) = shard.awaiting.get(corr_data) {
+            if !is_final_status(status) {
+                return Ok(Some(ResponseSender::Stream(tx.clone())));
+            }
+        }
+
+        match shard.awaiting.remove(corr_data) {
+            Some(tx) => Ok(Some(tx)),
+            None if shard.retired.contains(corr_data) => Ok(None),
+            None => Err(Error::new(&format!(
+                "Failed to commit response with correlation data = '{}': not being awaited",
+                corr_data
+            ))),
+        }
+    }
+
+    pub(super) fn retire(&self, corr_data: &str) {
+        self.shard(corr_data).retire(corr_data);
+    }
+
+    /// Retires the correlation data only if it's being awaited.
+    pub(super) fn cancel(&self, corr_data: &str) -> Result<(), Error> {
+        let mut shard = self.shard(corr_data);
+
+        if !shard.awaiting.contains_key(corr_data) {
+            return Err(Error::new(&format!(
+                "Failed to cancel request; response with correlation data = '{}' is not being awaited",
+                corr_data
+            )));
+        }
+
+        shard.retire(corr_data);
+        Ok(())
+    }
+
+    /// Passes the response to the awaiting side.
+    ///
+    /// Returns `Ok(false)` for a late response to a request that is not awaited anymore.
+    pub(super) fn send(&self, resp: RawResponse) -> Result<bool, Error> {
+        let props = resp.properties();
+
+        match self.take(props.correlation_data(), props.status())? {
+            Some(tx) => tx.send(resp).map(|()| true),
+            None => {
+                debug!(
+                    "Dropped late response with correlation data = '{}'",
+                    resp.properties().correlation_data()
+                );
+
+                Ok(false)
+            }
+        }
+    }
+
+    /// Commits the response to the awaiting dispatcher.
+    ///
+    /// Gives the response back if it's not being awaited so it could be handled elsewhere.
+    /// Late responses to requests that are not awaited anymore are dropped.
+    pub(crate) fn commit(&self, resp: RawResponse) -> Option<RawResponse> {
+        let props = resp.properties();
+
+        let tx = match self.take(props.correlation_data(), props.status()) {
+            Ok(Some(tx)) => tx,
+            Ok(None) => {
+                debug!(
+                    "Dropped late response with correlation data = '{}'",
+                    resp.properties().correlation_data()
+                );
+
+                return None;
+            }
+            Err(_) => return Some(resp),
+        };
+
+        if let Err(err) = tx.send(resp) {
+            error!("{}", err);
+        }
+
+        None
+    }
+}
+
+/// A response being awaited.
+///
+/// Retires its correlation data on drop so a late response doesn't get reported as unexpected.
+pub(super) struct PendingResponse {
+    store: PendingResponses,
+    corr_data: String,
+    rx: oneshot::Receiver<RawResponse>,
+}
+
+impl PendingResponse {
+    pub(super) async fn wait(
+        mut self,
+        timeout: Option<Duration>,
+    ) -> Result<RawResponse, AttemptError> {
+        let result = match timeout {
+            Some(timeout) => tokio::time::timeout(timeout, &mut self.rx)
+                .await
+                .map_err(|_| AttemptError::Timeout)?,
+            None => (&mut self.rx).await,
+        };
+
+        result.map_err(|err| {
+            AttemptError::Failed(Error::new(&format!("Failed to receive response: {}", err)))
+        })
+    }
+}
+
+impl Drop for PendingResponse {
+    fn drop(&mut self) {
+        self.store.retire(&self.corr_data);
+    }
+}
+
+pub(super) enum AttemptError {
+    Timeout,
+    Failed(Error),
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::mqtt::testing::response;
+
+    #[tokio::test]
+    async fn commits_awaited_response() {
+        let store = PendingResponses::default();
+        let pending = store.register("corr").unwrap();
+
+        assert!(store.commit(response(200, "corr", json!({}))).is_none());
+
+        let resp = pending.wait(None).await.ok().unwrap();
+        assert_eq!(resp.properties().correlation_data(), "corr");
+    }
+
+    #[test]
+    fn gives_back_unexpected_response() {
+        let store = PendingResponses::default();
+        let resp = store.commit(response(200, "corr", json!({})));
+        assert_eq!(resp.unwrap().properties().correlation_data(), "corr");
+    }
+
+    #[test]
+    fn drops_late_response() {
+        let store = PendingResponses::default();
+        drop(store.register("corr").unwrap());
+
+        assert!(store.commit(response(200, "corr", json!({}))).is_none());
+        assert!(store.commit(response(200, "corr", json!({}))).is_none());
+    }
+
+    #[test]
+    fn rejects_awaiting_same_correlation_data_twice() {
+        let store = PendingResponses::default();
+        let _pending = store.register("corr").unwrap();
+        assert!(store.register("corr").is_err());
+    }
+
+    #[tokio::test]
+    async fn awaits_retired_correlation_data_again() {
+        let store = PendingResponses::default();
+        drop(store.register("corr").unwrap());
+
+        let pending = store.register("corr").unwrap();
+        assert!(store.commit(response(200, "corr", json!({}))).is_none());
+        assert!(pending.wait(None).await.is_ok());
+    }
+
+    #[test]
+    fn spreads_correlation_data_over_shards() {
+        let store = PendingResponses::default();
+
+        let pending = (0..SHARDS * 4)
+            .map(|idx| store.register(&idx.to_string()).unwrap())
+            .collect::<Vec<_>>();
+
+        let used = store
+            .0
+            .shards
+            .iter()
+            .filter(|shard| !shard.lock().unwrap().awaiting.is_empty())
+            .count();
+
+        assert!(used > 1);
+        drop(pending);
+    }
+}
@@ -0,0 +1,186 @@
+use std::{
+    future::Future,
+    marker::PhantomData,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use futures::Stream;
+use serde::de::DeserializeOwned;
+use tokio::{
+    sync::mpsc,
+    time::{Instant, Sleep},
+};
+
+use super::{convert_response, PendingResponses, RawResponse};
+use crate::{mqtt::IncomingResponse, Error};
+
+/// A stream of responses to a single request.
+///
+/// See [Dispatcher::request_stream](struct.Dispatcher.html#method.request_stream) for details.
+pub struct ResponseStream<T> {
+    store: PendingResponses,
+    corr_data: String,
+    rx: mpsc::UnboundedReceiver<RawResponse>,
+    timeout: Option<Duration>,
+    deadline: Option<Instant>,
+    sleep: Option<Pin<Box<Sleep>>>,
+    is_terminated: bool,
+    payload_type: PhantomData<fn() -> T>,
+}
+
+impl<T> ResponseStream<T> {
+    pub(super) fn new(
+        store: PendingResponses,
+        corr_data: &str,
+        rx: mpsc::UnboundedReceiver<RawResponse>,
+        timeout: Option<Duration>,
+        deadline: Option<Instant>,
+    ) -> Self {
+        Self {
+            store,
+            corr_data: corr_data.to_owned(),
+            rx,
+            timeout,
+            deadline,
+            sleep: wake_up_at(timeout, deadline).map(|at| Box::pin(tokio::time::sleep_until(at))),
+            is_terminated: false,
+            payload_type: PhantomData,
+        }
+    }
+
+    /// Returns correlation data of the request.
+    pub fn correlation_data(&self) -> &str {
+        &self.corr_data
+    }
+}
+
+impl<T: DeserializeOwned> Stream for ResponseStream<T> {
+    type Item = Result<IncomingResponse<T>, Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if self.is_terminated {
+            return Poll::Ready(None);
+        }
+
+        match self.rx.poll_recv(cx) {
+            Poll::Ready(Some(resp)) => {
+                // The timeout restarts with each response but never goes past the deadline.
+                if let Some(at) = wake_up_at(self.timeout, self.deadline) {
+                    if let Some(ref mut sleep) = self.sleep {
+                        sleep.as_mut().reset(at);
+                    }
+                }
+
+                return Poll::Ready(Some(convert_response(resp)));
+            }
+            Poll::Ready(None) => {
+                // The final response has been received or the request has been cancelled.
+                self.is_terminated = true;
+                return Poll::Ready(None);
+            }
+            Poll::Pending => (),
+        }
+
+        if let Some(ref mut sleep) = self.sleep {
+            if sleep.as_mut().poll(cx).is_ready() {
+                self.is_terminated = true;
+                self.store.retire(&self.corr_data);
+
+                let err = Error::new(&format!(
+                    "Timed out awaiting response with correlation data = '{}'",
+                    self.corr_data
+                ));
+
+                return Poll::Ready(Some(Err(err)));
+            }
+        }
+
+        Poll::Pending
+    }
+}
+
+/// Returns the time to stop awaiting the next response at.
+fn wake_up_at(timeout: Option<Duration>, deadline: Option<Instant>) -> Option<Instant> {
+    let next = timeout.map(|timeout| Instant::now() + timeout);
+
+    match (next, deadline) {
+        (Some(next), Some(deadline)) => Some(next.min(deadline)),
+        (next, deadline) => next.or(deadline),
+    }
+}
+
+impl<T> Drop for ResponseStream<T> {
+    fn drop(&mut self) {
+        self.store.retire(&self.corr_data);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::StreamExt;
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::{mqtt::testing::response, request::store::ResponseSender};
+
+    fn stream(store: &PendingResponses, timeout: Option<Duration>) -> ResponseStream<Value> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        store.insert("corr", ResponseSender::Stream(tx)).unwrap();
+        ResponseStream::new(store.clone(), "corr", rx, timeout, None)
+    }
+
+    #[tokio::test]
+    async fn ends_after_final_response() {
+        let store = PendingResponses::default();
+        let mut stream = stream(&store, None);
+
+        for status in [202, 202, 200] {
+            assert!(store.commit(response(status, "corr", json!({}))).is_none());
+        }
+
+        for status in [202, 202, 200] {
+            let resp = stream.next().await.unwrap().unwrap();
+            assert_eq!(resp.properties().status(), status);
+        }
+
+        assert!(stream.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn times_out_awaiting_next_response() {
+        let store = PendingResponses::default();
+        let mut stream = stream(&store, Some(Duration::from_millis(10)));
+
+        store.commit(response(202, "corr", json!({})));
+        assert!(stream.next().await.unwrap().is_ok());
+        assert!(stream.next().await.unwrap().is_err());
+        assert!(stream.next().await.is_none());
+
+        // Late responses are dropped rather than given back as unexpected.
+        assert!(store.commit(response(200, "corr", json!({}))).is_none());
+    }
+
+    #[tokio::test]
+    async fn ends_on_cancellation() {
+        let store = PendingResponses::default();
+        let mut stream = stream(&store, None);
+
+        store.cancel("corr").unwrap();
+        assert!(stream.next().await.is_none());
+    }
+
+    #[test]
+    fn wakes_up_at_deadline_before_timeout() {
+        let deadline = Instant::now() + Duration::from_secs(1);
+
+        assert_eq!(wake_up_at(None, Some(deadline)), Some(deadline));
+        assert_eq!(
+            wake_up_at(Some(Duration::from_secs(60)), Some(deadline)),
+            Some(deadline)
+        );
+        assert!(wake_up_at(Some(Duration::from_millis(1)), Some(deadline)).unwrap() < deadline);
+        assert_eq!(wake_up_at(None, None), None);
+    }
+}
@@ -0,0 +1,265 @@
+use std::{
+    future::Future,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use futures::future::{self, BoxFuture, FutureExt};
+use log::warn;
+use serde::de::DeserializeOwned;
+
+use crate::{
+    mqtt::{topic_matches, IncomingEvent, IncomingPayload},
+    Error,
+};
+
+type Handler = Box<
+    dyn Fn(IncomingEvent<IncomingPayload>) -> BoxFuture<'static, Result<(), Error>> + Send + Sync,
+>;
+
+struct Route {
+    label: String,
+    topic_pattern: Option<String>,
+    handler: Handler,
+}
+
+impl Route {
+    fn matches(&self, label: &str, topic: &str) -> bool {
+        let is_topic_matching = match self.topic_pattern {
+            Some(ref pattern) => topic_matches(pattern, topic),
+            None => true,
+        };
+
+        self.label == label && is_topic_matching
+    }
+}
+
+/// Dispatches incoming events to handlers by the event label.
+///
+/// Handlers are async functions taking [IncomingEvent](../mqtt/type.IncomingEvent.html) with
+/// the payload of the type they expect. A route may be scoped by a topic pattern with `+` and
+/// `#` wildcards. Routes scoped by a topic take precedence over unscoped ones of the same label.
+/// Otherwise the first registered route wins.
+///
+/// Events with no matching route are counted and logged.
+///
+/// # Example
+///
+/// ```
+/// let router = EventRouter::new()
+///     .on("room.close", |event: IncomingEvent<RoomClose>| async move {
+///         close_room(event.payload().id).await
+///     })
+///     .on_topic(
+///         "message.create",
+///         "apps/conference.svc.example.org/api/v1/rooms/+/events",
+///         |event: IncomingEvent<Message>| async move { store_message(event.payload()).await },
+///     );
+///
+/// while let Some(notification) = rx.recv().await {
+///     if let AgentNotification::Message(Ok(IncomingMessage::Event(event)), data) = notification {
+///         if let Err(err) = router.route(event, &data.topic).await {
+///             error!("Failed to handle event: {}", err);
+///         }
+///     }
+/// }
+/// ```
+#[derive(Default)]
+pub struct EventRouter {
+    routes: Vec<Route>,
+    unmatched: AtomicU64,
+}
+
+impl EventRouter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a route for events with the `label`.
+    ///
+    /// # Arguments
+    ///
+    /// * `label` – event label.
+    /// * `handler` – async function taking an event with `T` payload.
+    pub fn on<T, F, Fut>(self, label: &str, handler: F) -> Self
+    where
+        T: DeserializeOwned + 'static,
+        F: Fn(IncomingEvent<T>) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<(), Error>> + Send + 'static,
+    {
+        self.add_route(label, None, handler)
+    }
+
+    /// Adds a route for events with the `label` and topic matching the `topic_pattern`.
+    ///
+    /// # Arguments
+    ///
+    /// * `label` – event label.
+    /// * `topic_pattern` – MQTT topic filter which may contain `+` and `#` wildcards.
+    /// * `handler` – async function taking an event with `T` payload.
+    pub fn on_topic<T, F, Fut>(self, label: &str, topic_pattern: &str, handler: F) -> Self
+    where
+        T: DeserializeOwned + 'static,
+        F: Fn(IncomingEvent<T>) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<(), Error>> + Send + 'static,
+    {
+        self.add_route(label, Some(topic_pattern), handler)
+    }
+
+    fn add_route<T, F, Fut>(mut self, label: &str, topic_pattern: Option<&str>, handler: F) -> Self
+    where
+        T: DeserializeOwned + 'static,
+        F: Fn(IncomingEvent<T>) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<(), Error>> + Send + 'static,
+    {
+        let handler =
+            move |event: IncomingEvent<IncomingPayload>| match IncomingEvent::convert::<T>(event) {
+                Ok(event) => handler(event).boxed(),
+                Err(err) => future::ready(Err(err)).boxed(),
+            };
+
+        self.routes.push(Route {
+            label: label.to_owned(),
+            topic_pattern: topic_pattern.map(ToOwned::to_owned),
+            handler: Box::new(handler),
+        });
+
+        self
+    }
+
+    /// Passes the event received on the `topic` to the matching handler.
+    ///
+    /// Returns the handler's error or an error of converting the event payload.
+    /// An event with no matching route is not considered an error.
+    pub async fn route(
+        &self,
+        event: IncomingEvent<IncomingPayload>,
+        topic: &str,
+    ) -> Result<(), Error> {
+        let label = event.properties().label().unwrap_or_default();
+
+        let route = self
+            .routes
+            .iter()
+            .find(|route| route.topic_pattern.is_some() && route.matches(label, topic))
+            .or_else(|| {
+                self.routes
+                    .iter()
+                    .find(|route| route.topic_pattern.is_none() && route.matches(label, topic))
+            });
+
+        match route {
+            Some(route) => (route.handler)(event).await,
+            None => {
+                self.unmatched.fetch_add(1, Ordering::Relaxed);
+
+                warn!(
+                    "No route for event with label = '{}' on topic = '{}'",
+                    label, topic
+                );
+
+                Ok(())
+            }
+        }
+    }
+
+    /// Returns the number of events with no matching route.
+    pub fn unmatched_count(&self) -> u64 {
+        self.unmatched.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use serde::Deserialize;
+    use serde_json::json;
+
+    use super::*;
+    use crate::mqtt::testing::event;
+
+    const TOPIC: &str = "apps/sender.svc.example.org/api/v1/rooms/123/events";
+
+    #[derive(Deserialize)]
+    struct Room {
+        id: u64,
+    }
+
+    /// Builds a router recording names of the handlers called.
+    fn router(calls: &Arc<Mutex<Vec<&'static str>>>) -> EventRouter {
+        let record = |name: &'static str| {
+            let calls = calls.clone();
+
+            move |event: IncomingEvent<Room>| {
+                calls.lock().unwrap().push(name);
+                let result = if event.payload().id > 0 {
+                    Ok(())
+                } else {
+                    Err(Error::new("zero id"))
+                };
+                async move { result }
+            }
+        };
+
+        EventRouter::new()
+            .on("room.update", record("unscoped"))
+            .on_topic(
+                "room.update",
+                "apps/sender.svc.example.org/api/v1/rooms/+/events",
+                record("scoped"),
+            )
+            .on("room.close", record("first"))
+            .on("room.close", record("second"))
+    }
+
+    #[tokio::test]
+    async fn prefers_routes_scoped_by_topic() {
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let router = router(&calls);
+
+        let event = event("room.update", json!({ "id": 1 }));
+        router.route(event.clone(), TOPIC).await.unwrap();
+        router
+            .route(event, "apps/sender.svc.example.org/api/v1/users")
+            .await
+            .unwrap();
+
+        assert_eq!(*calls.lock().unwrap(), vec!["scoped", "unscoped"]);
+    }
+
+    #[tokio::test]
+    async fn picks_first_registered_route() {
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let router = router(&calls);
+
+        let event = event("room.close", json!({ "id": 1 }));
+        router.route(event, TOPIC).await.unwrap();
+
+        assert_eq!(*calls.lock().unwrap(), vec!["first"]);
+    }
+
+    #[tokio::test]
+    async fn returns_handler_and_conversion_errors() {
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let router = router(&calls);
+
+        let rejected = event("room.close", json!({ "id": 0 }));
+        assert!(router.route(rejected, TOPIC).await.is_err());
+
+        let invalid = event("room.close", json!({ "id": "invalid" }));
+        assert!(router.route(invalid, TOPIC).await.is_err());
+        assert_eq!(calls.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn counts_unmatched_events() {
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let router = router(&calls);
+
+        let event = event("room.create", json!({ "id": 1 }));
+        router.route(event, TOPIC).await.unwrap();
+
+        assert_eq!(router.unmatched_count(), 1);
+        assert!(calls.lock().unwrap().is_empty());
+    }
+}
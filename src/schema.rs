@@ -0,0 +1,379 @@
+use std::collections::BTreeMap;
+
+use jsonschema::Validator;
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::{
+    mqtt::{IncomingEvent, IncomingPayload, IncomingRequest, OutgoingMessage},
+    Error,
+};
+
+struct Schema {
+    schema: Value,
+    validator: Validator,
+}
+
+impl Schema {
+    fn new(schema: Value) -> Result<Self, Error> {
+        let validator = jsonschema::validator_for(&schema)
+            .map_err(|e| Error::new(&format!("invalid JSON schema, {}", e)))?;
+
+        Ok(Self { schema, validator })
+    }
+
+    fn validate(&self, instance: &Value) -> Result<(), SchemaViolations> {
+        let violations = self
+            .validator
+            .iter_errors(instance)
+            .map(|err| SchemaViolation {
+                path: err.instance_path.to_string(),
+                message: err.to_string(),
+            })
+            .collect::<Vec<_>>();
+
+        if violations.is_empty() {
+            Ok(())
+        } else {
+            Err(SchemaViolations { violations })
+        }
+    }
+}
+
+/// A single mismatch of a payload against its schema.
+#[derive(Debug, Clone, Serialize)]
+pub struct SchemaViolation {
+    path: String,
+    message: String,
+}
+
+impl SchemaViolation {
+    /// JSON pointer to the offending value within the payload.
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+}
+
+/// Schema violations of a payload.
+///
+/// 422 responses to invalid requests list them in the `errors` field of the error payload.
+#[derive(Debug, Clone, Serialize)]
+pub struct SchemaViolations {
+    violations: Vec<SchemaViolation>,
+}
+
+impl SchemaViolations {
+    fn single(message: &str) -> Self {
+        Self {
+            violations: vec![SchemaViolation {
+                path: String::new(),
+                message: message.to_owned(),
+            }],
+        }
+    }
+
+    pub fn violations(&self) -> &[SchemaViolation] {
+        &self.violations
+    }
+}
+
+impl std::fmt::Display for SchemaViolations {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let violations = self
+            .violations
+            .iter()
+            .map(|violation| format!("'{}': {}", violation.path, violation.message))
+            .collect::<Vec<_>>();
+
+        write!(f, "{}", violations.join(", "))
+    }
+}
+
+/// JSON schemas of request payloads by method and event payloads by label.
+///
+/// Attach it to the agent with
+/// [AgentBuilder::schemas](../mqtt/struct.AgentBuilder.html#method.schemas) to validate
+/// incoming messages before they get to the application. Invalid requests get answered
+/// with `422 Unprocessable Entity` listing the [violations](struct.SchemaViolations.html).
+/// Invalid events are logged and dropped. Messages with no registered schema pass as is.
+///
+/// # Example
+///
+/// ```
+/// let schemas = SchemaRegistry::new()
+///     .request("room.enter", json!({
+///         "type": "object",
+///         "properties": { "id": { "type": "string", "format": "uuid" } },
+///         "required": ["id"],
+///     }))?
+///     .event("room.close", json!({ "type": "object", "required": ["id"] }))?
+///     .validate_outgoing(true);
+/// ```
+#[derive(Default)]
+pub struct SchemaRegistry {
+    requests: BTreeMap<String, Schema>,
+    events: BTreeMap<String, Schema>,
+    validate_outgoing: bool,
+}
+
+impl std::fmt::Debug for SchemaRegistry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SchemaRegistry")
+            .field("requests", &self.requests.keys().collect::<Vec<_>>())
+            .field("events", &self.events.keys().collect::<Vec<_>>())
+            .field("validate_outgoing", &self.validate_outgoing)
+            .finish()
+    }
+}
+
+impl SchemaRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers a JSON schema for payloads of requests with the `method`.
+    pub fn request(mut self, method: &str, schema: Value) -> Result<Self, Error> {
+        self.requests
+            .insert(method.to_owned(), Schema::new(schema)?);
+        Ok(self)
+    }
+
+    /// Registers a JSON schema for payloads of events with the `label`.
+    pub fn event(mut self, label: &str, schema: Value) -> Result<Self, Error> {
+        self.events.insert(label.to_owned(), Schema::new(schema)?);
+        Ok(self)
+    }
+
+    /// Validates payloads of published requests and events as well.
+    ///
+    /// It's only effective in debug builds to catch mistakes in development.
+    /// Publishing a message with an invalid payload fails then.
+    pub fn validate_outgoing(self, validate_outgoing: bool) -> Self {
+        Self {
+            validate_outgoing,
+            ..self
+        }
+    }
+
+    /// Returns the schema registered for the request `method`.
+    pub fn request_schema(&self, method: &str) -> Option<&Value> {
+        self.requests.get(method).map(|schema| &schema.schema)
+    }
+
+    /// Returns the schema registered for the event `label`.
+    pub fn event_schema(&self, label: &str) -> Option<&Value> {
+        self.events.get(label).map(|schema| &schema.schema)
+    }
+
+    /// Exports all registered schemas for API documentation.
+    ///
+    /// The result looks like `{"requests": {"<method>": <schema>}, "events": {"<label>": <schema>}}`.
+    pub fn export(&self) -> Value {
+        let export = |schemas: &BTreeMap<String, Schema>| {
+            schemas
+                .iter()
+                .map(|(key, schema)| (key.to_owned(), schema.schema.to_owned()))
+                .collect::<serde_json::Map<_, _>>()
+        };
+
+        serde_json::json!({
+            "requests": export(&self.requests),
+            "events": export(&self.events),
+        })
+    }
+
+    /// Validates the payload of an incoming request against the schema of its method.
+    pub fn validate_request(
+        &self,
+        request: &IncomingRequest<IncomingPayload>,
+    ) -> Result<(), SchemaViolations> {
+        match self.requests.get(request.properties().method()) {
+            Some(schema) => {
+                let codec = request
+                    .properties()
+                    .codec()
+                    .map_err(|e| SchemaViolations::single(&e.to_string()))?;
+
+                let payload = codec
+                    .decode::<Value>(request.payload())
+                    .map_err(|e| SchemaViolations::single(&e.to_string()))?;
+
+                schema.validate(&payload)
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Validates the payload of an incoming event against the schema of its label.
+    pub fn validate_event(
+        &self,
+        event: &IncomingEvent<IncomingPayload>,
+    ) -> Result<(), SchemaViolations> {
+        let label = event.properties().label().unwrap_or_default();
+
+        match self.events.get(label) {
+            Some(schema) => {
+                let codec = event
+                    .properties()
+                    .codec()
+                    .map_err(|e| SchemaViolations::single(&e.to_string()))?;
+
+                let payload = codec
+                    .decode::<Value>(event.payload())
+                    .map_err(|e| SchemaViolations::single(&e.to_string()))?;
+
+                schema.validate(&payload)
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Validates the payload of an outgoing request or event if enabled in debug builds.
+    pub(crate) fn validate_outgoing_message<T: Serialize>(
+        &self,
+        message: &OutgoingMessage<T>,
+    ) -> Result<(), Error> {
+        if !cfg!(debug_assertions) || !self.validate_outgoing {
+            return Ok(());
+        }
+
+        let (kind, key, schema, payload) = match message {
+            OutgoingMessage::Request(req) => {
+                let method = req.properties.method();
+                ("request", method, self.requests.get(method), &req.payload)
+            }
+            OutgoingMessage::Event(event) => {
+                let label = event.properties.label();
+                ("event", label, self.events.get(label), &event.payload)
+            }
+            OutgoingMessage::Response(_) => return Ok(()),
+        };
+
+        if let Some(schema) = schema {
+            let payload = serde_json::to_value(payload)
+                .map_err(|e| Error::new(&format!("error serializing payload, {}", e)))?;
+
+            schema.validate(&payload).map_err(|violations| {
+                Error::new(&format!(
+                    "payload of outgoing {} = '{}' doesn't match the schema: {}",
+                    kind, key, violations
+                ))
+            })?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::mqtt::{
+        testing::{event, packet, receive, request},
+        IncomingMessage, OutgoingEvent, OutgoingEventProperties, OutgoingShortTermTimingProperties,
+    };
+
+    fn registry() -> SchemaRegistry {
+        let room = json!({
+            "type": "object",
+            "properties": { "id": { "type": "string" } },
+            "required": ["id"],
+        });
+
+        SchemaRegistry::new()
+            .request("room.enter", room.clone())
+            .unwrap()
+            .event("room.close", room)
+            .unwrap()
+    }
+
+    #[test]
+    fn rejects_invalid_schema() {
+        let result = SchemaRegistry::new().request("room.enter", json!({ "type": 42 }));
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn validates_request_payload() {
+        let schemas = registry();
+        let valid = request("room.enter", "corr", json!({ "id": "123" }));
+        assert!(schemas.validate_request(&valid).is_ok());
+
+        let invalid = request("room.enter", "corr", json!({ "id": 123 }));
+        let violations = schemas.validate_request(&invalid).unwrap_err();
+        assert_eq!(violations.violations().len(), 1);
+        assert_eq!(violations.violations()[0].path(), "/id");
+    }
+
+    #[test]
+    fn passes_messages_with_no_schema() {
+        let schemas = registry();
+        let req = request("room.leave", "corr", json!(42));
+        assert!(schemas.validate_request(&req).is_ok());
+        assert!(schemas
+            .validate_event(&event("room.open", json!(42)))
+            .is_ok());
+    }
+
+    #[test]
+    fn validates_event_payload() {
+        let schemas = registry();
+        let valid = event("room.close", json!({ "id": "123" }));
+        assert!(schemas.validate_event(&valid).is_ok());
+
+        let violations = schemas
+            .validate_event(&event("room.close", json!({})))
+            .unwrap_err();
+
+        assert_eq!(violations.violations()[0].path(), "");
+    }
+
+    #[test]
+    fn reports_unknown_content_type_as_violation() {
+        let properties = json!({
+            "type": "request",
+            "method": "room.enter",
+            "correlation_data": "corr",
+            "response_topic": "agents/instance01.sender.svc.example.org/api/v1/in/receiver",
+            "content_type": "application/xml",
+        });
+
+        let req = match receive(packet(properties, &json!({ "id": "123" }))) {
+            IncomingMessage::Request(req) => req,
+            _ => panic!("expected a request"),
+        };
+
+        let violations = registry().validate_request(&req).unwrap_err();
+        assert_eq!(violations.violations().len(), 1);
+    }
+
+    #[test]
+    fn exports_schemas() {
+        let export = registry().export();
+        assert_eq!(export["requests"]["room.enter"]["required"], json!(["id"]));
+        assert_eq!(export["events"]["room.close"]["required"], json!(["id"]));
+    }
+
+    #[test]
+    fn validates_outgoing_messages_when_enabled() {
+        let message = || {
+            let timing = OutgoingShortTermTimingProperties::new(chrono::Utc::now());
+            let props = OutgoingEventProperties::new("room.close", timing);
+            OutgoingEvent::broadcast(json!({}), props, "rooms/123/events")
+        };
+
+        assert!(registry().validate_outgoing_message(&message()).is_ok());
+
+        let result = registry()
+            .validate_outgoing(true)
+            .validate_outgoing_message(&message());
+
+        assert_eq!(result.is_err(), cfg!(debug_assertions));
+    }
+}
@@ -0,0 +1,529 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use ed25519_dalek::{Signer, Verifier};
+use hmac::{Hmac, Mac};
+use serde::Deserialize;
+use serde_json::{value::RawValue, Map, Value};
+use sha2::Sha256;
+
+use crate::{
+    mqtt::compat::{OutgoingEnvelope, RAW_PAYLOAD_FORMAT},
+    AccountId, AgentId, Authenticable, Error,
+};
+
+const SIGNATURE_PROPERTY: &str = "signature";
+const ACCOUNT_ID_PROPERTY: &str = "signature_account_id";
+const SIGNED_PROPERTIES_PROPERTY: &str = "signed_properties";
+
+const HMAC_PREFIX: &str = "hmac-sha256:";
+const ED25519_PREFIX: &str = "ed25519:";
+
+/// Versions the layout of the signed data.
+const SIGNATURE_CONTEXT: &str = "svc-agent-signature-v1";
+
+/// Envelope properties covered by the signature when present.
+const CRITICAL_PROPERTIES: &[&str] = &[
+    "type",
+    "method",
+    "label",
+    "status",
+    "correlation_data",
+    "response_topic",
+    "agent_id",
+    "deadline",
+    "content_type",
+    "content_encoding",
+    "payload_format",
+    "encryption",
+    "encryption_key_id",
+    "encryption_ephemeral_key",
+    "encryption_payload_format",
+];
+
+/// A key to sign outgoing messages with.
+#[derive(Clone)]
+pub enum SigningKey {
+    /// HMAC-SHA256 shared secret.
+    Hmac(Vec<u8>),
+    Ed25519(ed25519_dalek::SigningKey),
+}
+
+impl SigningKey {
+    /// Makes an Ed25519 key of its 32 bytes secret.
+    pub fn ed25519(secret: &[u8; 32]) -> Self {
+        Self::Ed25519(ed25519_dalek::SigningKey::from_bytes(secret))
+    }
+
+    /// Returns the key to verify the signatures made with this one.
+    pub fn verifying_key(&self) -> VerifyingKey {
+        match self {
+            Self::Hmac(secret) => VerifyingKey::Hmac(secret.to_owned()),
+            Self::Ed25519(key) => VerifyingKey::Ed25519(key.verifying_key()),
+        }
+    }
+}
+
+impl fmt::Debug for SigningKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Hmac(_) => write!(f, "SigningKey::Hmac(..)"),
+            Self::Ed25519(_) => write!(f, "SigningKey::Ed25519(..)"),
+        }
+    }
+}
+
+/// A key to verify signatures of incoming messages with.
+#[derive(Clone)]
+pub enum VerifyingKey {
+    /// HMAC-SHA256 shared secret.
+    Hmac(Vec<u8>),
+    Ed25519(ed25519_dalek::VerifyingKey),
+}
+
+impl VerifyingKey {
+    /// Makes an Ed25519 key of its 32 bytes public key.
+    pub fn ed25519(public_key: &[u8; 32]) -> Result<Self, Error> {
+        ed25519_dalek::VerifyingKey::from_bytes(public_key)
+            .map(Self::Ed25519)
+            .map_err(|e| Error::new(&format!("invalid Ed25519 public key, {}", e)))
+    }
+}
+
+impl fmt::Debug for VerifyingKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Hmac(_) => write!(f, "VerifyingKey::Hmac(..)"),
+            Self::Ed25519(key) => write!(f, "VerifyingKey::Ed25519({:?})", key),
+        }
+    }
+}
+
+/// Looks up keys to verify signatures of incoming messages by the signer's account.
+///
+/// Implement it to fetch keys from a database or a secret storage.
+/// A `HashMap` of keys works as a static store.
+pub trait KeyStore: Send + Sync {
+    fn verifying_key(&self, account_id: &AccountId) -> Option<VerifyingKey>;
+}
+
+impl KeyStore for HashMap<AccountId, VerifyingKey> {
+    fn verifying_key(&self, account_id: &AccountId) -> Option<VerifyingKey> {
+        self.get(account_id).cloned()
+    }
+}
+
+/// A reason of an incoming message failing signature verification.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SignatureError {
+    /// The message carries no signature while signatures are required.
+    Unsigned,
+    /// The key store has no key for the signer's account.
+    UnknownKey(AccountId),
+    /// The signature doesn't match the message.
+    Invalid(AccountId),
+    /// The message or its signature properties can't be parsed.
+    Malformed(String),
+}
+
+impl fmt::Display for SignatureError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Unsigned => write!(f, "message is not signed"),
+            Self::UnknownKey(account_id) => {
+                write!(
+                    f,
+                    "no key to verify signature of account = '{}'",
+                    account_id
+                )
+            }
+            Self::Invalid(account_id) => {
+                write!(f, "invalid signature of account = '{}'", account_id)
+            }
+            Self::Malformed(reason) => write!(f, "malformed signed message, {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for SignatureError {}
+
+/// Message signing and verification settings.
+///
+/// Outgoing messages get signed with the key of the agent's account. The signature covers
+/// the envelope payload and the properties routing and authorization rely on such as
+/// `method`, `correlation_data`, `response_topic` and `agent_id`. It's added to the envelope
+/// in the `signature` property along with the signer's account in `signature_account_id`
+/// and the list of covered properties in `signed_properties`. Dumps published with
+/// [Agent::publish_dump](../mqtt/struct.Agent.html#method.publish_dump) don't get signed.
+///
+/// Signatures of incoming messages are verified with keys looked up in the
+/// [KeyStore](trait.KeyStore.html) by the signer's account. Messages failing verification
+/// never get to the application. They're reported as
+/// [AgentNotification::UnverifiedMessage](../mqtt/enum.AgentNotification.html#variant.UnverifiedMessage)
+/// instead. So do messages with `agent_id` of another account than the signer's one.
+/// So do signed messages carrying any of the covered properties outside of `signed_properties`
+/// except for `agent_id` which is set by the broker. Unsigned messages pass as is unless
+/// signatures are required.
+///
+/// Attach it to the agent with
+/// [AgentBuilder::signing](../mqtt/struct.AgentBuilder.html#method.signing).
+///
+/// # Example
+///
+/// ```
+/// let key = SigningKey::ed25519(&secret);
+///
+/// let mut keys = HashMap::new();
+/// keys.insert(peer_account_id, VerifyingKey::ed25519(&peer_public_key)?);
+///
+/// let signing = Signing::new()
+///     .sign_with(key)
+///     .verify_with(keys)
+///     .require_signatures();
+/// ```
+#[derive(Clone, Default)]
+pub struct Signing {
+    key: Option<SigningKey>,
+    key_store: Option<Arc<dyn KeyStore>>,
+    is_required: bool,
+}
+
+impl fmt::Debug for Signing {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Signing")
+            .field("key", &self.key)
+            .field("key_store", &self.key_store.is_some())
+            .field("is_required", &self.is_required)
+            .finish()
+    }
+}
+
+#[derive(Deserialize)]
+struct SignedEnvelope<'a> {
+    #[serde(borrow)]
+    payload: &'a RawValue,
+    properties: Map<String, Value>,
+}
+
+impl Signing {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Signs outgoing messages with the `key`.
+    pub fn sign_with(self, key: SigningKey) -> Self {
+        Self {
+            key: Some(key),
+            ..self
+        }
+    }
+
+    /// Verifies signatures of incoming messages with keys from the `key_store`.
+    pub fn verify_with<K: KeyStore + 'static>(self, key_store: K) -> Self {
+        Self {
+            key_store: Some(Arc::new(key_store)),
+            ..self
+        }
+    }
+
+    /// Rejects incoming messages having no signature.
+    pub fn require_signatures(self) -> Self {
+        Self {
+            is_required: true,
+            ..self
+        }
+    }
+
+    /// Adds the signature properties to the envelope if there's a signing key.
+    pub(crate) fn sign(
+        &self,
+        envelope: &mut OutgoingEnvelope,
+        account_id: &AccountId,
+    ) -> Result<(), Error> {
+        let key = match self.key {
+            Some(ref key) => key,
+            None => return Ok(()),
+        };
+
+        let properties = envelope.properties_map()?;
+
+        let signed_properties = CRITICAL_PROPERTIES
+            .iter()
+            .filter(|key| properties.contains_key(**key))
+            .copied()
+            .collect::<Vec<_>>()
+            .join(",");
+
+        let data = signed_data(
+            account_id,
+            &signed_properties,
+            &properties,
+            envelope.payload_str(),
+        )?;
+
+        let signature = match key {
+            SigningKey::Hmac(secret) => {
+                let mut mac = hmac_sha256(secret)?;
+                mac.update(&data);
+                format!(
+                    "{}{}",
+                    HMAC_PREFIX,
+                    STANDARD.encode(mac.finalize().into_bytes())
+                )
+            }
+            SigningKey::Ed25519(key) => {
+                let signature = key.sign(&data);
+                format!(
+                    "{}{}",
+                    ED25519_PREFIX,
+                    STANDARD.encode(signature.to_bytes())
+                )
+            }
+        };
+
+        envelope.insert_property(SIGNATURE_PROPERTY, Value::from(signature));
+        envelope.insert_property(ACCOUNT_ID_PROPERTY, Value::from(account_id.to_string()));
+        envelope.insert_property(SIGNED_PROPERTIES_PROPERTY, Value::from(signed_properties));
+        Ok(())
+    }
+
+    /// Verifies the signature of a serialized incoming envelope if there's a key store.
+    ///
+    /// Unsigned envelopes pass unless signatures are required.
+    pub(crate) fn verify(&self, envelope: &[u8]) -> Result<(), SignatureError> {
+        let key_store = match self.key_store {
+            Some(ref key_store) => key_store,
+            None => return Ok(()),
+        };
+
+        let malformed = |e: &dyn fmt::Display| SignatureError::Malformed(e.to_string());
+
+        let envelope =
+            serde_json::from_slice::<SignedEnvelope>(envelope).map_err(|e| malformed(&e))?;
+
+        let properties = &envelope.properties;
+
+        let signature = match properties.get(SIGNATURE_PROPERTY) {
+            Some(Value::String(signature)) => signature,
+            Some(_) => return Err(malformed(&"signature is not a string")),
+            None if self.is_required => return Err(SignatureError::Unsigned),
+            None => return Ok(()),
+        };
+
+        let account_id = string_property(properties, ACCOUNT_ID_PROPERTY)?;
+        let account_id = AccountId::from_str(account_id).map_err(|e| malformed(&e))?;
+        let signed_properties = string_property(properties, SIGNED_PROPERTIES_PROPERTY)?;
+
+        // A critical property added after signing is as bad as a tampered one.
+        // The broker sets `agent_id` itself so it's checked against the signer below instead.
+        let is_unsigned = |key: &&str| !signed_properties.split(',').any(|signed| signed == *key);
+
+        if CRITICAL_PROPERTIES
+            .iter()
+            .filter(|key| **key != "agent_id")
+            .any(|key| properties.contains_key(*key) && is_unsigned(key))
+        {
+            return Err(SignatureError::Invalid(account_id));
+        }
+
+        // The agent can't belong to another account than the signer whether it's signed or not.
+        if properties.contains_key("agent_id") {
+            let agent_id = string_property(properties, "agent_id")?;
+            let agent_id = AgentId::from_str(agent_id).map_err(|e| malformed(&e))?;
+
+            if agent_id.as_account_id() != &account_id {
+                return Err(SignatureError::Invalid(account_id));
+            }
+        }
+
+        let payload = if properties.get("payload_format").and_then(Value::as_str)
+            == Some(RAW_PAYLOAD_FORMAT)
+        {
+            envelope.payload.get().to_owned()
+        } else {
+            serde_json::from_str::<String>(envelope.payload.get()).map_err(|e| malformed(&e))?
+        };
+
+        let data = signed_data(&account_id, signed_properties, properties, &payload)
+            .map_err(|e| malformed(&e))?;
+
+        let key = key_store
+            .verifying_key(&account_id)
+            .ok_or_else(|| SignatureError::UnknownKey(account_id.clone()))?;
+
+        let is_valid = match (key, signature) {
+            (VerifyingKey::Hmac(secret), signature) if signature.starts_with(HMAC_PREFIX) => {
+                let signature = decode_signature(&signature[HMAC_PREFIX.len()..])?;
+                let mut mac = hmac_sha256(&secret).map_err(|e| malformed(&e))?;
+                mac.update(&data);
+                mac.verify_slice(&signature).is_ok()
+            }
+            (VerifyingKey::Ed25519(key), signature) if signature.starts_with(ED25519_PREFIX) => {
+                let signature = decode_signature(&signature[ED25519_PREFIX.len()..])?;
+
+                ed25519_dalek::Signature::from_slice(&signature)
+                    .map(|signature| key.verify(&data, &signature).is_ok())
+                    .map_err(|e| malformed(&e))?
+            }
+            // The signature algorithm doesn't match the key.
+            _ => false,
+        };
+
+        if is_valid {
+            Ok(())
+        } else {
+            Err(SignatureError::Invalid(account_id))
+        }
+    }
+}
+
+/// Serializes the data covered by the signature.
+///
+/// It's a JSON array so the boundaries of the values are unambiguous.
+fn signed_data(
+    account_id: &AccountId,
+    signed_properties: &str,
+    properties: &Map<String, Value>,
+    payload: &str,
+) -> Result<Vec<u8>, Error> {
+    let values = signed_properties
+        .split(',')
+        .filter(|key| !key.is_empty())
+        .map(|key| properties.get(key).cloned().unwrap_or(Value::Null))
+        .collect::<Vec<_>>();
+
+    let data = serde_json::json!([
+        SIGNATURE_CONTEXT,
+        account_id.to_string(),
+        signed_properties,
+        values,
+        payload,
+    ]);
+
+    serde_json::to_vec(&data)
+        .map_err(|e| Error::new(&format!("error serializing signed data, {}", e)))
+}
+
+fn hmac_sha256(secret: &[u8]) -> Result<Hmac<Sha256>, Error> {
+    Hmac::<Sha256>::new_from_slice(secret)
+        .map_err(|e| Error::new(&format!("invalid HMAC key, {}", e)))
+}
+
+fn decode_signature(signature: &str) -> Result<Vec<u8>, SignatureError> {
+    STANDARD
+        .decode(signature)
+        .map_err(|e| SignatureError::Malformed(format!("invalid signature encoding, {}", e)))
+}
+
+fn string_property<'a>(
+    properties: &'a Map<String, Value>,
+    key: &str,
+) -> Result<&'a str, SignatureError> {
+    properties
+        .get(key)
+        .and_then(Value::as_str)
+        .ok_or_else(|| SignatureError::Malformed(format!("missing property = '{}'", key)))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    const SECRET: &[u8] = b"secret";
+
+    fn signer() -> AccountId {
+        AccountId::new("signer", "svc.example.org")
+    }
+
+    fn signing() -> Signing {
+        let mut keys = HashMap::new();
+        keys.insert(signer(), VerifyingKey::Hmac(SECRET.to_vec()));
+        Signing::new().verify_with(keys).require_signatures()
+    }
+
+    /// Builds an envelope signed with HMAC covering `signed_properties`.
+    fn envelope(mut properties: Map<String, Value>, signed_properties: &str) -> Vec<u8> {
+        let payload = "{}";
+        let data = signed_data(&signer(), signed_properties, &properties, payload).unwrap();
+
+        let mut mac = hmac_sha256(SECRET).unwrap();
+        mac.update(&data);
+        let signature = STANDARD.encode(mac.finalize().into_bytes());
+
+        properties.insert(
+            SIGNATURE_PROPERTY.to_owned(),
+            Value::from(format!("{}{}", HMAC_PREFIX, signature)),
+        );
+        properties.insert(
+            ACCOUNT_ID_PROPERTY.to_owned(),
+            Value::from(signer().to_string()),
+        );
+        properties.insert(
+            SIGNED_PROPERTIES_PROPERTY.to_owned(),
+            Value::from(signed_properties),
+        );
+
+        serde_json::to_vec(&json!({ "payload": payload, "properties": properties })).unwrap()
+    }
+
+    fn properties(agent_id: &AgentId) -> Map<String, Value> {
+        let mut properties = Map::new();
+        properties.insert("type".to_owned(), Value::from("event"));
+        properties.insert("agent_id".to_owned(), Value::from(agent_id.to_string()));
+        properties
+    }
+
+    #[test]
+    fn verifies_signed_agent_id() {
+        let agent_id = AgentId::new("instance01", signer());
+        let envelope = envelope(properties(&agent_id), "type,agent_id");
+
+        assert_eq!(signing().verify(&envelope), Ok(()));
+    }
+
+    #[test]
+    fn rejects_forged_unsigned_agent_id() {
+        let agent_id = AgentId::new("instance01", AccountId::new("victim", "svc.example.org"));
+        let envelope = envelope(properties(&agent_id), "type");
+
+        assert_eq!(
+            signing().verify(&envelope),
+            Err(SignatureError::Invalid(signer()))
+        );
+    }
+
+    #[test]
+    fn rejects_unsigned_critical_property() {
+        let agent_id = AgentId::new("instance01", signer());
+        let mut envelope =
+            serde_json::from_slice::<Value>(&envelope(properties(&agent_id), "type,agent_id"))
+                .unwrap();
+
+        envelope["properties"]["deadline"] = json!("1700000000000");
+        let envelope = serde_json::to_vec(&envelope).unwrap();
+
+        assert_eq!(
+            signing().verify(&envelope),
+            Err(SignatureError::Invalid(signer()))
+        );
+    }
+
+    #[test]
+    fn rejects_tampered_payload() {
+        let agent_id = AgentId::new("instance01", signer());
+        let envelope = envelope(properties(&agent_id), "type,agent_id");
+        let envelope = String::from_utf8(envelope)
+            .unwrap()
+            .replace("{}", r#"{\"a\":1}"#);
+
+        assert_eq!(
+            signing().verify(envelope.as_bytes()),
+            Err(SignatureError::Invalid(signer()))
+        );
+    }
+}